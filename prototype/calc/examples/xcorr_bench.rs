@@ -0,0 +1,100 @@
+//! Host benchmark of the cross-correlation kernel, reporting the cost per frame.
+//!
+//! Run with `cargo xcorr-bench`, which builds it for the host rather than for the MCU.
+//! Absolute numbers don't carry over to the MCU, but the ratio between
+//! the kernels gives an idea of the gain.
+
+use std::time::{Duration, Instant};
+
+use folley_calc::{max_lags_size, sample::Sample, xcorr_real};
+
+const T_S_US: u32 = 37;
+const D_MICS_MM: u32 = 125;
+const SAMPLE_BUF_SIZE: usize = 1024;
+const XCORR_LEN: usize = max_lags_size(T_S_US, D_MICS_MM);
+const FRAMES: u32 = 2000;
+
+/// The kernel as it was before, checking the bounds of y for every product
+#[allow(clippy::needless_range_loop)]
+fn xcorr_naive<S: Sample, const XCORR_LEN: usize, const SIGNAL_LEN: usize>(
+    x: &[S; SIGNAL_LEN],
+    y: &[S; SIGNAL_LEN],
+    out: &mut [S::Acc; XCORR_LEN],
+) -> usize {
+    let mut argmax = 0;
+    let mut max = S::Acc::default();
+    for n in 0..XCORR_LEN {
+        for m in 0..SIGNAL_LEN {
+            let y_index = (n + m) as isize - (XCORR_LEN as isize) / 2;
+            let y_val = if y_index >= 0 {
+                *y.get(y_index as usize).unwrap_or(&S::default())
+            } else {
+                S::default()
+            };
+            out[n] += x[m].mul_acc(y_val);
+        }
+        if out[n] > max {
+            max = out[n];
+            argmax = n;
+        }
+    }
+    argmax
+}
+
+/// Pseudo-random 12 bit samples, centered around zero like the output of `Channels::from_samples`
+fn signal(seed: u32) -> [i16; SAMPLE_BUF_SIZE] {
+    let mut state = seed;
+    let mut signal = [0i16; SAMPLE_BUF_SIZE];
+    signal.iter_mut().for_each(|s| {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        *s = ((state >> 16) % 4096) as i16 - 2048;
+    });
+    signal
+}
+
+fn bench<
+    S: Sample,
+    F: Fn(&[S; SAMPLE_BUF_SIZE], &[S; SAMPLE_BUF_SIZE], &mut [S::Acc; XCORR_LEN]),
+>(
+    x: &[S; SAMPLE_BUF_SIZE],
+    y: &[S; SAMPLE_BUF_SIZE],
+    kernel: F,
+) -> (Duration, [S::Acc; XCORR_LEN]) {
+    let mut out = [S::Acc::default(); XCORR_LEN];
+    let start = Instant::now();
+    (0..FRAMES).for_each(|_| {
+        out = [S::Acc::default(); XCORR_LEN];
+        // Keep the compiler from hoisting the kernel out of the loop
+        let (x, y) = unsafe { (std::ptr::read_volatile(x), std::ptr::read_volatile(y)) };
+        kernel(&x, &y, &mut out);
+    });
+    (start.elapsed() / FRAMES, out)
+}
+
+fn report<S: Sample>(name: &str, x: &[S; SAMPLE_BUF_SIZE], y: &[S; SAMPLE_BUF_SIZE]) {
+    let (naive, expected) = bench(x, y, |x, y, out| {
+        xcorr_naive(x, y, out);
+    });
+    let (current, out) = bench(x, y, |x, y, out| {
+        xcorr_real(x, y, out);
+    });
+    assert_eq!(out, expected, "Kernels disagree");
+    println!(
+        "{:>4}: naive {:>9.2?}/frame, xcorr_real {:>9.2?}/frame ({:.1}x)",
+        name,
+        naive,
+        current,
+        naive.as_secs_f64() / current.as_secs_f64()
+    );
+}
+
+fn main() {
+    println!(
+        "{} samples, {} lags, {} frames per kernel",
+        SAMPLE_BUF_SIZE, XCORR_LEN, FRAMES
+    );
+    let (x, y) = (signal(1), signal(2));
+    report("i16", &x, &y);
+    report("i32", &x.map(i32::from), &y.map(i32::from));
+    report("f32", &x.map(f32::from), &y.map(f32::from));
+}
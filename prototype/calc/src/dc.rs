@@ -0,0 +1,133 @@
+/// Single-pole DC blocking filter: `y[n] = x[n] - x[n-1] + a * y[n-1]`.
+///
+/// In contrast to subtracting the frame mean, the filter state is carried over
+/// between consecutive frames, so that there are no steps at frame boundaries.
+/// The pole `a` is stored as a Q15 fixed point number, the output as Q15 as well,
+/// in order not to lose the fractional part between samples.
+#[derive(Debug, Clone, Copy)]
+pub struct DcBlocker {
+    pole_q15: i64,
+    prev_x: i16,
+    prev_y_q15: i64,
+    primed: bool,
+}
+
+impl DcBlocker {
+    /// Create a DC blocker with a pole `a = pole_q15 / 32768`.
+    /// The closer the pole is to 1, the lower the cutoff frequency.
+    pub const fn new(pole_q15: u16) -> Self {
+        Self {
+            pole_q15: pole_q15 as i64,
+            prev_x: 0,
+            prev_y_q15: 0,
+            primed: false,
+        }
+    }
+
+    /// Create a DC blocker with a -3 dB cutoff frequency of approximately `cutoff_hz`,
+    /// for a signal sampled every `sample_period_us` microseconds.
+    pub const fn with_cutoff(cutoff_hz: u32, sample_period_us: u32) -> Self {
+        // a = 1 - 2 * pi * f_c * T_s
+        const TWO_PI_Q15: u64 = 205_887;
+        let decrement = TWO_PI_Q15 * cutoff_hz as u64 * sample_period_us as u64 / 1_000_000;
+        let pole_q15 = if decrement >= 1 << 15 {
+            0
+        } else if decrement == 0 {
+            // The pole must stay below 1 for the filter to be stable
+            (1 << 15) - 1
+        } else {
+            (1 << 15) - decrement
+        };
+        Self::new(pole_q15 as u16)
+    }
+
+    /// Forget the filter state. The next sample will be treated as the first one.
+    pub fn reset(&mut self) {
+        self.prev_x = 0;
+        self.prev_y_q15 = 0;
+        self.primed = false;
+    }
+
+    /// Filter a single sample
+    pub fn process(&mut self, x: i16) -> i16 {
+        if !self.primed {
+            // Avoid a large transient caused by the DC offset of the very first sample
+            self.prev_x = x;
+            self.primed = true;
+        }
+        let y_q15 =
+            ((x as i64 - self.prev_x as i64) << 15) + ((self.pole_q15 * self.prev_y_q15) >> 15);
+        self.prev_x = x;
+        self.prev_y_q15 = y_q15;
+
+        ((y_q15 + (1 << 14)) >> 15).clamp(i16::MIN as i64, i16::MAX as i64) as i16
+    }
+
+    /// Filter a slice of samples in place
+    pub fn process_slice(&mut self, samples: &mut [i16]) {
+        samples.iter_mut().for_each(|s| *s = self.process(*s));
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "std")]
+mod test {
+    use crate::dc::*;
+
+    const T_S_US: u32 = 37;
+
+    fn sine(len: usize, freq_hz: f64, amplitude: f64, offset: f64) -> Vec<i16> {
+        (0..len)
+            .map(|n| {
+                let t = n as f64 * T_S_US as f64 * 1e-6;
+                (offset + amplitude * (2. * std::f64::consts::PI * freq_hz * t).sin()) as i16
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_dc_removed() {
+        let mut blocker = DcBlocker::with_cutoff(20, T_S_US);
+        let mut signal = sine(4096, 1000., 500., 2000.);
+        blocker.process_slice(&mut signal);
+
+        // No large transient at the start
+        assert!(signal.iter().all(|s| s.abs() < 600));
+        // Settled signal has no DC component, but retains its amplitude
+        let settled = &signal[2048..];
+        let mean = settled.iter().map(|s| *s as i64).sum::<i64>() / settled.len() as i64;
+        assert!(mean.abs() <= 5, "Mean: {}", mean);
+        let max = *settled.iter().max().unwrap();
+        assert!((480..=510).contains(&max), "Max: {}", max);
+    }
+
+    #[test]
+    fn test_state_carried_over() {
+        let signal = sine(2048, 300., 700., 1500.);
+
+        let mut whole = signal.clone();
+        DcBlocker::with_cutoff(20, T_S_US).process_slice(&mut whole);
+
+        let mut framed = signal;
+        let mut blocker = DcBlocker::with_cutoff(20, T_S_US);
+        framed
+            .chunks_mut(100)
+            .for_each(|frame| blocker.process_slice(frame));
+
+        assert_eq!(whole, framed);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut blocker = DcBlocker::with_cutoff(20, T_S_US);
+        let mut signal = sine(1024, 1000., 500., 2000.);
+        let mut expected = signal.clone();
+        blocker.process_slice(&mut signal);
+
+        blocker.reset();
+        let mut again = sine(1024, 1000., 500., 2000.);
+        blocker.process_slice(&mut again);
+        DcBlocker::with_cutoff(20, T_S_US).process_slice(&mut expected);
+        assert_eq!(again, expected);
+    }
+}
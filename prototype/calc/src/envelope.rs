@@ -0,0 +1,244 @@
+use crate::{calc_lag, sample::Sample};
+
+/// The way the envelope of a signal is obtained
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum EnvelopeMethod {
+    /// Full-wave rectification
+    Rectify,
+    /// Magnitude of the analytic signal, obtained using the Hilbert transform.
+    /// The signal length must be a power of two.
+    #[cfg(feature = "std")]
+    Hilbert,
+}
+
+/// Parameters of the envelope based time difference of arrival estimation
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct EnvelopeParams {
+    pub method: EnvelopeMethod,
+    /// Cutoff frequency of the low-pass filter used to smooth the envelope
+    pub cutoff_hz: u32,
+    /// Sample period in microseconds
+    pub sample_period_us: u32,
+    /// Fraction of the envelope range, between its minimum and its maximum,
+    /// the envelope must rise above to be considered an onset
+    pub onset_fraction: f32,
+}
+
+impl EnvelopeParams {
+    pub const fn new(method: EnvelopeMethod, cutoff_hz: u32, sample_period_us: u32) -> Self {
+        Self {
+            method,
+            cutoff_hz,
+            sample_period_us,
+            onset_fraction: 0.5,
+        }
+    }
+}
+
+/// Time difference of arrival estimated from the envelopes of two signals, in samples
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct EnvelopeLag {
+    /// Lag of the envelope of y relative to the envelope of x, found by cross-correlation
+    pub lag: isize,
+    /// Difference between the onsets of y and x, if both signals contain an onset
+    pub onset_lag: Option<isize>,
+}
+
+/// Calculate the envelope of a signal, smoothed by a zero-phase low-pass filter
+pub fn envelope<S: Sample, const SIGNAL_LEN: usize>(
+    signal: &[S; SIGNAL_LEN],
+    params: &EnvelopeParams,
+) -> [f32; SIGNAL_LEN] {
+    let mut env = [0f32; SIGNAL_LEN];
+    match params.method {
+        #[cfg(feature = "std")]
+        EnvelopeMethod::Hilbert => analytic_magnitude(signal, &mut env),
+        EnvelopeMethod::Rectify => env.iter_mut().zip(signal.iter()).for_each(|(e, s)| {
+            let s = s.to_f32();
+            *e = if s < 0. { -s } else { s };
+        }),
+    }
+    low_pass(&mut env, params);
+    env
+}
+
+/// Find the first sample at which the envelope rises above `fraction` of its range.
+/// Returns `None` if the envelope is flat, or if it is above the threshold right from the start,
+/// meaning the onset happened before the frame.
+pub fn detect_onset(envelope: &[f32], fraction: f32) -> Option<usize> {
+    let (min, max) = envelope.iter().fold((f32::MAX, f32::MIN), |(min, max), e| {
+        (min.min(*e), max.max(*e))
+    });
+    if max <= min {
+        return None;
+    }
+    let threshold = min + fraction * (max - min);
+    match envelope.iter().position(|e| *e >= threshold) {
+        Some(0) | None => None,
+        onset => onset,
+    }
+}
+
+/// Estimate the lag of signal y relative to signal x by cross-correlating their envelopes,
+/// and by comparing their onsets. This is more robust than correlating the raw signals
+/// for transient sounds, and for periodic signals of which the raw correlation has several
+/// similar peaks. The buffer is used to store the cross-correlation output.
+pub fn calc_envelope_lag<S: Sample, const XCORR_LEN: usize, const SIGNAL_LEN: usize>(
+    x: &[S; SIGNAL_LEN],
+    y: &[S; SIGNAL_LEN],
+    params: &EnvelopeParams,
+    buf: &mut [f32; XCORR_LEN],
+) -> EnvelopeLag {
+    let mut x_env = envelope(x, params);
+    let mut y_env = envelope(y, params);
+
+    let onset_lag = detect_onset(&x_env, params.onset_fraction)
+        .zip(detect_onset(&y_env, params.onset_fraction))
+        .map(|(x_onset, y_onset)| y_onset as isize - x_onset as isize);
+
+    // An envelope is positive, so its mean needs to be removed to get a clear correlation peak
+    remove_mean(&mut x_env);
+    remove_mean(&mut y_env);
+    EnvelopeLag {
+        lag: calc_lag(&x_env, &y_env, buf),
+        onset_lag,
+    }
+}
+
+fn remove_mean(signal: &mut [f32]) {
+    let mean = signal.iter().sum::<f32>() / signal.len() as f32;
+    signal.iter_mut().for_each(|s| *s -= mean);
+}
+
+/// Single-pole low-pass filter, run forwards and backwards so that it does not delay the envelope
+fn low_pass(signal: &mut [f32], params: &EnvelopeParams) {
+    if signal.is_empty() {
+        return;
+    }
+    let w =
+        2. * core::f32::consts::PI * params.cutoff_hz as f32 * params.sample_period_us as f32 / 1e6;
+    let alpha = w / (1. + w);
+
+    let mut y = signal[0];
+    signal.iter_mut().for_each(|s| {
+        y += alpha * (*s - y);
+        *s = y;
+    });
+    let mut y = signal[signal.len() - 1];
+    signal.iter_mut().rev().for_each(|s| {
+        y += alpha * (*s - y);
+        *s = y;
+    });
+}
+
+#[cfg(feature = "std")]
+fn analytic_magnitude<S: Sample>(signal: &[S], out: &mut [f32]) {
+    use crate::fft::{fft, ifft, Complex};
+
+    let n = signal.len();
+    let mut buf: Vec<Complex> = signal
+        .iter()
+        .map(|s| Complex::new(s.to_f32(), 0.))
+        .collect();
+    fft(&mut buf);
+    // Keep DC and Nyquist, double the positive and drop the negative frequencies
+    buf.iter_mut().enumerate().for_each(|(k, bin)| {
+        if k > 0 && k < n / 2 {
+            *bin = bin.scale(2.);
+        } else if k > n / 2 {
+            *bin = Complex::ZERO;
+        }
+    });
+    ifft(&mut buf);
+    out.iter_mut()
+        .zip(buf.iter())
+        .for_each(|(o, bin)| *o = bin.norm_sqr().sqrt());
+}
+
+#[cfg(test)]
+#[cfg(feature = "std")]
+mod test {
+    use crate::envelope::*;
+    use crate::*;
+
+    const T_S_US: u32 = 37;
+    const D_MICS_MM: u32 = 125;
+    const M: usize = 1024;
+    const N: usize = max_lags_size(T_S_US, D_MICS_MM);
+
+    fn tone(n: usize, freq_hz: f64) -> f64 {
+        (2. * std::f64::consts::PI * freq_hz * n as f64 * T_S_US as f64 * 1e-6).sin()
+    }
+
+    /// A buzz that starts abruptly at `start` and decays, delayed by `delay` samples
+    fn transient(start: usize, delay: usize) -> [i16; M] {
+        let mut signal = [0i16; M];
+        signal.iter_mut().enumerate().for_each(|(n, s)| {
+            if n >= start + delay {
+                let t = (n - start - delay) as f64;
+                *s = (2000. * (-t / 150.).exp() * tone(n - delay, 2500.)) as i16;
+            }
+        });
+        signal
+    }
+
+    #[test]
+    fn test_envelope() {
+        let mut signal = [0i16; M];
+        signal
+            .iter_mut()
+            .enumerate()
+            .for_each(|(n, s)| *s = (1000. * tone(n, 3000.)) as i16);
+
+        let params = EnvelopeParams::new(EnvelopeMethod::Rectify, 200, T_S_US);
+        let env = envelope(&signal, &params);
+        // The mean of a rectified sine is 2 / pi times its amplitude
+        env[100..M - 100]
+            .iter()
+            .for_each(|e| assert!((e - 637.).abs() < 40., "{}", e));
+
+        let params = EnvelopeParams::new(EnvelopeMethod::Hilbert, 200, T_S_US);
+        let env = envelope(&signal, &params);
+        env[100..M - 100]
+            .iter()
+            .for_each(|e| assert!((e - 1000.).abs() < 40., "{}", e));
+    }
+
+    #[test]
+    fn test_detect_onset() {
+        let params = EnvelopeParams::new(EnvelopeMethod::Rectify, 500, T_S_US);
+        let onset = detect_onset(&envelope(&transient(300, 0), &params), 0.5).unwrap();
+        assert!((295..310).contains(&onset), "{}", onset);
+
+        // No onset in silence, or in a sound that started before the frame
+        assert_eq!(detect_onset(&[0f32; M], 0.5), None);
+        assert_eq!(
+            detect_onset(&envelope(&transient(0, 0), &params), 0.5),
+            None
+        );
+    }
+
+    #[test]
+    fn test_envelope_lag() {
+        for method in [EnvelopeMethod::Rectify, EnvelopeMethod::Hilbert] {
+            let params = EnvelopeParams::new(method, 500, T_S_US);
+            for delay in 0..=6 {
+                let x = transient(300, 0);
+                let y = transient(300, delay);
+                let mut buf = [0f32; N];
+                let lag = calc_envelope_lag(&x, &y, &params, &mut buf);
+                assert_eq!(lag.onset_lag, Some(delay as isize), "{:?}", method);
+                assert!(
+                    (lag.lag - delay as isize).abs() <= 1,
+                    "{:?} {} {:?}",
+                    method,
+                    delay,
+                    lag
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use core::ops::{Add, Mul, Sub};
+
+/// Complex number, used as FFT bin
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Complex {
+    pub re: f32,
+    pub im: f32,
+}
+
+impl Complex {
+    pub const ZERO: Self = Self::new(0., 0.);
+
+    pub const fn new(re: f32, im: f32) -> Self {
+        Self { re, im }
+    }
+
+    pub fn conj(self) -> Self {
+        Self::new(self.re, -self.im)
+    }
+
+    /// Squared magnitude
+    pub fn norm_sqr(self) -> f32 {
+        self.re * self.re + self.im * self.im
+    }
+
+    pub fn scale(self, factor: f32) -> Self {
+        Self::new(self.re * factor, self.im * factor)
+    }
+}
+
+impl Add for Complex {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self {
+        Self::new(self.re + rhs.re, self.im + rhs.im)
+    }
+}
+
+impl Sub for Complex {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self {
+        Self::new(self.re - rhs.re, self.im - rhs.im)
+    }
+}
+
+impl Mul for Complex {
+    type Output = Self;
+
+    fn mul(self, rhs: Self) -> Self {
+        Self::new(
+            self.re * rhs.re - self.im * rhs.im,
+            self.re * rhs.im + self.im * rhs.re,
+        )
+    }
+}
+
+/// In-place forward FFT. The length of `buf` must be a power of two.
+pub fn fft(buf: &mut [Complex]) {
+    transform(buf, false);
+}
+
+/// In-place inverse FFT, including the 1/N scaling. The length of `buf` must be a power of two.
+pub fn ifft(buf: &mut [Complex]) {
+    transform(buf, true);
+    let scale = 1. / buf.len() as f32;
+    buf.iter_mut().for_each(|c| *c = c.scale(scale));
+}
+
+/// Iterative radix-2 decimation-in-time FFT
+fn transform(buf: &mut [Complex], inverse: bool) {
+    let n = buf.len();
+    assert!(n.is_power_of_two(), "FFT length must be a power of two");
+    if n == 1 {
+        return;
+    }
+
+    // Bit-reversal permutation
+    let bits = n.trailing_zeros();
+    (0..n).for_each(|i| {
+        let j = i.reverse_bits() >> (usize::BITS - bits);
+        if j > i {
+            buf.swap(i, j);
+        }
+    });
+
+    let mut len = 2;
+    while len <= n {
+        let half = len / 2;
+        (0..half).for_each(|k| {
+            let (sin, cos) = sin_cos_turn(k, len);
+            // exp(-2 * pi * i * k / len) for the forward transform
+            let w = if inverse {
+                Complex::new(cos, sin)
+            } else {
+                Complex::new(cos, -sin)
+            };
+            (0..n).step_by(len).for_each(|start| {
+                let even = buf[start + k];
+                let odd = buf[start + k + half] * w;
+                buf[start + k] = even + odd;
+                buf[start + k + half] = even - odd;
+            });
+        });
+        len *= 2;
+    }
+}
+
+/// Calculate the sine and cosine of `2 * pi * k / n` to f32 precision, without relying
+/// on `std`. The angle is reduced to the first quadrant and evaluated using a Taylor series.
+pub(crate) fn sin_cos_turn(k: usize, n: usize) -> (f32, f32) {
+    let k = k % n;
+    let quadrant = 4 * k / n;
+    let x = core::f32::consts::FRAC_PI_2 * (4 * k % n) as f32 / n as f32;
+
+    let x2 = x * x;
+    let (mut sin, mut cos) = (0f32, 0f32);
+    let (mut sin_term, mut cos_term) = (x, 1f32);
+    (1..=8).for_each(|i| {
+        sin += sin_term;
+        cos += cos_term;
+        let i = i as f32;
+        sin_term *= -x2 / ((2. * i) * (2. * i + 1.));
+        cos_term *= -x2 / ((2. * i - 1.) * (2. * i));
+    });
+
+    match quadrant {
+        0 => (sin, cos),
+        1 => (cos, -sin),
+        2 => (-sin, -cos),
+        _ => (-cos, sin),
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "std")]
+mod test {
+    use crate::fft::*;
+
+    #[test]
+    fn test_sin_cos_turn() {
+        (0..64).for_each(|k| {
+            let (sin, cos) = sin_cos_turn(k, 64);
+            let angle = 2. * std::f32::consts::PI * k as f32 / 64.;
+            assert!((sin - angle.sin()).abs() < 1e-6);
+            assert!((cos - angle.cos()).abs() < 1e-6);
+        });
+    }
+
+    #[test]
+    fn test_fft_impulse_and_tone() {
+        let mut buf = [Complex::ZERO; 16];
+        buf[0] = Complex::new(1., 0.);
+        fft(&mut buf);
+        assert!(buf
+            .iter()
+            .all(|c| (c.re - 1.).abs() < 1e-6 && c.im.abs() < 1e-6));
+
+        let mut buf = [Complex::ZERO; 64];
+        buf.iter_mut().enumerate().for_each(|(n, c)| {
+            c.re = (2. * std::f32::consts::PI * 5. * n as f32 / 64.).cos();
+        });
+        fft(&mut buf);
+        buf.iter().enumerate().for_each(|(k, c)| {
+            let expected = if k == 5 || k == 59 { 32. } else { 0. };
+            assert!((c.re - expected).abs() < 1e-4, "{}: {:?}", k, c);
+            assert!(c.im.abs() < 1e-4);
+        });
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let signal: Vec<Complex> = (0..1024)
+            .map(|n| Complex::new(((n * 7919) % 4096) as f32 - 2048., 0.))
+            .collect();
+        let mut buf = signal.clone();
+        fft(&mut buf);
+        ifft(&mut buf);
+        signal.iter().zip(buf.iter()).for_each(|(a, b)| {
+            assert!((a.re - b.re).abs() < 1e-2);
+            assert!(b.im.abs() < 1e-2);
+        });
+    }
+}
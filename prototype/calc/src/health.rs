@@ -0,0 +1,416 @@
+use folley_format::{device_to_server::MicArraySample, units::Bearing};
+
+use crate::{calc_lag, lag_to_angle, sample::Sample, Channels};
+
+/// Thresholds used to decide whether a channel or channel pair is usable
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct HealthLimits {
+    /// Raw samples at or below this value are considered clipped
+    pub clip_low: i16,
+    /// Raw samples at or above this value are considered clipped
+    pub clip_high: i16,
+    /// Maximum amount of clipped or saturated samples in a single frame
+    pub max_clipped: u32,
+    /// Minimum RMS value of a channel around its mean, in ADC counts
+    pub min_rms: u32,
+    /// Maximum amount of consecutive identical samples
+    pub max_stuck_run: u32,
+    /// Minimum normalized cross-correlation peak of a channel pair, in per-mille
+    pub min_correlation_permille: u32,
+}
+
+impl HealthLimits {
+    /// Limits for a single-ended 12 bit SAADC measurement
+    pub const fn saadc_12bit() -> Self {
+        Self {
+            clip_low: 0,
+            clip_high: 4095,
+            max_clipped: 8,
+            min_rms: 2,
+            max_stuck_run: 32,
+            min_correlation_permille: 300,
+        }
+    }
+}
+
+/// The reason a channel is considered unhealthy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ChannelFault {
+    /// Too many samples were at the edges of the ADC range
+    Clipping,
+    /// Too many samples saturated the `i16` range while removing the DC component
+    Saturation,
+    /// The channel hardly varies, e.g. because the microphone is unplugged
+    Flatline,
+    /// The channel stayed at the same value for too long
+    Stuck,
+}
+
+/// The reason an angle could not be calculated from a channel pair
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PairError {
+    /// One of the channels is unhealthy
+    UnhealthyChannel { channel: usize, fault: ChannelFault },
+    /// The channels are not correlated enough to yield a meaningful lag
+    Uncorrelated { correlation_permille: u32 },
+}
+
+/// Statistics of a single channel over a single frame
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ChannelHealth {
+    /// Amount of raw samples at the edges of the ADC range
+    pub clipped: u32,
+    /// Amount of prepared samples that saturated the `i16` range
+    pub saturated: u32,
+    /// RMS value around the channel mean
+    pub rms: u32,
+    /// Length of the longest run of identical consecutive samples
+    pub longest_run: u32,
+}
+
+impl ChannelHealth {
+    /// Check the channel statistics against the limits
+    pub fn check(&self, limits: &HealthLimits) -> Result<(), ChannelFault> {
+        if self.longest_run > limits.max_stuck_run {
+            Err(ChannelFault::Stuck)
+        } else if self.rms < limits.min_rms {
+            Err(ChannelFault::Flatline)
+        } else if self.clipped > limits.max_clipped {
+            Err(ChannelFault::Clipping)
+        } else if self.saturated > limits.max_clipped {
+            Err(ChannelFault::Saturation)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Health of all four channels in a single frame
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FrameHealth {
+    pub channels: [ChannelHealth; 4],
+}
+
+impl FrameHealth {
+    /// Analyze the raw samples of a frame
+    pub fn analyze(samples: &[MicArraySample], limits: &HealthLimits) -> Self {
+        let mut health = Self::default();
+        let mut totals = [0i64; 4];
+        let mut squares = [0i64; 4];
+        let mut runs = [0u32; 4];
+
+        samples.iter().enumerate().for_each(|(i, sample)| {
+            (0..4).for_each(|ch| {
+                let s = sample[ch];
+                let ch_health = &mut health.channels[ch];
+                if s <= limits.clip_low || s >= limits.clip_high {
+                    ch_health.clipped += 1;
+                }
+                totals[ch] += s as i64;
+                squares[ch] += s as i64 * s as i64;
+
+                runs[ch] = if i > 0 && samples[i - 1][ch] == s {
+                    runs[ch] + 1
+                } else {
+                    1
+                };
+                ch_health.longest_run = ch_health.longest_run.max(runs[ch]);
+            })
+        });
+
+        if !samples.is_empty() {
+            let n = samples.len() as i64;
+            (0..4).for_each(|ch| {
+                let mean = totals[ch] / n;
+                let variance = (squares[ch] / n - mean * mean).max(0);
+                health.channels[ch].rms = isqrt(variance as u64) as u32;
+            });
+        }
+        health
+    }
+
+    /// Count the samples that saturated the range of the sample type while preparing the channels
+    pub fn count_saturation<S: Sample, const SIGNAL_LEN: usize>(
+        &mut self,
+        channels: &Channels<S, SIGNAL_LEN>,
+    ) {
+        (0..4).for_each(|ch| {
+            self.channels[ch].saturated = channels
+                .channel(ch)
+                .iter()
+                .filter(|s| **s <= S::MIN || **s >= S::MAX)
+                .count() as u32;
+        });
+    }
+
+    /// Check whether both channels of a pair are healthy
+    pub fn check_pair(&self, x: usize, y: usize, limits: &HealthLimits) -> Result<(), PairError> {
+        [x, y].iter().try_for_each(|&channel| {
+            self.channels[channel]
+                .check(limits)
+                .map_err(|fault| PairError::UnhealthyChannel { channel, fault })
+        })
+    }
+}
+
+/// Result of cross-correlating a healthy, sufficiently correlated channel pair
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PairEstimate {
+    pub bearing: Bearing,
+    /// Lag of the second channel relative to the first, in samples
+    pub lag: i32,
+    /// Normalized cross-correlation peak, see [normalized_correlation_permille]
+    pub correlation_permille: u32,
+}
+
+/// Calculate the angle of an audio source like [crate::calc_angle], using channels `x` and `y`
+/// of `channels`. Refuses to do so if either channel is unhealthy, or if the
+/// channels are not sufficiently correlated.
+#[allow(clippy::too_many_arguments)]
+pub fn calc_angle_checked<
+    S: Sample,
+    const T_S_US: u32,
+    const D_MICS_MM: u32,
+    const XCORR_LEN: usize,
+    const SIGNAL_LEN: usize,
+>(
+    channels: &Channels<S, SIGNAL_LEN>,
+    x: usize,
+    y: usize,
+    health: &FrameHealth,
+    limits: &HealthLimits,
+    buf: &mut [S::Acc; XCORR_LEN],
+    lag_table: &[u32; XCORR_LEN],
+) -> Result<Bearing, PairError> {
+    estimate_pair_checked::<S, T_S_US, D_MICS_MM, XCORR_LEN, SIGNAL_LEN>(
+        channels, x, y, health, limits, buf, lag_table,
+    )
+    .map(|estimate| estimate.bearing)
+}
+
+/// Like [calc_angle_checked], but also report the lag and correlation the angle is based on
+#[allow(clippy::too_many_arguments)]
+pub fn estimate_pair_checked<
+    S: Sample,
+    const T_S_US: u32,
+    const D_MICS_MM: u32,
+    const XCORR_LEN: usize,
+    const SIGNAL_LEN: usize,
+>(
+    channels: &Channels<S, SIGNAL_LEN>,
+    x: usize,
+    y: usize,
+    health: &FrameHealth,
+    limits: &HealthLimits,
+    buf: &mut [S::Acc; XCORR_LEN],
+    lag_table: &[u32; XCORR_LEN],
+) -> Result<PairEstimate, PairError> {
+    health.check_pair(x, y, limits)?;
+
+    let (x_chan, y_chan) = (channels.channel(x), channels.channel(y));
+    let lag = calc_lag(x_chan, y_chan, buf);
+    let peak = buf[(lag + XCORR_LEN as isize / 2) as usize];
+
+    let correlation_permille = normalized_correlation_permille(peak, x_chan, y_chan);
+    if correlation_permille < limits.min_correlation_permille {
+        return Err(PairError::Uncorrelated {
+            correlation_permille,
+        });
+    }
+    Ok(PairEstimate {
+        bearing: lag_to_angle::<T_S_US, D_MICS_MM, XCORR_LEN>(lag as i32, lag_table),
+        lag: lag as i32,
+        correlation_permille,
+    })
+}
+
+/// Normalize a cross-correlation value by the energy of both signals, in per-mille
+pub fn normalized_correlation_permille<S: Sample>(xcorr: S::Acc, x: &[S], y: &[S]) -> u32 {
+    let energy = |s: &[S]| {
+        let mut energy = S::Acc::default();
+        s.iter().for_each(|v| energy += v.mul_acc(*v));
+        S::acc_to_f32(energy)
+    };
+    let (energy_x, energy_y) = (energy(x), energy(y));
+    if xcorr <= S::Acc::default() || energy_x <= 0. || energy_y <= 0. {
+        return 0;
+    }
+    // Square of the normalized correlation, as there is no square root in `core`
+    let xcorr = S::acc_to_f32(xcorr);
+    let squared = (xcorr / energy_x) * (xcorr / energy_y);
+    isqrt((squared * 1_000_000.) as u64) as u32
+}
+
+/// Integer square root, rounded down
+pub(crate) fn isqrt(n: u64) -> u64 {
+    if n < 2 {
+        return n;
+    }
+    // Newton's method, starting from a value that is guaranteed to be too large
+    let mut x = 1u64 << ((64 - n.leading_zeros() + 1) / 2);
+    loop {
+        let next = (x + n / x) / 2;
+        if next >= x {
+            return x;
+        }
+        x = next;
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "std")]
+mod test {
+    use crate::health::*;
+    use crate::*;
+
+    const T_S_US: u32 = 37;
+    const D_MICS_MM: u32 = 125;
+    const M: usize = 1024;
+    const N: usize = max_lags_size(T_S_US, D_MICS_MM);
+
+    /// Four channels carrying the same tone around the middle of the ADC range
+    fn healthy_frame() -> [MicArraySample; M] {
+        let mut samples = [[0i16; 4]; M];
+        samples.iter_mut().enumerate().for_each(|(n, s)| {
+            let t = n as f64 * T_S_US as f64 * 1e-6;
+            let envelope = 1. + 0.5 * (2. * std::f64::consts::PI * 23. * t).sin();
+            let v = 2048. + 500. * envelope * (2. * std::f64::consts::PI * 1000. * t).sin();
+            *s = [v as i16; 4];
+        });
+        samples
+    }
+
+    #[test]
+    fn test_isqrt() {
+        (0..10_000u64).for_each(|n| {
+            let r = isqrt(n);
+            assert!(r * r <= n && (r + 1) * (r + 1) > n);
+        });
+        assert_eq!(isqrt(u64::MAX), u32::MAX as u64);
+    }
+
+    #[test]
+    fn test_healthy() {
+        let limits = HealthLimits::saadc_12bit();
+        let samples = healthy_frame();
+        let health = FrameHealth::analyze(&samples, &limits);
+        health
+            .channels
+            .iter()
+            .for_each(|h| assert_eq!(h.check(&limits), Ok(())));
+
+        let channels = Channels::from_samples(samples);
+        let mut buf = [0i64; N];
+        let table = gen_lag_table::<T_S_US, D_MICS_MM, N>();
+        let angle = calc_angle_checked::<_, T_S_US, D_MICS_MM, N, M>(
+            &channels, 0, 1, &health, &limits, &mut buf, &table,
+        );
+        assert_eq!(angle, Ok(Bearing::BROADSIDE));
+
+        let mut buf = [0i64; N];
+        let estimate = estimate_pair_checked::<_, T_S_US, D_MICS_MM, N, M>(
+            &channels, 2, 3, &health, &limits, &mut buf, &table,
+        )
+        .unwrap();
+        assert_eq!((estimate.bearing, estimate.lag), (Bearing::BROADSIDE, 0));
+        assert!(estimate.correlation_permille > 990);
+    }
+
+    #[test]
+    fn test_faults() {
+        let limits = HealthLimits::saadc_12bit();
+        let mut samples = healthy_frame();
+        samples.iter_mut().enumerate().for_each(|(n, s)| {
+            // Unplugged mic, reading 0 with a little noise
+            s[0] = (n % 2) as i16;
+            // Overdriven mic
+            s[1] = (s[1] - 2048) * 6 + 2048;
+            s[1] = s[1].clamp(0, 4095);
+            // Mic stuck halfway through the frame
+            if n > M / 2 {
+                s[2] = 1000;
+            }
+        });
+        let health = FrameHealth::analyze(&samples, &limits);
+        assert_eq!(
+            health.channels[0].check(&limits),
+            Err(ChannelFault::Flatline)
+        );
+        assert_eq!(
+            health.channels[1].check(&limits),
+            Err(ChannelFault::Clipping)
+        );
+        assert_eq!(health.channels[2].check(&limits), Err(ChannelFault::Stuck));
+        assert_eq!(health.channels[3].check(&limits), Ok(()));
+
+        let channels = Channels::from_samples(samples);
+        let mut buf = [0i64; N];
+        let table = gen_lag_table::<T_S_US, D_MICS_MM, N>();
+        let angle = calc_angle_checked::<_, T_S_US, D_MICS_MM, N, M>(
+            &channels, 3, 2, &health, &limits, &mut buf, &table,
+        );
+        assert_eq!(
+            angle,
+            Err(PairError::UnhealthyChannel {
+                channel: 2,
+                fault: ChannelFault::Stuck
+            })
+        );
+    }
+
+    #[test]
+    fn test_saturation() {
+        let limits = HealthLimits {
+            clip_low: i16::MIN,
+            clip_high: i16::MAX,
+            ..HealthLimits::saadc_12bit()
+        };
+        let mut samples = healthy_frame();
+        samples.iter_mut().enumerate().for_each(|(n, s)| {
+            let wiggle = (n % 2) as i16;
+            s[3] = if n < 900 {
+                30000 + wiggle
+            } else {
+                -30000 - wiggle
+            };
+        });
+        let mut health = FrameHealth::analyze(&samples, &limits);
+        assert_eq!(health.channels[3].check(&limits), Ok(()));
+
+        // Subtracting the mean makes the last samples saturate
+        let channels = Channels::from_samples(samples);
+        health.count_saturation(&channels);
+        assert_eq!(health.channels[3].saturated, 124);
+        assert_eq!(health.channels[0].saturated, 0);
+        assert_eq!(
+            health.channels[3].check(&limits),
+            Err(ChannelFault::Saturation)
+        );
+    }
+
+    #[test]
+    fn test_uncorrelated() {
+        let limits = HealthLimits::saadc_12bit();
+        let mut samples = healthy_frame();
+        // Pseudo-random noise on channel 4
+        let mut state = 12345u32;
+        samples.iter_mut().for_each(|s| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            s[3] = 1548 + ((state >> 16) % 1000) as i16;
+        });
+        let health = FrameHealth::analyze(&samples, &limits);
+        let channels = Channels::from_samples(samples);
+        let mut buf = [0i64; N];
+        let table = gen_lag_table::<T_S_US, D_MICS_MM, N>();
+        let angle = calc_angle_checked::<_, T_S_US, D_MICS_MM, N, M>(
+            &channels, 2, 3, &health, &limits, &mut buf, &table,
+        );
+        assert!(matches!(angle, Err(PairError::Uncorrelated { .. })));
+    }
+}
@@ -1,47 +1,70 @@
 #![cfg_attr(not(feature = "std"), no_std)]
 
-use folley_format::device_to_server::MicArraySample;
+use folley_format::{device_to_server::MicArraySample, units::Bearing};
+
+pub mod dc;
+pub mod envelope;
+pub mod fft;
+pub mod health;
+pub mod motion;
+pub mod noise;
+pub mod resample;
+pub mod sample;
+pub mod scan;
+pub mod template;
+
+use dc::DcBlocker;
+use sample::Sample;
+
 const V_SOUND: i32 = 343;
 
-/// Calculate the cross-correlation of real-valued signals x and y. The result is put in the output buffer.
+/// Calculate the cross-correlation of real-valued signals x and y. The result is added to the output buffer.
 /// Make sure x and y are of the same length M, and the output buffer is of length N <= 2* M -1.M
+///
+/// For each lag, only the range of samples where x and y overlap is processed, so the inner loop
+/// has no bounds checks or branches. Products are summed in a narrow type where
+/// the sample type allows it, see [Sample::dot_acc].
 #[allow(non_snake_case)]
-pub fn xcorr_real<const XCORR_LEN: usize, const SIGNAL_LEN: usize>(
-    x: &[i16; SIGNAL_LEN],
-    y: &[i16; SIGNAL_LEN],
-    out: &mut [i64; XCORR_LEN],
+pub fn xcorr_real<S: Sample, const XCORR_LEN: usize, const SIGNAL_LEN: usize>(
+    x: &[S; SIGNAL_LEN],
+    y: &[S; SIGNAL_LEN],
+    out: &mut [S::Acc; XCORR_LEN],
 ) -> usize {
     debug_assert!(XCORR_LEN <= 2 * SIGNAL_LEN - 1);
     // This method may be improved by taking the Fourier transform X and Y of each of the signals x and Y,
     // multiplying the output of X with the complex conjugate of Y, and reverse-transform the product.
+    let partial_len = S::partial_len(x, y);
     let mut argmax = 0;
-    let mut max = 0;
-    for n in 0..XCORR_LEN {
-        for m in 0..SIGNAL_LEN {
-            let x_val = x[m] as i64;
-            let y_index = (n + m) as isize - (XCORR_LEN as isize) / 2;
-            let y_val = if y_index >= 0 {
-                *y.get(y_index as usize).unwrap_or(&0)
-            } else {
-                0
-            } as i64;
-            out[n] += x_val * y_val;
+    let mut max = S::Acc::default();
+    out.iter_mut().enumerate().for_each(|(n, out)| {
+        // Sample m of x is multiplied with sample m + shift of y
+        let shift = n as isize - (XCORR_LEN as isize) / 2;
+        let start = (-shift).clamp(0, SIGNAL_LEN as isize) as usize;
+        let end = (SIGNAL_LEN as isize - shift).clamp(0, SIGNAL_LEN as isize) as usize;
+        if start < end {
+            let y_start = (start as isize + shift) as usize;
+            S::dot_acc(
+                out,
+                &x[start..end],
+                &y[y_start..y_start + end - start],
+                partial_len,
+            );
         }
-        if out[n] > max {
-            max = out[n];
+        if *out > max {
+            max = *out;
             argmax = n;
         }
-    }
+    });
     argmax
 }
 
 /// Calculate the lag in sample numbers of signals x and y using cross-correlation. The buffer is used
 /// to store the cross-correlation output.
 #[allow(non_snake_case)]
-pub fn calc_lag<const XCORR_LEN: usize, const SIGNAL_LEN: usize>(
-    x: &[i16; SIGNAL_LEN],
-    y: &[i16; SIGNAL_LEN],
-    buf: &mut [i64; XCORR_LEN],
+pub fn calc_lag<S: Sample, const XCORR_LEN: usize, const SIGNAL_LEN: usize>(
+    x: &[S; SIGNAL_LEN],
+    y: &[S; SIGNAL_LEN],
+    buf: &mut [S::Acc; XCORR_LEN],
 ) -> isize {
     let argmax = xcorr_real(x, y, buf) as isize;
     let lag_offset = XCORR_LEN as isize / 2;
@@ -49,27 +72,31 @@ pub fn calc_lag<const XCORR_LEN: usize, const SIGNAL_LEN: usize>(
 }
 
 /// Calculate the angle of an audio source, using the cross correlation of two signals.
+/// To correct for the time between sampling x and y, pass a lag table generated by
+/// [scan::gen_skewed_lag_table].
 pub fn calc_angle<
+    S: Sample,
     const T_S_US: u32,
     const D_MICS_MM: u32,
     const XCORR_LEN: usize,
     const SIGNAL_LEN: usize,
 >(
-    x: &[i16; SIGNAL_LEN],
-    y: &[i16; SIGNAL_LEN],
-    buf: &mut [i64; XCORR_LEN],
+    x: &[S; SIGNAL_LEN],
+    y: &[S; SIGNAL_LEN],
+    buf: &mut [S::Acc; XCORR_LEN],
     lag_table: &[u32; XCORR_LEN],
-) -> u32 {
+) -> Bearing {
     let lag = calc_lag(x, y, buf) as i32;
     lag_to_angle::<T_S_US, D_MICS_MM, XCORR_LEN>(lag, lag_table)
 }
 
+/// Look up the bearing corresponding to a lag in a table generated by [gen_lag_table]
 pub fn lag_to_angle<const T_S_US: u32, const D_MICS_MM: u32, const LAGS_SIZE: usize>(
     lag: i32,
     table: &[u32; LAGS_SIZE],
-) -> u32 {
+) -> Bearing {
     let i = lag + LAGS_SIZE as i32 / 2;
-    table[i as usize]
+    Bearing::from_degrees(table[i as usize])
 }
 
 pub fn gen_lag_table<const T_S_US: u32, const D_MICS_MM: u32, const SIZE: usize>() -> [u32; SIZE] {
@@ -79,17 +106,20 @@ pub fn gen_lag_table<const T_S_US: u32, const D_MICS_MM: u32, const SIZE: usize>
     table.iter_mut().enumerate().for_each(|(lag, angle)| {
         let lag = lag as i32 - SIZE as i32 / 2;
         let cos_theta = (lag * T_S_US as i32 * V_SOUND) / D_MICS_MM as i32;
-        let theta = ACOS_TABLE
-            .iter()
-            .find(|(cos, _)| *cos <= cos_theta)
-            .map(|(_, deg)| *deg)
-            .unwrap_or(0);
-
-        *angle = theta as u32;
+        *angle = acos_deg(cos_theta);
     });
     table
 }
 
+/// Look up the angle in degrees of which the cosine, multiplied by 1000, equals `cos_theta`
+fn acos_deg(cos_theta: i32) -> u32 {
+    ACOS_TABLE
+        .iter()
+        .find(|(cos, _)| *cos <= cos_theta)
+        .map(|(_, deg)| *deg)
+        .unwrap_or(0) as u32
+}
+
 /// Given the distance between two microphones in millimeters, the sample period in microseconds,
 /// and the speed of sound in m/s, calculates the maximum number of samples possible between
 /// the moment the signal hits the first microphone and the moment it reaches the second.
@@ -98,52 +128,148 @@ pub const fn max_lags_size(sample_period_us: u32, mic_distance_mm: u32) -> usize
 }
 
 /// Representation of samples of 4 separate channels
-pub struct Channels<const SIGNAL_LEN: usize> {
-    pub ch1: [i16; SIGNAL_LEN],
-    pub ch2: [i16; SIGNAL_LEN],
-    pub ch3: [i16; SIGNAL_LEN],
-    pub ch4: [i16; SIGNAL_LEN],
+pub struct Channels<S: Sample, const SIGNAL_LEN: usize> {
+    pub ch1: [S; SIGNAL_LEN],
+    pub ch2: [S; SIGNAL_LEN],
+    pub ch3: [S; SIGNAL_LEN],
+    pub ch4: [S; SIGNAL_LEN],
+}
+
+/// The way the DC component is removed from each channel when preparing [Channels]
+pub enum DcRemoval<'a> {
+    /// Subtract the mean of each channel in the current frame
+    FrameMean,
+    /// Run each channel through its own [DcBlocker], which keeps its state between frames
+    Blocker(&'a mut [DcBlocker; 4]),
 }
 
-impl<const SIGNAL_LEN: usize> Channels<SIGNAL_LEN> {
+impl<const SIGNAL_LEN: usize> Channels<i16, SIGNAL_LEN> {
     /// Put samples into four separate arrays and bundle them in a Channels object.
     /// In this method, the channel mean is subtracted from each sample,
     /// in order to make the DC value ~ zero. This improves cross correlation.
     pub fn from_samples(samples: [MicArraySample; SIGNAL_LEN]) -> Self {
-        let mut chans = Channels {
-            ch1: [0i16; SIGNAL_LEN],
-            ch2: [0i16; SIGNAL_LEN],
-            ch3: [0i16; SIGNAL_LEN],
-            ch4: [0i16; SIGNAL_LEN],
-        };
-        let mut totals = [0i64; 4];
-        samples.into_iter().enumerate().for_each(|(i, s)| {
-            chans.ch1[i] = s[0];
-            totals[0] += s[0] as i64;
-            chans.ch2[i] = s[1];
-            totals[1] += s[1] as i64;
-            chans.ch3[i] = s[2];
-            totals[2] += s[2] as i64;
-            chans.ch4[i] = s[3];
-            totals[3] += s[3] as i64;
-        });
+        Self::from_samples_with(samples, DcRemoval::FrameMean)
+    }
 
+    /// Put samples into four separate arrays and bundle them in a Channels object,
+    /// removing the DC component of each channel as specified by `dc_removal`.
+    pub fn from_samples_with(samples: [MicArraySample; SIGNAL_LEN], dc_removal: DcRemoval) -> Self {
+        match dc_removal {
+            DcRemoval::FrameMean => Self::from_frames(&samples),
+            DcRemoval::Blocker(blockers) => {
+                let mut chans = Self::split(&samples);
+                chans
+                    .channels_mut()
+                    .iter_mut()
+                    .zip(blockers.iter_mut())
+                    .for_each(|(ch, blocker)| blocker.process_slice(&mut ch[..]));
+                chans
+            }
+        }
+    }
+
+    /// Prepare a frame that is shorter than `SIGNAL_LEN` like [Channels::from_samples_with].
+    /// The DC component is removed using the samples in the frame only, and each channel
+    /// is padded with zeros.
+    pub fn from_samples_padded(samples: &[MicArraySample], dc_removal: DcRemoval) -> Self {
+        assert!(samples.len() <= SIGNAL_LEN);
+        let len = samples.len();
+        let mut chans = Self {
+            ch1: [0; SIGNAL_LEN],
+            ch2: [0; SIGNAL_LEN],
+            ch3: [0; SIGNAL_LEN],
+            ch4: [0; SIGNAL_LEN],
+        };
+        let mut blockers = match dc_removal {
+            DcRemoval::Blocker(blockers) => Some(blockers),
+            DcRemoval::FrameMean => None,
+        };
         chans
             .channels_mut()
             .iter_mut()
             .enumerate()
             .for_each(|(i, ch)| {
-                let mean = totals[i] / SIGNAL_LEN as i64;
-                let mean = mean as i16;
-                ch.iter_mut().for_each(|s| *s = s.saturating_sub(mean));
+                let ch = &mut ch[..len];
+                ch.iter_mut()
+                    .zip(samples)
+                    .for_each(|(s, sample)| *s = sample[i]);
+                match blockers.as_mut() {
+                    Some(blockers) => blockers[i].process_slice(ch),
+                    None if len > 0 => {
+                        let total = ch.iter().map(|s| s.to_acc()).sum();
+                        let mean = i16::mean(total, len);
+                        ch.iter_mut().for_each(|s| *s = s.saturating_sub(mean));
+                    }
+                    None => {}
+                }
             });
+        chans
+    }
+}
 
+impl<S: Sample, const SIGNAL_LEN: usize> Channels<S, SIGNAL_LEN> {
+    /// Put samples of any [Sample] type into four separate arrays and bundle them in
+    /// a Channels object, subtracting the channel mean from each sample.
+    pub fn from_frames(samples: &[[S; 4]; SIGNAL_LEN]) -> Self {
+        let mut chans = Self::split(samples);
+        chans.channels_mut().iter_mut().for_each(|ch| {
+            let mut total = S::Acc::default();
+            ch.iter().for_each(|s| total += s.to_acc());
+            let mean = S::mean(total, SIGNAL_LEN);
+            ch.iter_mut().for_each(|s| *s = s.saturating_sub(mean));
+        });
         chans
     }
 
-    fn channels_mut(&mut self) -> [&mut [i16; SIGNAL_LEN]; 4] {
+    /// Convert each sample, e.g. to process the channels using another [Sample] type
+    pub fn map<T: Sample, F: Fn(S) -> T>(&self, f: F) -> Channels<T, SIGNAL_LEN> {
+        let mut chans = Channels {
+            ch1: [T::default(); SIGNAL_LEN],
+            ch2: [T::default(); SIGNAL_LEN],
+            ch3: [T::default(); SIGNAL_LEN],
+            ch4: [T::default(); SIGNAL_LEN],
+        };
+        chans
+            .channels_mut()
+            .iter_mut()
+            .zip(self.channels())
+            .for_each(|(to, from)| {
+                to.iter_mut()
+                    .zip(from.iter())
+                    .for_each(|(to, from)| *to = f(*from))
+            });
+        chans
+    }
+
+    /// Get channel `i`, counting from 0
+    pub fn channel(&self, i: usize) -> &[S; SIGNAL_LEN] {
+        self.channels()[i]
+    }
+
+    fn channels(&self) -> [&[S; SIGNAL_LEN]; 4] {
+        [&self.ch1, &self.ch2, &self.ch3, &self.ch4]
+    }
+
+    fn channels_mut(&mut self) -> [&mut [S; SIGNAL_LEN]; 4] {
         [&mut self.ch1, &mut self.ch2, &mut self.ch3, &mut self.ch4]
     }
+
+    /// Put samples into four separate arrays, leaving them as they are
+    fn split(samples: &[[S; 4]; SIGNAL_LEN]) -> Self {
+        let mut chans = Channels {
+            ch1: [S::default(); SIGNAL_LEN],
+            ch2: [S::default(); SIGNAL_LEN],
+            ch3: [S::default(); SIGNAL_LEN],
+            ch4: [S::default(); SIGNAL_LEN],
+        };
+        samples.iter().enumerate().for_each(|(i, s)| {
+            chans.ch1[i] = s[0];
+            chans.ch2[i] = s[1];
+            chans.ch3[i] = s[2];
+            chans.ch4[i] = s[3];
+        });
+        chans
+    }
 }
 
 #[cfg(test)]
@@ -179,9 +305,10 @@ mod test {
         const M: usize = 10;
         const N: usize = 2 * M - 1;
 
+        // Of the first samples of data/74us/sine_45d_1372hz_small, which `samples` links to
         const EXPECTED: [i64; N] = [
-            -38192, -78724, -49321, 24901, 87948, 159501, 168362, 80307, -28044, -148461, -192540,
-            -134940, -52434, 9653, 71414, 66513, 39441, 21076, -6440,
+            -102512, -175882, -64168, 296735, 743989, 1016752, 886006, 285357, -643149, -1487399,
+            -1670037, -1205367, -403195, 332223, 742289, 757067, 497033, 182186, 12072,
         ];
 
         let samples: [_; M] = read_samples::<M>().try_into().unwrap();
@@ -193,6 +320,112 @@ mod test {
         (0..N).for_each(|i| assert_eq!(out[i], EXPECTED[i]));
     }
 
+    /// The straightforward implementation of [xcorr_real], checking the bounds of y for every product
+    fn xcorr_reference<S: Sample, const XCORR_LEN: usize, const SIGNAL_LEN: usize>(
+        x: &[S; SIGNAL_LEN],
+        y: &[S; SIGNAL_LEN],
+        out: &mut [S::Acc; XCORR_LEN],
+    ) {
+        (0..XCORR_LEN).for_each(|n| {
+            (0..SIGNAL_LEN).for_each(|m| {
+                let y_index = (n + m) as isize - (XCORR_LEN as isize) / 2;
+                if y_index >= 0 && (y_index as usize) < SIGNAL_LEN {
+                    out[n] += x[m].mul_acc(y[y_index as usize]);
+                }
+            })
+        });
+    }
+
+    fn noise<const M: usize>(seed: u32, amplitude: i32) -> [i16; M] {
+        let mut state = seed;
+        let mut signal = [0i16; M];
+        signal.iter_mut().for_each(|s| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            *s = ((state >> 8) as i32 % (amplitude + 1)).clamp(i16::MIN as i32, i16::MAX as i32)
+                as i16;
+        });
+        signal
+    }
+
+    #[test]
+    pub fn test_xcorr_matches_reference() {
+        const M: usize = 257;
+        const N: usize = 2 * M - 1;
+        // 12 bit samples, full scale samples that can't be summed in an i32, and extremes
+        let mut extreme = [i16::MIN; M];
+        extreme.iter_mut().step_by(3).for_each(|s| *s = i16::MAX);
+        let signals = [
+            (noise::<M>(1, 2048), noise::<M>(2, 2048)),
+            (noise::<M>(3, 40_000), noise::<M>(4, 40_000)),
+            (extreme, noise::<M>(5, 40_000)),
+        ];
+        signals.iter().for_each(|(x, y)| {
+            // Full and partial lag ranges, adding to a buffer that is not zeroed
+            let mut out = [7i64; N];
+            let mut expected = [7i64; N];
+            xcorr_real(x, y, &mut out);
+            xcorr_reference(x, y, &mut expected);
+            assert_eq!(out, expected);
+
+            let mut out = [-3i64; 9];
+            let mut expected = [-3i64; 9];
+            xcorr_real(x, y, &mut out);
+            xcorr_reference(x, y, &mut expected);
+            assert_eq!(out, expected);
+
+            let (x, y) = (x.map(f32::from), y.map(|s| f32::from(s) / 3.));
+            let mut out = [0.1f32; N];
+            let mut expected = [0.1f32; N];
+            xcorr_real(&x, &y, &mut out);
+            xcorr_reference(&x, &y, &mut expected);
+            assert_eq!(out, expected);
+        });
+    }
+
+    #[test]
+    pub fn test_from_samples_dc_blocked() {
+        const M: usize = 1024;
+        let samples = read_samples::<{ 2 * M }>();
+        let mut blockers = [dc::DcBlocker::with_cutoff(20, 74); 4];
+
+        let frames: Vec<Channels<i16, M>> = samples
+            .chunks(M)
+            .map(|c| {
+                Channels::from_samples_with(
+                    c.try_into().unwrap(),
+                    DcRemoval::Blocker(&mut blockers),
+                )
+            })
+            .collect();
+
+        // The filter state is carried over to the next frame
+        let mut ch1: Vec<i16> = samples.iter().map(|s| s[0]).collect();
+        dc::DcBlocker::with_cutoff(20, 74).process_slice(&mut ch1);
+        assert_eq!(&frames[0].ch1[..], &ch1[..M]);
+        assert_eq!(&frames[1].ch1[..], &ch1[M..]);
+    }
+
+    #[test]
+    pub fn test_from_samples_padded() {
+        const M: usize = 1024;
+        let samples = read_samples::<M>();
+
+        // A full frame is prepared like any other
+        let full = Channels::<i16, M>::from_samples_padded(&samples, DcRemoval::FrameMean);
+        let expected = Channels::<i16, M>::from_samples(samples.clone().try_into().unwrap());
+        assert_eq!(full.ch3, expected.ch3);
+
+        let mut blockers = [dc::DcBlocker::with_cutoff(20, 74); 4];
+        let short = Channels::<i16, M>::from_samples_padded(
+            &samples[..M / 2],
+            DcRemoval::Blocker(&mut blockers),
+        );
+        let mut ch2: Vec<i16> = samples[..M / 2].iter().map(|s| s[1]).collect();
+        dc::DcBlocker::with_cutoff(20, 74).process_slice(&mut ch2);
+        assert_eq!(&short.ch2[..M / 2], &ch2[..]);
+        assert!(short.ch2[M / 2..].iter().all(|&s| s == 0));
+    }
+
     #[test]
     pub fn test_calc_lag() {
         const M: usize = 1024;
@@ -212,8 +445,9 @@ mod test {
         let channels = Channels::from_samples(samples);
         let mut buf = [0i64; N];
         let lag_table = gen_lag_table::<74, 125, N>();
-        let theta = calc_angle::<74, 125, N, M>(&channels.ch1, &channels.ch2, &mut buf, &lag_table);
-        assert_eq!(theta, 145);
+        let theta =
+            calc_angle::<_, 74, 125, N, M>(&channels.ch1, &channels.ch2, &mut buf, &lag_table);
+        assert_eq!(theta.degrees(), 145);
     }
 
     #[test]
@@ -275,7 +509,10 @@ mod test {
         ];
         let lag_table = gen_lag_table::<14, 125, 53>();
         FLOORED_LAG_ANGLES.iter().for_each(|(lag, angle)| {
-            assert_eq!(lag_to_angle::<14, 125, 53>(*lag, &lag_table), *angle)
+            assert_eq!(
+                lag_to_angle::<14, 125, 53>(*lag, &lag_table).degrees(),
+                *angle
+            )
         });
     }
 }
@@ -0,0 +1,184 @@
+use folley_format::{motion::AxisLimits, units::Degrees};
+
+use crate::health::isqrt;
+
+/// One axis of a pan/tilt bracket, moving towards its goal while speeding up and slowing
+/// down within its [AxisLimits]. The position is kept in millidegrees, so that slow speeds
+/// still move the axis a bit every step.
+#[derive(Debug, Clone, Copy)]
+pub struct Axis {
+    pub goal: Degrees,
+    pub limits: AxisLimits,
+    position_mdeg: i64,
+    /// Signed, in millidegrees per second
+    velocity_mdeg_s: i64,
+}
+
+impl Axis {
+    /// An axis at standstill at zero degrees
+    pub const fn new(limits: AxisLimits) -> Self {
+        Self {
+            goal: Degrees::ZERO,
+            limits,
+            position_mdeg: 0,
+            velocity_mdeg_s: 0,
+        }
+    }
+
+    /// Position rounded to the nearest degree
+    pub fn position(&self) -> Degrees {
+        Degrees((self.position_mdeg + 500).div_euclid(1000) as i32)
+    }
+
+    pub fn is_at_goal(&self) -> bool {
+        self.velocity_mdeg_s == 0 && self.position_mdeg == self.goal.0 as i64 * 1000
+    }
+
+    /// Stop at the nearest degree, without slowing down first
+    pub fn hold(&mut self) {
+        self.goal = self.position();
+        self.position_mdeg = self.goal.0 as i64 * 1000;
+        self.velocity_mdeg_s = 0;
+    }
+
+    /// Move towards the goal for a step of `step_ms` milliseconds
+    pub fn step(&mut self, step_ms: u32) {
+        let step_ms = step_ms as i64;
+        let distance = self.goal.0 as i64 * 1000 - self.position_mdeg;
+        let max_speed = self.limits.max_speed_deg_s as i64 * 1000;
+        let acceleration = self.limits.acceleration_deg_s2 as i64 * 1000;
+        let velocity = if acceleration == 0 {
+            max_speed * distance.signum()
+        } else {
+            // The fastest the axis can go and still come to a halt at the goal
+            let braking_speed = isqrt(2 * acceleration as u64 * distance.unsigned_abs()) as i64;
+            let wanted = max_speed.min(braking_speed) * distance.signum();
+            let change = acceleration * step_ms / 1000;
+            wanted.clamp(self.velocity_mdeg_s - change, self.velocity_mdeg_s + change)
+        };
+        let moved = match velocity * step_ms / 1000 {
+            // Don't get stuck just short of the goal
+            0 => velocity.signum(),
+            moved => moved,
+        };
+        if distance == 0 || (moved.signum() == distance.signum() && moved.abs() >= distance.abs()) {
+            self.position_mdeg += distance;
+            self.velocity_mdeg_s = 0;
+        } else {
+            self.position_mdeg += moved;
+            self.velocity_mdeg_s = velocity;
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "std")]
+mod test {
+    use crate::motion::*;
+
+    const STEP_MS: u32 = 20;
+
+    /// Step `axis` until it reaches its goal, returning the position and velocity after
+    /// each step
+    fn run(axis: &mut Axis, max_steps: usize) -> Vec<(i64, i64)> {
+        let mut trace = vec![];
+        while !axis.is_at_goal() {
+            assert!(trace.len() < max_steps, "axis didn't reach its goal");
+            axis.step(STEP_MS);
+            trace.push((axis.position_mdeg, axis.velocity_mdeg_s));
+        }
+        trace
+    }
+
+    /// Check that the speed and the changes in speed between `trace` and the
+    /// state it started from stay within `limits`
+    fn assert_within(limits: AxisLimits, start: (i64, i64), trace: &[(i64, i64)]) {
+        let max_moved = (limits.max_speed_deg_s * STEP_MS) as i64;
+        let max_change = (limits.acceleration_deg_s2 * STEP_MS) as i64;
+        let mut prev = start;
+        trace
+            .iter()
+            .enumerate()
+            .for_each(|(i, &(position, velocity))| {
+                assert!(
+                    (position - prev.0).abs() <= max_moved,
+                    "too fast at step {}",
+                    i
+                );
+                // Arriving at the goal halts the axis
+                if i + 1 < trace.len() {
+                    assert!(
+                        (velocity - prev.1).abs() <= max_change,
+                        "too much acceleration at step {}",
+                        i
+                    );
+                }
+                prev = (position, velocity);
+            });
+    }
+
+    #[test]
+    fn test_reaches_goal() {
+        let limits = AxisLimits {
+            max_speed_deg_s: 60,
+            acceleration_deg_s2: 120,
+        };
+        let mut axis = Axis::new(limits);
+        axis.goal = Degrees(90);
+        let trace = run(&mut axis, 1000);
+
+        assert_eq!(axis.position(), Degrees(90));
+        assert!(trace.windows(2).all(|w| w[0].0 <= w[1].0));
+        assert!(trace.iter().all(|&(position, _)| position <= 90_000));
+        assert_within(limits, (0, 0), &trace);
+        // Up to full speed and back down: 1.5 s at full speed, 0.5 s each way
+        assert!(trace.iter().any(|&(_, velocity)| velocity == 60_000));
+        assert!((95..=110).contains(&trace.len()));
+
+        // Without acceleration, the axis moves at full speed right away
+        axis.limits.acceleration_deg_s2 = 0;
+        axis.goal = Degrees(30);
+        let trace = run(&mut axis, 1000);
+        assert_eq!(trace[0], (88_800, -60_000));
+        assert_eq!(axis.position(), Degrees(30));
+        assert_eq!(trace.len(), 50);
+    }
+
+    #[test]
+    fn test_reverse() {
+        let limits = AxisLimits {
+            max_speed_deg_s: 90,
+            acceleration_deg_s2: 180,
+        };
+        let mut axis = Axis::new(limits);
+        axis.goal = Degrees(180);
+        (0..40).for_each(|_| axis.step(STEP_MS));
+        let start = (axis.position_mdeg, axis.velocity_mdeg_s);
+        assert_eq!(start.1, 90_000);
+
+        // Sent back while at full speed, it slows down before heading back
+        axis.goal = Degrees(20);
+        let trace = run(&mut axis, 1000);
+        assert_eq!(axis.position(), Degrees(20));
+        assert_within(limits, start, &trace);
+        let furthest = trace.iter().map(|&(position, _)| position).max().unwrap();
+        assert!(furthest > start.0);
+        assert!(trace.iter().all(|&(position, _)| position >= 20_000));
+    }
+
+    #[test]
+    fn test_slow() {
+        let limits = AxisLimits {
+            max_speed_deg_s: 1,
+            acceleration_deg_s2: 1,
+        };
+        let mut axis = Axis::new(limits);
+        axis.goal = Degrees(2);
+        let trace = run(&mut axis, 1000);
+
+        // Every step gets the axis closer, even while it is too slow to move a millidegree a step
+        assert!(trace.windows(2).all(|w| w[0].0 < w[1].0));
+        assert_within(limits, (0, 0), &trace);
+        assert_eq!(axis.position(), Degrees(2));
+    }
+}
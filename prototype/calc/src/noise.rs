@@ -0,0 +1,253 @@
+use crate::{
+    fft::{fft, ifft, Complex},
+    health::FrameHealth,
+    sample::Sample,
+    Channels,
+};
+
+/// Parameters of the spectral noise suppression
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SuppressionParams {
+    /// Factor the noise power is multiplied with before it is subtracted.
+    /// Values above 1 suppress more noise, at the cost of more distortion.
+    pub over_subtraction: f32,
+    /// Minimum gain applied to any frequency bin, to avoid musical noise
+    pub gain_floor: f32,
+    /// Amount of frames the noise profile is averaged over. Once this many frames have been
+    /// learned, older frames are forgotten exponentially.
+    pub max_learn_frames: u32,
+}
+
+impl Default for SuppressionParams {
+    fn default() -> Self {
+        Self {
+            over_subtraction: 1.5,
+            gain_floor: 0.05,
+            max_learn_frames: 32,
+        }
+    }
+}
+
+/// Per-channel noise power spectrum, learned from frames that contain no signal of interest.
+/// Used to suppress stationary noise in incoming frames before correlating them.
+pub struct NoiseProfile<const LEN: usize> {
+    power: [[f32; LEN]; 4],
+    frames: u32,
+}
+
+impl<const LEN: usize> NoiseProfile<LEN> {
+    /// Create an empty noise profile
+    pub const fn new() -> Self {
+        Self {
+            power: [[0.; LEN]; 4],
+            frames: 0,
+        }
+    }
+
+    /// Restore a noise profile from previously saved power spectra
+    pub const fn from_power(power: [[f32; LEN]; 4], frames: u32) -> Self {
+        Self { power, frames }
+    }
+
+    /// The learned noise power spectrum of each channel
+    pub fn power(&self) -> &[[f32; LEN]; 4] {
+        &self.power
+    }
+
+    /// Amount of frames the profile was learned from
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    /// Whether any noise has been learned yet
+    pub fn is_learned(&self) -> bool {
+        self.frames > 0
+    }
+
+    /// Forget the learned noise
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Update the noise profile with a frame that is known to be silent
+    pub fn learn<S: Sample>(&mut self, channels: &Channels<S, LEN>, params: &SuppressionParams) {
+        let weight = 1. / (self.frames + 1).min(params.max_learn_frames.max(1)) as f32;
+        let mut buf = [Complex::ZERO; LEN];
+
+        (0..4).for_each(|ch| {
+            to_spectrum(channels.channel(ch), &mut buf);
+            self.power[ch]
+                .iter_mut()
+                .zip(buf.iter())
+                .for_each(|(noise, bin)| *noise += (bin.norm_sqr() - *noise) * weight);
+        });
+        self.frames = self.frames.saturating_add(1);
+    }
+
+    /// Suppress the learned noise in each channel using a Wiener-style gain per frequency bin:
+    /// `G = max(gain_floor, 1 - over_subtraction * N / P)`, where `P` is the power of the
+    /// bin in the incoming frame and `N` the learned noise power.
+    pub fn suppress<S: Sample>(&self, channels: &mut Channels<S, LEN>, params: &SuppressionParams) {
+        if !self.is_learned() {
+            return;
+        }
+        let mut buf = [Complex::ZERO; LEN];
+
+        channels
+            .channels_mut()
+            .iter_mut()
+            .zip(self.power.iter())
+            .for_each(|(ch, noise)| {
+                to_spectrum(&ch[..], &mut buf);
+                buf.iter_mut().zip(noise.iter()).for_each(|(bin, noise)| {
+                    let power = bin.norm_sqr();
+                    let gain = if power > 0. {
+                        1. - params.over_subtraction * noise / power
+                    } else {
+                        0.
+                    };
+                    *bin = bin.scale(gain.max(params.gain_floor));
+                });
+                ifft(&mut buf);
+                ch.iter_mut()
+                    .zip(buf.iter())
+                    .for_each(|(s, bin)| *s = S::from_f32(bin.re));
+            });
+    }
+}
+
+impl<const LEN: usize> Default for NoiseProfile<LEN> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Decide whether a frame is silent, i.e. whether all channels have an RMS value
+/// of at most `max_rms`. Silent frames can be used to learn the noise profile on the fly.
+pub fn is_silent(health: &FrameHealth, max_rms: u32) -> bool {
+    health.channels.iter().all(|ch| ch.rms <= max_rms)
+}
+
+fn to_spectrum<S: Sample>(signal: &[S], buf: &mut [Complex]) {
+    buf.iter_mut()
+        .zip(signal.iter())
+        .for_each(|(bin, s)| *bin = Complex::new(s.to_f32(), 0.));
+    fft(buf);
+}
+
+#[cfg(test)]
+#[cfg(feature = "std")]
+mod test {
+    use crate::health::{FrameHealth, HealthLimits};
+    use crate::noise::*;
+    use folley_format::device_to_server::MicArraySample;
+
+    const M: usize = 1024;
+    const T_S_US: f64 = 37e-6;
+
+    struct Lcg(u32);
+
+    impl Lcg {
+        /// Roughly uniform noise between -amplitude and amplitude
+        fn next(&mut self, amplitude: i32) -> i16 {
+            self.0 = self.0.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (((self.0 >> 16) % (2 * amplitude as u32 + 1)) as i32 - amplitude) as i16
+        }
+    }
+
+    /// Stationary noise consisting of a 4 kHz hum and white noise, optionally with a 1 kHz tone
+    fn frame(rng: &mut Lcg, frame_index: usize, tone: bool) -> [MicArraySample; M] {
+        let mut samples = [[0i16; 4]; M];
+        samples.iter_mut().enumerate().for_each(|(n, s)| {
+            let t = (frame_index * M + n) as f64 * T_S_US;
+            let hum = 200. * (2. * std::f64::consts::PI * 4000. * t).sin();
+            let tone = if tone {
+                300. * (2. * std::f64::consts::PI * 1000. * t).sin()
+            } else {
+                0.
+            };
+            (0..4).for_each(|ch| s[ch] = 2048 + (hum + tone) as i16 + rng.next(30));
+        });
+        samples
+    }
+
+    fn energy(channels: &Channels<i16, M>, ch: usize) -> f64 {
+        channels
+            .channel(ch)
+            .iter()
+            .map(|s| *s as f64 * *s as f64)
+            .sum()
+    }
+
+    #[test]
+    fn test_learn_and_suppress() {
+        let params = SuppressionParams::default();
+        let mut rng = Lcg(1);
+        let mut profile = Box::new(NoiseProfile::<M>::new());
+        assert!(!profile.is_learned());
+
+        (0..8).for_each(|i| {
+            let silent = Channels::from_samples(frame(&mut rng, i, false));
+            profile.learn(&silent, &params);
+        });
+        assert_eq!(profile.frames(), 8);
+
+        // Noise only frame is suppressed heavily
+        let mut noise = Channels::from_samples(frame(&mut rng, 8, false));
+        let noise_energy = energy(&noise, 0);
+        profile.suppress(&mut noise, &params);
+        assert!(energy(&noise, 0) < noise_energy / 50.);
+
+        // A tone is retained
+        let mut tonal = Channels::from_samples(frame(&mut rng, 9, true));
+        let mut clean = Channels::<i16, M>::from_samples({
+            let mut samples = [[0i16; 4]; M];
+            samples.iter_mut().enumerate().for_each(|(n, s)| {
+                let t = (9 * M + n) as f64 * T_S_US;
+                *s = [(300. * (2. * std::f64::consts::PI * 1000. * t).sin()) as i16; 4];
+            });
+            samples
+        });
+        profile.suppress(&mut tonal, &params);
+        let error: f64 = tonal
+            .channel(1)
+            .iter()
+            .zip(clean.channel(1).iter())
+            .map(|(a, b)| (*a as f64 - *b as f64).powi(2))
+            .sum();
+        assert!(error < energy(&clean, 1) / 10.);
+
+        // An empty profile leaves the signal alone
+        let original = clean.ch3;
+        NoiseProfile::<M>::new().suppress(&mut clean, &params);
+        assert_eq!(original, clean.ch3);
+    }
+
+    #[test]
+    fn test_restore() {
+        let params = SuppressionParams::default();
+        let mut rng = Lcg(2);
+        let mut profile = Box::new(NoiseProfile::<M>::new());
+        profile.learn(&Channels::from_samples(frame(&mut rng, 0, false)), &params);
+
+        let restored = Box::new(NoiseProfile::from_power(*profile.power(), profile.frames()));
+        let samples = frame(&mut rng, 1, true);
+        let mut a = Channels::from_samples(samples);
+        let mut b = Channels::from_samples(samples);
+
+        profile.suppress(&mut a, &params);
+        restored.suppress(&mut b, &params);
+        assert_eq!(a.ch4, b.ch4);
+    }
+
+    #[test]
+    fn test_is_silent() {
+        let limits = HealthLimits::saadc_12bit();
+        let mut rng = Lcg(3);
+        let silent = FrameHealth::analyze(&frame(&mut rng, 0, false), &limits);
+        let loud = FrameHealth::analyze(&frame(&mut rng, 1, true), &limits);
+        assert!(is_silent(&silent, 200));
+        assert!(!is_silent(&loud, 200));
+    }
+}
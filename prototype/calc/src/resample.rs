@@ -0,0 +1,245 @@
+use folley_format::device_to_server::MicArraySample;
+
+use crate::{fft::sin_cos_turn, sample::Sample};
+
+/// Cutoff frequency of the anti-aliasing filter, relative to the lower of
+/// the input and output Nyquist frequencies
+const CUTOFF_NUM: i64 = 4;
+const CUTOFF_DEN: i64 = 5;
+
+/// Streaming polyphase FIR resampler for four channels, changing the sample rate
+/// by a rational factor `up / down`.
+///
+/// The anti-aliasing filter is a Blackman-windowed sinc of at most `TAPS` coefficients,
+/// designed at the upsampled rate. The more taps, the steeper the filter: for a
+/// decimation factor D, at least `16 * D` taps are advised. The filter state is kept
+/// between calls to [Resampler::process], so consecutive frames can be processed
+/// without discontinuities. The output is delayed by half the filter length.
+#[derive(Debug, Clone)]
+pub struct Resampler<const TAPS: usize> {
+    up: usize,
+    down: usize,
+    taps_per_phase: usize,
+    /// Coefficient `t` of polyphase component `p` is stored at `p * taps_per_phase + t`
+    coeffs: [f32; TAPS],
+    /// Ring buffer of the last `taps_per_phase` input samples
+    history: [[f32; 4]; TAPS],
+    pos: usize,
+    phase: usize,
+}
+
+impl<const TAPS: usize> Resampler<TAPS> {
+    /// Create a resampler that changes the sample rate by a factor `up / down`
+    pub fn new(up: usize, down: usize) -> Self {
+        assert!(
+            up > 0 && down > 0,
+            "Resampling factors must be greater than 0"
+        );
+        let divisor = gcd(up, down);
+        let (up, down) = (up / divisor, down / divisor);
+        let taps_per_phase = if up == down { 1 } else { TAPS / up };
+        assert!(
+            taps_per_phase > 0,
+            "Not enough taps for the interpolation factor"
+        );
+
+        let mut resampler = Self {
+            up,
+            down,
+            taps_per_phase,
+            coeffs: [0.; TAPS],
+            history: [[0.; 4]; TAPS],
+            pos: 0,
+            phase: 0,
+        };
+        resampler.design();
+        resampler
+    }
+
+    /// Create a resampler that lowers the sample rate by an integer factor
+    pub fn decimator(factor: usize) -> Self {
+        Self::new(1, factor)
+    }
+
+    /// The resampling factor `(up, down)`, reduced to its lowest terms
+    pub fn ratio(&self) -> (usize, usize) {
+        (self.up, self.down)
+    }
+
+    /// Forget the filter state
+    pub fn reset(&mut self) {
+        self.history = [[0.; 4]; TAPS];
+        self.pos = 0;
+        self.phase = 0;
+    }
+
+    /// Resample a block of input samples, calling `output` for every output sample
+    pub fn process<S: Sample, F: FnMut([S; 4])>(&mut self, input: &[[S; 4]], mut output: F) {
+        let taps = self.taps_per_phase;
+        input.iter().for_each(|x| {
+            self.pos = (self.pos + 1) % taps;
+            self.history[self.pos] = x.map(|s| s.to_f32());
+
+            while self.phase < self.up {
+                let coeffs = &self.coeffs[self.phase * taps..(self.phase + 1) * taps];
+                let mut y = [0f32; 4];
+                coeffs.iter().enumerate().for_each(|(k, c)| {
+                    let x = &self.history[(self.pos + taps - k) % taps];
+                    y.iter_mut().zip(x.iter()).for_each(|(y, x)| *y += c * x);
+                });
+                output(y.map(S::from_f32));
+                self.phase += self.down;
+            }
+            self.phase -= self.up;
+        });
+    }
+
+    /// Resample raw microphone array samples
+    pub fn process_samples<F: FnMut(MicArraySample)>(
+        &mut self,
+        input: &[MicArraySample],
+        output: F,
+    ) {
+        self.process(input, output)
+    }
+
+    /// Design the anti-aliasing filter and split it into its polyphase components
+    fn design(&mut self) {
+        let len = self.up * self.taps_per_phase;
+        if len == 1 {
+            self.coeffs[0] = 1.;
+            return;
+        }
+        let max = self.up.max(self.down) as i64;
+
+        let mut total = 0.;
+        (0..len).for_each(|n| {
+            // sinc(2 * f_c * (n - center)) with 2 * f_c = CUTOFF / max, written as sin(pi * a / b)
+            // divided by pi * a / b
+            let a = CUTOFF_NUM * (2 * n as i64 - len as i64 + 1);
+            let b = CUTOFF_DEN * 2 * max;
+            let sinc = if a == 0 {
+                1.
+            } else {
+                let (sin, _) = sin_cos_turn(a.rem_euclid(2 * b) as usize, 2 * b as usize);
+                sin / (core::f32::consts::PI * a as f32 / b as f32)
+            };
+            let (_, cos1) = sin_cos_turn(n, len - 1);
+            let (_, cos2) = sin_cos_turn(2 * n, len - 1);
+            let window = 0.42 - 0.5 * cos1 + 0.08 * cos2;
+
+            let h = sinc * window;
+            self.coeffs[(n % self.up) * self.taps_per_phase + n / self.up] = h;
+            total += h;
+        });
+        // Unity gain at DC, compensating for the zeros inserted while upsampling
+        let gain = self.up as f32 / total;
+        self.coeffs[..len].iter_mut().for_each(|c| *c *= gain);
+    }
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "std")]
+mod test {
+    use crate::resample::*;
+
+    const TAPS: usize = 128;
+
+    fn tone(len: usize, cycles_per_sample: f64, amplitude: f64) -> Vec<MicArraySample> {
+        (0..len)
+            .map(|n| {
+                let v =
+                    amplitude * (2. * std::f64::consts::PI * cycles_per_sample * n as f64).sin();
+                [v as i16, 1000, -(v as i16), 0]
+            })
+            .collect()
+    }
+
+    fn resample(resampler: &mut Resampler<TAPS>, input: &[MicArraySample]) -> Vec<MicArraySample> {
+        let mut out = vec![];
+        resampler.process_samples(input, |s| out.push(s));
+        out
+    }
+
+    /// Peak amplitude of a channel, after the filter has settled
+    fn amplitude(samples: &[MicArraySample], ch: usize) -> i16 {
+        samples[samples.len() / 2..]
+            .iter()
+            .map(|s| s[ch].abs())
+            .max()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_decimate() {
+        let mut decimator = Resampler::<TAPS>::decimator(4);
+        let out = resample(&mut decimator, &tone(4096, 0.02, 1000.));
+        assert_eq!(out.len(), 1024);
+        // Pass band tone and DC are retained
+        assert!(
+            (amplitude(&out, 0) - 1000).abs() < 20,
+            "{}",
+            amplitude(&out, 0)
+        );
+        assert!((amplitude(&out, 2) - 1000).abs() < 20);
+        assert!(out[512..].iter().all(|s| (s[1] - 1000).abs() <= 1));
+        assert!(out[512..].iter().all(|s| s[3] == 0));
+
+        // A tone above the new Nyquist frequency is suppressed instead of aliased
+        decimator.reset();
+        let out = resample(&mut decimator, &tone(4096, 0.2, 1000.));
+        assert!(amplitude(&out, 0) < 10, "{}", amplitude(&out, 0));
+
+        // Averaging lets it through
+        let averaged: Vec<i16> = tone(4096, 0.2, 1000.)
+            .chunks(4)
+            .map(|c| (c.iter().map(|s| s[0] as i32).sum::<i32>() / 4) as i16)
+            .collect();
+        assert!(averaged[512..].iter().map(|s| s.abs()).max().unwrap() > 100);
+    }
+
+    #[test]
+    fn test_streaming() {
+        let input = tone(3000, 0.05, 2000.);
+        let whole = resample(&mut Resampler::new(3, 2), &input);
+
+        let mut resampler = Resampler::new(3, 2);
+        let chunked: Vec<MicArraySample> = input
+            .chunks(77)
+            .flat_map(|c| resample(&mut resampler, c))
+            .collect();
+        assert_eq!(whole, chunked);
+    }
+
+    #[test]
+    fn test_rational() {
+        let mut resampler = Resampler::<TAPS>::new(6, 4);
+        assert_eq!(resampler.ratio(), (3, 2));
+        let out = resample(&mut resampler, &tone(2000, 0.05, 1000.));
+        assert_eq!(out.len(), 3000);
+        assert!(
+            (amplitude(&out, 0) - 1000).abs() < 20,
+            "{}",
+            amplitude(&out, 0)
+        );
+
+        // The tone now has a frequency of 0.05 * 2 / 3 cycles per sample
+        let crossings = out[1000..]
+            .windows(2)
+            .filter(|w| w[0][0] < 0 && w[1][0] >= 0)
+            .count();
+        assert!((66..=67).contains(&crossings), "{}", crossings);
+
+        // Equal factors don't change the signal
+        let input = tone(100, 0.3, 1000.);
+        assert_eq!(resample(&mut Resampler::new(2, 2), &input), input);
+    }
+}
@@ -0,0 +1,298 @@
+use core::{fmt::Debug, ops::AddAssign};
+
+/// Numeric type of the samples the algorithms operate on.
+///
+/// Implemented for `i16` (raw SAADC samples), `i32` (oversampled or 24 bit audio)
+/// and `f32` (for targets with an FPU).
+pub trait Sample: Copy + Default + PartialOrd + Debug + From<i16> {
+    /// Type products of samples are accumulated in, without overflowing
+    type Acc: Copy + Default + PartialOrd + AddAssign + Debug;
+
+    /// Lowest representable sample value
+    const MIN: Self;
+    /// Highest representable sample value
+    const MAX: Self;
+
+    /// Multiply two samples, widening the result to the accumulator type
+    fn mul_acc(self, other: Self) -> Self::Acc;
+
+    /// Widen a sample to the accumulator type
+    fn to_acc(self) -> Self::Acc;
+
+    /// Divide an accumulated sum of samples by `n`, yielding the mean sample value
+    fn mean(sum: Self::Acc, n: usize) -> Self;
+
+    /// Subtract two samples, saturating at the bounds of the sample type
+    fn saturating_sub(self, other: Self) -> Self;
+
+    fn to_f32(self) -> f32;
+
+    /// Convert from `f32`, rounding to the nearest value and saturating at the bounds
+    fn from_f32(v: f32) -> Self;
+
+    fn acc_to_f32(acc: Self::Acc) -> f32;
+
+    /// Amount of products of samples of `x` and `y` that [Sample::dot_acc] may sum
+    /// in a narrower type before widening them to the accumulator type
+    fn partial_len(_x: &[Self], _y: &[Self]) -> usize {
+        1
+    }
+
+    /// Add the products of the samples of `x` and `y` to `acc`, in order. Products are summed
+    /// in blocks of `partial_len` as obtained from [Sample::partial_len] before being widened.
+    fn dot_acc(acc: &mut Self::Acc, x: &[Self], y: &[Self], _partial_len: usize) {
+        x.iter()
+            .zip(y.iter())
+            .for_each(|(x, y)| *acc += x.mul_acc(*y));
+    }
+}
+
+/// `sum / n`, rounded to the nearest integer and halves away from zero like [Sample::from_f32]
+fn rounded_mean(sum: i64, n: usize) -> i64 {
+    let n = n as i64;
+    (sum + sum.signum() * (n / 2)) / n
+}
+
+impl Sample for i16 {
+    type Acc = i64;
+
+    const MIN: Self = i16::MIN;
+    const MAX: Self = i16::MAX;
+
+    fn mul_acc(self, other: Self) -> i64 {
+        self as i64 * other as i64
+    }
+
+    fn to_acc(self) -> i64 {
+        self as i64
+    }
+
+    fn mean(sum: i64, n: usize) -> Self {
+        rounded_mean(sum, n) as i16
+    }
+
+    fn saturating_sub(self, other: Self) -> Self {
+        i16::saturating_sub(self, other)
+    }
+
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+
+    fn from_f32(v: f32) -> Self {
+        let v = v + if v < 0. { -0.5 } else { 0.5 };
+        v.clamp(i16::MIN as f32, i16::MAX as f32) as i16
+    }
+
+    fn acc_to_f32(acc: i64) -> f32 {
+        acc as f32
+    }
+
+    fn partial_len(x: &[i16], y: &[i16]) -> usize {
+        let peak = |s: &[i16]| s.iter().map(|s| s.unsigned_abs() as u64).max().unwrap_or(0);
+        let max_product = (peak(x) * peak(y)).max(1);
+        (i32::MAX as u64 / max_product) as usize
+    }
+
+    /// Sums pairs of products in an `i32`, which maps onto the dual multiply-accumulate
+    /// instructions of the Cortex-M4, and only widens to `i64` once per block
+    fn dot_acc(acc: &mut i64, x: &[i16], y: &[i16], partial_len: usize) {
+        if partial_len < 2 {
+            x.iter()
+                .zip(y.iter())
+                .for_each(|(x, y)| *acc += x.mul_acc(*y));
+            return;
+        }
+        let block = partial_len & !1;
+        x.chunks(block).zip(y.chunks(block)).for_each(|(x, y)| {
+            let x_pairs = x.chunks_exact(2);
+            let y_pairs = y.chunks_exact(2);
+            let mut partial = x_pairs
+                .remainder()
+                .iter()
+                .zip(y_pairs.remainder().iter())
+                .fold(0i32, |sum, (x, y)| sum + *x as i32 * *y as i32);
+            x_pairs.zip(y_pairs).for_each(|(x, y)| {
+                partial += x[0] as i32 * y[0] as i32 + x[1] as i32 * y[1] as i32;
+            });
+            *acc += partial as i64;
+        });
+    }
+}
+
+/// Samples of up to 24 significant bits. Wider samples may overflow the accumulator.
+impl Sample for i32 {
+    type Acc = i64;
+
+    const MIN: Self = i32::MIN;
+    const MAX: Self = i32::MAX;
+
+    fn mul_acc(self, other: Self) -> i64 {
+        self as i64 * other as i64
+    }
+
+    fn to_acc(self) -> i64 {
+        self as i64
+    }
+
+    fn mean(sum: i64, n: usize) -> Self {
+        rounded_mean(sum, n) as i32
+    }
+
+    fn saturating_sub(self, other: Self) -> Self {
+        i32::saturating_sub(self, other)
+    }
+
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+
+    fn from_f32(v: f32) -> Self {
+        // Float to int casts saturate
+        (v + if v < 0. { -0.5 } else { 0.5 }) as i32
+    }
+
+    fn acc_to_f32(acc: i64) -> f32 {
+        acc as f32
+    }
+}
+
+impl Sample for f32 {
+    type Acc = f32;
+
+    const MIN: Self = f32::MIN;
+    const MAX: Self = f32::MAX;
+
+    fn mul_acc(self, other: Self) -> f32 {
+        self * other
+    }
+
+    fn to_acc(self) -> f32 {
+        self
+    }
+
+    fn mean(sum: f32, n: usize) -> Self {
+        sum / n as f32
+    }
+
+    fn saturating_sub(self, other: Self) -> Self {
+        self - other
+    }
+
+    fn to_f32(self) -> f32 {
+        self
+    }
+
+    fn from_f32(v: f32) -> Self {
+        v
+    }
+
+    fn acc_to_f32(acc: f32) -> f32 {
+        acc
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "std")]
+mod test {
+    use crate::health::{calc_angle_checked, FrameHealth, HealthLimits};
+    use crate::sample::*;
+    use crate::*;
+
+    const T_S_US: u32 = 37;
+    const D_MICS_MM: u32 = 125;
+    const M: usize = 1024;
+    const N: usize = max_lags_size(T_S_US, D_MICS_MM);
+
+    /// A modulated 1 kHz tone around the middle of the ADC range, arriving at
+    /// channel 2 `delay` samples after channel 1
+    fn frame(delay: usize) -> [MicArraySample; M] {
+        let signal = |n: usize| {
+            let t = n as f64 * T_S_US as f64 * 1e-6;
+            let envelope = 1. + 0.5 * (2. * std::f64::consts::PI * 23. * t).sin();
+            (2048. + 500. * envelope * (2. * std::f64::consts::PI * 1000. * t).sin()) as i16
+        };
+        let mut samples = [[0i16; 4]; M];
+        samples.iter_mut().enumerate().for_each(|(n, s)| {
+            let delayed = signal(n + 100 - delay);
+            *s = [signal(n + 100), delayed, delayed, signal(n + 100)];
+        });
+        samples
+    }
+
+    #[test]
+    fn test_conversions() {
+        assert_eq!(i16::from_f32(1.5), 2);
+        assert_eq!(i16::from_f32(-1.5), -2);
+        assert_eq!(i16::from_f32(1e6), i16::MAX);
+        assert_eq!(i32::from_f32(-2.4), -2);
+        assert_eq!(i32::from_f32(-1e12), i32::MIN);
+        assert_eq!(i16::mean(-7, 2), -4);
+        assert_eq!(i16::mean(2047 * 3 + 2, 3), 2048);
+        assert_eq!(i32::mean(-5, 3), -2);
+        assert_eq!(f32::mean(-7., 2), -3.5);
+        assert_eq!(i16::MAX.saturating_sub(-1), i16::MAX);
+    }
+
+    #[test]
+    fn test_xcorr_backends_agree() {
+        let channels = Channels::from_samples(frame(3));
+        let wide = channels.map(|s| i32::from(s) << 8);
+        let float = channels.map(f32::from);
+
+        let mut buf16 = [0i64; N];
+        let mut buf32 = [0i64; N];
+        let mut buf_f = [0f32; N];
+        let lag16 = calc_lag(&channels.ch1, &channels.ch2, &mut buf16);
+        let lag32 = calc_lag(&wide.ch1, &wide.ch2, &mut buf32);
+        let lag_f = calc_lag(&float.ch1, &float.ch2, &mut buf_f);
+        assert_eq!(lag16, 3);
+        assert_eq!(lag16, lag32);
+        assert_eq!(lag16, lag_f);
+
+        let max = buf16.iter().map(|v| v.abs()).max().unwrap() as f32;
+        (0..N).for_each(|i| {
+            // Scaling each sample by 2^8 scales each product by 2^16, exactly
+            assert_eq!(buf32[i], buf16[i] << 16);
+            assert!((buf_f[i] - buf16[i] as f32).abs() < max * 1e-5);
+        });
+    }
+
+    #[test]
+    fn test_angle_backends_agree() {
+        let limits = HealthLimits::saadc_12bit();
+        let table = gen_lag_table::<T_S_US, D_MICS_MM, N>();
+        (0..=4).for_each(|delay| {
+            let samples = frame(delay);
+            let health = FrameHealth::analyze(&samples, &limits);
+
+            let narrow = Channels::from_samples(samples);
+            // 24 bit samples straight from a host capture, without going through i16
+            let mut wide_samples = [[0i32; 4]; M];
+            wide_samples
+                .iter_mut()
+                .zip(samples.iter())
+                .for_each(|(w, s)| *w = s.map(|v| (v as i32) << 12));
+            let wide = Channels::from_frames(&wide_samples);
+            let float = narrow.map(f32::from);
+
+            let mut buf = [0i64; N];
+            let expected = calc_angle_checked::<_, T_S_US, D_MICS_MM, N, M>(
+                &narrow, 0, 1, &health, &limits, &mut buf, &table,
+            );
+            assert!(expected.is_ok());
+
+            let mut buf = [0i64; N];
+            let angle = calc_angle_checked::<_, T_S_US, D_MICS_MM, N, M>(
+                &wide, 0, 1, &health, &limits, &mut buf, &table,
+            );
+            assert_eq!(angle, expected);
+
+            let mut buf = [0f32; N];
+            let angle = calc_angle_checked::<_, T_S_US, D_MICS_MM, N, M>(
+                &float, 0, 1, &health, &limits, &mut buf, &table,
+            );
+            assert_eq!(angle, expected);
+        });
+    }
+}
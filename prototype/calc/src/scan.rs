@@ -0,0 +1,196 @@
+use crate::V_SOUND;
+
+/// Time the SAADC needs to convert a single acquired sample, in nanoseconds.
+pub const T_CONV_NS: u32 = 2000;
+
+/// Timing of a single SAADC scan over the four microphone channels.
+///
+/// In scan mode, the SAADC samples the enabled channels one after another on every
+/// SAMPLE task. Each channel takes `2^oversample * (t_acq + t_conv)` to sample, so channel n
+/// is sampled a fixed amount of time after the first channel in the scan. This offset biases
+/// every time difference of arrival calculated from the raw lags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScanTiming {
+    /// Acquisition time per channel in microseconds
+    pub acq_time_us: u32,
+    /// Base-2 logarithm of the amount of oversampled conversions per channel
+    pub oversample_log2: u32,
+    /// Position of each channel in the scan
+    pub scan_order: [u8; 4],
+}
+
+impl ScanTiming {
+    pub const fn new(acq_time_us: u32, oversample_log2: u32, scan_order: [u8; 4]) -> Self {
+        Self {
+            acq_time_us,
+            oversample_log2,
+            scan_order,
+        }
+    }
+
+    /// Time it takes to sample a single channel, in nanoseconds
+    pub const fn channel_time_ns(&self) -> u32 {
+        (self.acq_time_us * 1000 + T_CONV_NS) << self.oversample_log2
+    }
+
+    /// Time between the start of the scan and the moment channel `ch` is sampled, in nanoseconds
+    pub const fn channel_offset_ns(&self, ch: usize) -> u32 {
+        self.scan_order[ch] as u32 * self.channel_time_ns()
+    }
+
+    /// Time between sampling channel `x` and sampling channel `y`, in nanoseconds.
+    /// This is the amount of time that needs to be added to a measured lag of `y` relative to `x`.
+    pub const fn skew_ns(&self, x: usize, y: usize) -> i32 {
+        self.channel_offset_ns(y) as i32 - self.channel_offset_ns(x) as i32
+    }
+
+    /// Time it takes to scan all four channels, in nanoseconds.
+    /// Should not exceed the sample period.
+    pub const fn scan_time_ns(&self) -> u32 {
+        4 * self.channel_time_ns()
+    }
+}
+
+/// Convert a lag in samples of signal y relative to signal x into a time difference of arrival
+/// in nanoseconds, correcting for the inter-channel sampling skew.
+pub const fn lag_to_delay_ns(lag: i32, sample_period_us: u32, skew_ns: i32) -> i32 {
+    lag * sample_period_us as i32 * 1000 + skew_ns
+}
+
+/// Generate a lag table like [crate::gen_lag_table], mapping every lag onto the angle
+/// that corresponds to the skew-corrected time difference of arrival.
+pub fn gen_skewed_lag_table<const T_S_US: u32, const D_MICS_MM: u32, const SIZE: usize>(
+    skew_ns: i32,
+) -> [u32; SIZE] {
+    debug_assert_eq!(SIZE, crate::max_lags_size(T_S_US, D_MICS_MM));
+    gen_skewed_lag_table_for(T_S_US, D_MICS_MM, skew_ns)
+}
+
+/// Generate a skew-corrected lag table like [gen_skewed_lag_table], for a sample period and
+/// microphone distance only known at runtime. `SIZE` must be at least
+/// [crate::max_lags_size] for the given parameters; lags that are physically impossible map
+/// onto 0 or 180 degrees.
+pub fn gen_skewed_lag_table_for<const SIZE: usize>(
+    sample_period_us: u32,
+    mic_distance_mm: u32,
+    skew_ns: i32,
+) -> [u32; SIZE] {
+    debug_assert!(SIZE >= crate::max_lags_size(sample_period_us, mic_distance_mm));
+    let mut table = [0u32; SIZE];
+
+    table.iter_mut().enumerate().for_each(|(lag, angle)| {
+        let lag = lag as i32 - SIZE as i32 / 2;
+        let delay_ns = lag_to_delay_ns(lag, sample_period_us, skew_ns) as i64;
+        let cos_theta = delay_ns * V_SOUND as i64 / (mic_distance_mm as i64 * 1000);
+        *angle = crate::acos_deg(cos_theta.clamp(-1000, 1000) as i32);
+    });
+    table
+}
+
+#[cfg(test)]
+#[cfg(feature = "std")]
+mod test {
+    use crate::scan::*;
+    use crate::*;
+
+    const T_S_US: u32 = 37;
+    const D_MICS_MM: u32 = 125;
+    const M: usize = 1024;
+    const N: usize = max_lags_size(T_S_US, D_MICS_MM);
+
+    /// Sample a 1 kHz tone arriving at the microphones from `angle_deg`, with channel y
+    /// sampled `skew_ns` after channel x.
+    fn synthesize(angle_deg: f64, skew_ns: i32) -> ([i16; M], [i16; M]) {
+        let delay_s = D_MICS_MM as f64 / 1000. * angle_deg.to_radians().cos() / V_SOUND as f64;
+        let signal = |t: f64| {
+            // Tone with a slow amplitude modulation, to get a single clear correlation peak
+            let envelope = 1. + 0.5 * (2. * std::f64::consts::PI * 23. * t).sin();
+            (1000. * envelope * (2. * std::f64::consts::PI * 1000. * t).sin()) as i16
+        };
+        let mut x = [0i16; M];
+        let mut y = [0i16; M];
+        (0..M).for_each(|k| {
+            let t = k as f64 * T_S_US as f64 * 1e-6;
+            x[k] = signal(t);
+            y[k] = signal(t + skew_ns as f64 * 1e-9 - delay_s);
+        });
+        (x, y)
+    }
+
+    #[test]
+    fn test_scan_timing() {
+        let timing = ScanTiming::new(5, 0, [0, 1, 2, 3]);
+        assert_eq!(timing.channel_offset_ns(0), 0);
+        assert_eq!(timing.channel_offset_ns(3), 21000);
+        assert_eq!(timing.skew_ns(0, 1), 7000);
+        assert_eq!(timing.skew_ns(3, 2), -7000);
+        assert_eq!(timing.scan_time_ns(), 28000);
+
+        let timing = ScanTiming::new(3, 2, [3, 2, 1, 0]);
+        assert_eq!(timing.skew_ns(0, 1), -20000);
+    }
+
+    #[test]
+    fn test_skewed_lag_table() {
+        // Without skew, the table equals the regular one
+        assert_eq!(
+            gen_skewed_lag_table::<T_S_US, D_MICS_MM, N>(0),
+            gen_lag_table::<T_S_US, D_MICS_MM, N>()
+        );
+        // A skew of exactly one sample period shifts the table by one lag
+        let regular = gen_lag_table::<T_S_US, D_MICS_MM, N>();
+        let skewed = gen_skewed_lag_table::<T_S_US, D_MICS_MM, N>(T_S_US as i32 * 1000);
+        assert_eq!(&skewed[..N - 1], &regular[1..]);
+        assert_eq!(skewed[N - 1], 0);
+    }
+
+    #[test]
+    fn test_lag_table_for() {
+        assert_eq!(
+            gen_skewed_lag_table_for::<N>(T_S_US, D_MICS_MM, 1234),
+            gen_skewed_lag_table::<T_S_US, D_MICS_MM, N>(1234)
+        );
+        // At twice the sample period, only the middle lags are physically possible
+        const T_S_US_2: u32 = 2 * T_S_US;
+        const N_2: usize = max_lags_size(T_S_US_2, D_MICS_MM);
+        let table = gen_skewed_lag_table_for::<N>(T_S_US_2, D_MICS_MM, 0);
+        assert_eq!(
+            &table[(N - N_2) / 2..(N + N_2) / 2],
+            &gen_lag_table::<T_S_US_2, D_MICS_MM, N_2>()[..]
+        );
+        assert_eq!((table[0], table[N - 1]), (180, 0));
+    }
+
+    #[test]
+    fn test_skew_correction() {
+        let timing = ScanTiming::new(5, 0, [0, 1, 2, 3]);
+        // Channels 1 and 4 are furthest apart in the scan
+        let skew_ns = timing.skew_ns(0, 3);
+        let mut buf = [0i64; N];
+        let regular_table = gen_lag_table::<T_S_US, D_MICS_MM, N>();
+        let skewed_table = gen_skewed_lag_table::<T_S_US, D_MICS_MM, N>(skew_ns);
+        let mut total_error = 0.;
+        let mut total_uncorrected_error = 0.;
+
+        for expected in [40., 65., 90., 115., 140.] {
+            let (x, y) = synthesize(expected, skew_ns);
+            let lag = calc_lag(&x, &y, &mut buf) as i32;
+            buf = [0i64; N];
+
+            let delay_ns = lag_to_delay_ns(lag, T_S_US, skew_ns);
+            let expected_delay_ns = (D_MICS_MM as f64 * 1e6 * f64::cos(f64::to_radians(expected))
+                / V_SOUND as f64) as i32;
+            assert!((delay_ns - expected_delay_ns).abs() <= T_S_US as i32 * 1000 / 2);
+
+            let skewed = calc_angle::<_, T_S_US, D_MICS_MM, N, M>(&x, &y, &mut buf, &skewed_table);
+            buf = [0i64; N];
+            let uncorrected = lag_to_angle::<T_S_US, D_MICS_MM, N>(lag, &regular_table);
+
+            let error = (skewed.degrees() as f64 - expected).abs();
+            assert!(error <= 5., "{:?} vs {}", skewed, expected);
+            total_error += error;
+            total_uncorrected_error += (uncorrected.degrees() as f64 - expected).abs();
+        }
+        assert!(total_error < total_uncorrected_error);
+    }
+}
@@ -0,0 +1,208 @@
+use crate::{
+    fft::{fft, Complex},
+    health::isqrt,
+    sample::Sample,
+    Channels,
+};
+
+/// Spectral signature of a sound, e.g. the buzz of a mosquito, consisting of the average power
+/// in `BANDS` equally wide frequency bands between 0 Hz and the Nyquist frequency.
+///
+/// Frames are scored against the template by comparing the shape of their spectrum,
+/// regardless of their loudness and of the phase of the signal.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpectralTemplate<const BANDS: usize> {
+    power: [f32; BANDS],
+    frames: u32,
+}
+
+impl<const BANDS: usize> SpectralTemplate<BANDS> {
+    /// Create an empty template, to which frames can be added
+    pub const fn new() -> Self {
+        Self {
+            power: [0.; BANDS],
+            frames: 0,
+        }
+    }
+
+    /// Restore a template from previously saved band powers
+    pub const fn from_power(power: [f32; BANDS], frames: u32) -> Self {
+        Self { power, frames }
+    }
+
+    /// Average power of each band
+    pub fn power(&self) -> &[f32; BANDS] {
+        &self.power
+    }
+
+    /// Amount of frames the template was built from
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    /// Add a frame of a recording of the sound of interest to the template
+    pub fn add_frame<S: Sample, const SIGNAL_LEN: usize>(&mut self, signal: &[S; SIGNAL_LEN]) {
+        let power = band_power::<S, SIGNAL_LEN, BANDS>(signal);
+        let weight = 1. / (self.frames + 1) as f32;
+        self.power
+            .iter_mut()
+            .zip(power.iter())
+            .for_each(|(t, p)| *t += (p - *t) * weight);
+        self.frames = self.frames.saturating_add(1);
+    }
+
+    /// Score a signal against the template, in per-mille. 1000 means the spectrum
+    /// of the signal has exactly the same shape as the template.
+    pub fn score<S: Sample, const SIGNAL_LEN: usize>(&self, signal: &[S; SIGNAL_LEN]) -> u32 {
+        similarity_permille(&self.power, &band_power::<S, SIGNAL_LEN, BANDS>(signal))
+    }
+
+    /// Score the average spectrum of all four channels against the template, in per-mille
+    pub fn score_channels<S: Sample, const SIGNAL_LEN: usize>(
+        &self,
+        channels: &Channels<S, SIGNAL_LEN>,
+    ) -> u32 {
+        let mut power = [0f32; BANDS];
+        (0..4).for_each(|ch| {
+            power
+                .iter_mut()
+                .zip(band_power::<S, SIGNAL_LEN, BANDS>(channels.channel(ch)).iter())
+                .for_each(|(total, p)| *total += p);
+        });
+        similarity_permille(&self.power, &power)
+    }
+}
+
+impl<const BANDS: usize> Default for SpectralTemplate<BANDS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Calculate the power of a signal in `BANDS` equally wide frequency bands between
+/// 0 Hz and the Nyquist frequency. The signal length must be a power of two.
+pub fn band_power<S: Sample, const SIGNAL_LEN: usize, const BANDS: usize>(
+    signal: &[S; SIGNAL_LEN],
+) -> [f32; BANDS] {
+    let mut buf = [Complex::ZERO; SIGNAL_LEN];
+    buf.iter_mut()
+        .zip(signal.iter())
+        .for_each(|(bin, s)| *bin = Complex::new(s.to_f32(), 0.));
+    fft(&mut buf);
+
+    let half = SIGNAL_LEN / 2;
+    let mut power = [0f32; BANDS];
+    buf[..half].iter().enumerate().for_each(|(k, bin)| {
+        power[k * BANDS / half] += bin.norm_sqr();
+    });
+    power
+}
+
+/// Cosine similarity of two band power vectors, in per-mille
+fn similarity_permille(a: &[f32], b: &[f32]) -> u32 {
+    // Scale both vectors to a maximum of 1, to keep the products within the range of f32
+    let max = |v: &[f32]| v.iter().fold(0f32, |max, p| max.max(*p));
+    let (max_a, max_b) = (max(a), max(b));
+    if max_a <= 0. || max_b <= 0. {
+        return 0;
+    }
+
+    let (mut dot, mut norm_a, mut norm_b) = (0f32, 0f32, 0f32);
+    a.iter().zip(b.iter()).for_each(|(a, b)| {
+        let (a, b) = (a / max_a, b / max_b);
+        dot += a * b;
+        norm_a += a * a;
+        norm_b += b * b;
+    });
+    // Square of the similarity, as there is no square root in `core`
+    let squared = (dot / norm_a) * (dot / norm_b);
+    isqrt((squared * 1_000_000.) as u64).min(1000) as u32
+}
+
+#[cfg(test)]
+#[cfg(feature = "std")]
+mod test {
+    use crate::template::*;
+
+    const T_S_US: f64 = 37e-6;
+    const M: usize = 1024;
+    const BANDS: usize = 32;
+
+    /// A harmonic buzz with a fundamental of `freq_hz`, like the wingbeat of a mosquito
+    fn buzz(freq_hz: f64, amplitude: f64, phase: f64) -> [i16; M] {
+        let mut signal = [0i16; M];
+        signal.iter_mut().enumerate().for_each(|(n, s)| {
+            let t = n as f64 * T_S_US + phase;
+            let v: f64 = (1..=4)
+                .map(|h| (2. * std::f64::consts::PI * freq_hz * h as f64 * t).sin() / h as f64)
+                .sum();
+            *s = (amplitude * v) as i16;
+        });
+        signal
+    }
+
+    /// Low frequency rumble, like a fan or speech
+    fn rumble() -> [i16; M] {
+        let mut state = 7u32;
+        let mut y = 0f64;
+        let mut signal = [0i16; M];
+        signal.iter_mut().for_each(|s| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            y += 0.05 * (((state >> 16) % 2001) as f64 - 1000. - y);
+            *s = (4. * y) as i16;
+        });
+        signal
+    }
+
+    #[test]
+    fn test_band_power() {
+        let power = band_power::<_, M, BANDS>(&buzz(600., 1000., 0.));
+        // 600 Hz lies in band 600 / (13514 Hz / 32) = 1.4
+        let loudest = (0..BANDS)
+            .max_by(|a, b| power[*a].partial_cmp(&power[*b]).unwrap())
+            .unwrap();
+        assert_eq!(loudest, 1);
+        assert!(power[20..].iter().all(|p| *p < power[1] / 1000.));
+    }
+
+    #[test]
+    fn test_score() {
+        let mut template = SpectralTemplate::<BANDS>::new();
+        (0..4).for_each(|i| template.add_frame(&buzz(600., 1000., i as f64 * 0.013)));
+        assert_eq!(template.frames(), 4);
+
+        // Same sound at another loudness and phase matches
+        let matching = template.score(&buzz(600., 300., 0.5));
+        assert!(matching > 950, "{}", matching);
+        // Other sounds don't
+        let other = template.score(&buzz(2000., 1000., 0.));
+        assert!(other < 300, "{}", other);
+        let rumble = template.score(&rumble());
+        assert!(rumble < 500, "{}", rumble);
+
+        // Silence doesn't match anything, neither does an empty template
+        assert_eq!(template.score(&[0i16; M]), 0);
+        assert_eq!(
+            SpectralTemplate::<BANDS>::new().score(&buzz(600., 1000., 0.)),
+            0
+        );
+    }
+
+    #[test]
+    fn test_score_channels() {
+        let mut template = SpectralTemplate::<BANDS>::new();
+        template.add_frame(&buzz(600., 1000., 0.));
+
+        let signal = buzz(600., 800., 0.2);
+        let mut samples = [[0i16; 4]; M];
+        samples
+            .iter_mut()
+            .zip(signal.iter())
+            .for_each(|(s, v)| *s = [2048 + v; 4]);
+        let channels = Channels::from_samples(samples);
+        assert!(template.score_channels(&channels) > 950);
+
+        let restored = SpectralTemplate::from_power(*template.power(), template.frames());
+        assert_eq!(restored, template);
+    }
+}
@@ -1,4 +1,14 @@
-use folley_format::ServerToDevice;
+use folley_format::{
+    compression::SampleEncoding,
+    hello::Identify,
+    mode::Mode,
+    motion::{AxisLimits, Motion, MotionLimits},
+    server_to_device::Configure,
+    status::Query,
+    stream::{Stream, Subscribe},
+    units::{Bearing, DegreeOffset, Degrees},
+    ServerToDevice,
+};
 
 #[derive(Debug)]
 pub enum Action {
@@ -20,23 +30,63 @@ impl Cmd {
         let mut parts = line.split(' ');
         let first = parts.next();
         if let Some("pan") = first {
-            if let Some(Ok(degrees)) = parts.next().map(|p| p.parse::<f32>()) {
+            if let Some(Ok(degrees)) = parts.next().map(|p| p.parse::<i32>()) {
                 return SendMessage(ServerToDevice {
-                    pan_degrees: Some(degrees),
+                    pan_degrees: Some(Degrees(degrees)),
                     tilt_degrees: None,
                     ..ServerToDevice::default()
                 });
             }
         }
         if let Some("tilt") = first {
-            if let Some(Ok(degrees)) = parts.next().map(|p| p.parse::<f32>()) {
+            if let Some(Ok(degrees)) = parts.next().map(|p| p.parse::<i32>()) {
                 return SendMessage(ServerToDevice {
-                    tilt_degrees: Some(degrees),
+                    tilt_degrees: Some(Degrees(degrees)),
                     pan_degrees: None,
                     ..ServerToDevice::default()
                 });
             }
         }
+        if let Some("move") = first {
+            let mut offset = || parts.next().and_then(|p| p.parse::<i32>().ok());
+            return match (offset(), offset()) {
+                (Some(pan), Some(tilt)) => send_motion(Motion::By {
+                    pan: DegreeOffset(pan),
+                    tilt: DegreeOffset(tilt),
+                }),
+                _ => PrintErr("Usage: move <pan offset> <tilt offset>"),
+            };
+        }
+
+        if let Some("home") = first {
+            return send_motion(Motion::Home);
+        }
+
+        if let Some("halt") = first {
+            return send_motion(Motion::Stop);
+        }
+
+        if let Some("lookat") = first {
+            let mut bearing = || parts.next().and_then(|p| p.parse::<u32>().ok());
+            return match (bearing(), bearing()) {
+                (Some(azimuth), Some(elevation)) => send_motion(Motion::LookAt {
+                    azimuth: Bearing::from_degrees(azimuth),
+                    elevation: Bearing::from_degrees(elevation),
+                }),
+                _ => PrintErr("Usage: lookat <azimuth> <elevation>"),
+            };
+        }
+
+        if let Some("limits") = first {
+            return match parse_limits(parts) {
+                Some(motion_limits) => SendMessage(ServerToDevice {
+                    motion_limits: Some(motion_limits),
+                    ..ServerToDevice::default()
+                }),
+                None => PrintErr("Usage: limits <pan|tilt|both> <max speed> [acceleration]"),
+            };
+        }
+
         if let Some("start") = first {
             return SendMessage(ServerToDevice {
                 set_sampling_enabled: Some(true),
@@ -51,6 +101,130 @@ impl Cmd {
             });
         }
 
+        if let Some("identify") = first {
+            return SendMessage(ServerToDevice {
+                identify: Some(Identify::new()),
+                ..ServerToDevice::default()
+            });
+        }
+
+        if let Some("status") = first {
+            return SendMessage(ServerToDevice {
+                query: Some(Query::Status),
+                ..ServerToDevice::default()
+            });
+        }
+
+        if let Some("config") = first {
+            return SendMessage(ServerToDevice {
+                query: Some(Query::Config),
+                ..ServerToDevice::default()
+            });
+        }
+
+        if let Some("mode") = first {
+            return match parts.next().and_then(Mode::parse) {
+                Some(mode) => SendMessage(ServerToDevice {
+                    set_mode: Some(mode),
+                    ..ServerToDevice::default()
+                }),
+                None => PrintErr("Usage: mode <idle|measure|manual|autotrack|scan>"),
+            };
+        }
+
+        if let Some("encoding") = first {
+            let encoding = match parts.next() {
+                Some("raw") => SampleEncoding::Raw,
+                Some("rice") => SampleEncoding::Rice,
+                _ => return PrintErr("Usage: encoding <raw|rice>"),
+            };
+            return SendMessage(ServerToDevice {
+                sample_encoding: Some(encoding),
+                ..ServerToDevice::default()
+            });
+        }
+
+        if let Some("subscribe") = first {
+            let stream = parts.next().and_then(Stream::parse);
+            let decimation = parts.next().map_or(Ok(1), |p| p.parse::<u16>());
+            return match (stream, decimation) {
+                (Some(stream), Ok(decimation)) => SendMessage(ServerToDevice {
+                    subscribe: Some(Subscribe::every(stream, decimation)),
+                    ..ServerToDevice::default()
+                }),
+                _ => PrintErr(
+                    "Usage: subscribe <samples|detections|xcorr|telemetry|pantilt> [decimation]",
+                ),
+            };
+        }
+
+        if let Some("unsubscribe") = first {
+            return match parts.next().and_then(Stream::parse) {
+                Some(stream) => SendMessage(ServerToDevice {
+                    subscribe: Some(Subscribe::none(stream)),
+                    ..ServerToDevice::default()
+                }),
+                None => PrintErr("Usage: unsubscribe <samples|detections|xcorr|telemetry|pantilt>"),
+            };
+        }
+
+        if let Some("configure") = first {
+            return match parse_configure(parts) {
+                Some(configure) => SendMessage(ServerToDevice {
+                    configure: Some(configure),
+                    ..ServerToDevice::default()
+                }),
+                None => PrintErr("Usage: configure [period=<us>] [gain=<num>/<den>] [resolution=<bits>] [oversample=<log2>] [tacq=<us>] [frame=<len>]"),
+            };
+        }
+
         PrintErr("Error parsing command")
     }
 }
+
+fn send_motion(motion: Motion) -> Action {
+    Action::SendMessage(ServerToDevice {
+        motion: Some(motion),
+        ..ServerToDevice::default()
+    })
+}
+
+/// Parse the axes, maximum speed in degrees per second and acceleration in degrees per
+/// second squared
+fn parse_limits<'a>(mut parts: impl Iterator<Item = &'a str>) -> Option<MotionLimits> {
+    let (pan, tilt) = match parts.next()? {
+        "pan" => (true, false),
+        "tilt" => (false, true),
+        "both" => (true, true),
+        _ => return None,
+    };
+    let limits = AxisLimits {
+        max_speed_deg_s: parts.next()?.parse().ok()?,
+        acceleration_deg_s2: parts.next().map_or(Ok(0), str::parse).ok()?,
+    };
+    Some(MotionLimits {
+        pan: Some(limits).filter(|_| pan),
+        tilt: Some(limits).filter(|_| tilt),
+    })
+}
+
+/// Parse `key=value` pairs into the acquisition parameters to change
+fn parse_configure<'a>(parts: impl Iterator<Item = &'a str>) -> Option<Configure> {
+    let mut configure = Configure::default();
+    for part in parts.filter(|p| !p.is_empty()) {
+        let (key, value) = part.split_once('=')?;
+        match key {
+            "period" => configure.sample_period_us = Some(value.parse().ok()?),
+            "gain" => {
+                let (num, den) = value.split_once('/').unwrap_or((value, "1"));
+                configure.gain = Some((num.parse().ok()?, den.parse().ok()?));
+            }
+            "resolution" => configure.resolution_bits = Some(value.parse().ok()?),
+            "oversample" => configure.oversample_log2 = Some(value.parse().ok()?),
+            "tacq" => configure.acquisition_time_us = Some(value.parse().ok()?),
+            "frame" => configure.frame_len = Some(value.parse().ok()?),
+            _ => return None,
+        }
+    }
+    Some(configure)
+}
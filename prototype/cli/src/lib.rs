@@ -7,32 +7,170 @@ mod python_wrappers;
 #[cfg(feature = "pyo3")]
 pub use python_wrappers::*;
 
-use folley_format::DeviceToServer;
+use folley_format::{
+    chunk::SampleChunk,
+    compression::SampleEncoding,
+    device_to_server::MicArraySample,
+    heartbeat::{Heartbeat, LinkEvent, LinkMonitor},
+    hello::{Identify, Incompatible},
+    DeviceToServer, ServerToDevice,
+};
 
-use serial::TxPort;
-use std::{io, sync::mpsc::Sender, thread, time::Duration};
+use serial::{PendingReplies, TxPort};
+use std::{
+    io,
+    sync::{mpsc::Sender, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
 
-pub fn connect(port_name: &str, tx: Sender<DeviceToServer>) -> io::Result<TxPort<32>> {
+/// What happens on the connection to the device
+// Nearly all events are messages, boxing them would only add an allocation
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum Event {
+    /// A message from the device that isn't a reply to a command
+    Message(DeviceToServer),
+    /// The device has been silent for too long, see [folley_format::heartbeat]
+    LinkLost,
+    /// The device was heard from again after the link was lost
+    LinkRestored,
+    /// The device said hello in another protocol version than this build speaks. Its
+    /// [DeviceToServer::Hello] follows as a message.
+    Incompatible(Incompatible),
+    /// Reading from the port failed, so no more events will follow
+    Disconnected(io::Error),
+}
+
+/// Connect to the device on `port_name`, and send the events on the connection to `tx`.
+/// Heartbeats are exchanged with the device every `heartbeat_interval`, or not at all if
+/// it's zero.
+pub fn connect(
+    port_name: &str,
+    tx: Sender<Event>,
+    heartbeat_interval: Duration,
+) -> io::Result<TxPort<64>> {
     let port = serialport::new(port_name, 460800)
         .flow_control(serialport::FlowControl::Hardware)
         .timeout(Duration::from_millis(500))
         .open()?;
 
-    let tx_port = TxPort::new(port.try_clone().unwrap());
+    let replies = Arc::new(PendingReplies::default());
+    let mut tx_port = TxPort::new(port.try_clone()?, replies.clone());
 
-    let _rx_thread = thread::spawn(|| {
-        serial::RxPort::new(port).run_read_task::<_, 20000>(move |msg| tx.send(msg).unwrap())
+    let start = Instant::now();
+    let now_ms = move || start.elapsed().as_millis() as u64;
+    let heartbeat = Heartbeat {
+        interval_ms: heartbeat_interval.as_millis() as u32,
+    };
+    let monitor = Arc::new(Mutex::new(LinkMonitor::new()));
+    monitor
+        .lock()
+        .unwrap()
+        .set_interval(heartbeat.interval_ms, now_ms());
+
+    let rx_monitor = monitor.clone();
+    let rx_tx = tx.clone();
+    let _rx_thread = thread::spawn(move || {
+        let on_msg_tx = rx_tx.clone();
+        let error = serial::RxPort::new(port).run_read_task::<_, 20000>(move |msg| {
+            if let Some(LinkEvent::Restored) = rx_monitor.lock().unwrap().seen(now_ms()) {
+                on_msg_tx.send(Event::LinkRestored).ok();
+            }
+            // Whoever receives the events decides whether to carry on with the device
+            if let DeviceToServer::Hello(hello) = &msg {
+                if let Err(e) = hello.check_protocol() {
+                    on_msg_tx.send(Event::Incompatible(e)).ok();
+                }
+            }
+            // Replies to commands go to whoever sent the command
+            if let Some(msg) = replies.deliver(msg) {
+                on_msg_tx.send(Event::Message(msg)).ok();
+            }
+        });
+        rx_tx.send(Event::Disconnected(error)).ok();
     });
 
+    // Tell the device how often to expect heartbeats, which also turns them off if
+    // a previous connection turned them on
+    let beat = ServerToDevice {
+        heartbeat: Some(heartbeat),
+        ..ServerToDevice::default()
+    };
+    tx_port.write_message(&beat)?;
+    if heartbeat.interval_ms > 0 {
+        let mut beat_port = tx_port.clone();
+        let _heartbeat_thread = thread::spawn(move || loop {
+            thread::sleep(heartbeat_interval);
+            // Once writing fails, the read thread reports the disconnect
+            if beat_port.write_message(&beat).is_err() {
+                break;
+            }
+            if let Some(LinkEvent::Lost) = monitor.lock().unwrap().check(now_ms()) {
+                if tx.send(Event::LinkLost).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    // Ask the device to identify itself, in case it booted before the host connected
+    tx_port.write_message(&ServerToDevice {
+        identify: Some(Identify::new()),
+        ..ServerToDevice::default()
+    })?;
+    // Save bandwidth by having the device compress its samples. Devices that
+    // don't sample refuse this, which is fine.
+    tx_port.write_message(&ServerToDevice {
+        sample_encoding: Some(SampleEncoding::Rice),
+        ..ServerToDevice::default()
+    })?;
     Ok(tx_port)
 }
 
+/// Get the samples out of a chunk, whatever its encoding. Channels that are not
+/// in the chunk are zero.
+pub fn decode_chunk(chunk: &SampleChunk) -> Option<Vec<MicArraySample>> {
+    let mut samples = vec![[0; 4]; chunk.len()];
+    match chunk.decode(&mut samples) {
+        Ok(_) => Some(samples),
+        Err(e) => {
+            eprintln!("Dropping chunk that could not be decoded: {:?}", e);
+            None
+        }
+    }
+}
+
 pub mod consts {
-    use folley_calc::max_lags_size;
+    use folley_calc::{health::HealthLimits, max_lags_size, scan::ScanTiming};
+    use std::time::Duration;
 
-    pub const T_S_US: u32 = 22;
+    pub const T_S_US: u32 = 37;
     pub const D_MICS_MM: u32 = 125;
 
+    /// SAADC scan timing. Must match the SaadcConfig used by the firmware.
+    pub const SCAN_TIMING: ScanTiming = ScanTiming::new(5, 0, [0, 1, 2, 3]);
+    /// Cutoff frequency in Hz of the DC blocking filter applied to each channel
+    pub const DC_BLOCK_CUTOFF_HZ: u32 = 20;
+    /// Thresholds for the channel health analysis
+    pub const HEALTH_LIMITS: HealthLimits = HealthLimits::saadc_12bit();
+    /// Frames in which all channels have an RMS value of at most this amount of ADC counts
+    /// are considered silent, and are used to learn the noise profile.
+    pub const SILENCE_MAX_RMS: u32 = 8;
+    /// Amount of frequency bands in a spectral template
+    pub const TEMPLATE_BANDS: usize = 32;
+    /// Frames that score lower against the template are not used to calculate angles
+    pub const MIN_TEMPLATE_SCORE_PERMILLE: u32 = 600;
+    /// Length of the anti-aliasing filter used when lowering the sample rate
+    pub const RESAMPLER_TAPS: usize = 256;
+
+    /// Time to wait for the device to reply to a command
+    pub const REQUEST_TIMEOUT: Duration = Duration::from_millis(500);
+    /// Times to send a command again if the device doesn't reply in time
+    pub const REQUEST_RETRIES: u32 = 2;
+    /// Interval at which heartbeats are exchanged with the device
+    pub const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(1000);
+
     pub const SAMPLE_BUF_SIZE: usize = 1024;
     
     pub const LAG_TABLE_SIZE: usize = max_lags_size(T_S_US, D_MICS_MM);
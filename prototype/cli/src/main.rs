@@ -3,48 +3,385 @@ compile_error!("Please enable 'cli' feature to build CLI application");
 
 use clap::{App, Arg};
 use folley::serial::TxPort;
-use folley::store::SampleStore;
+use folley::store::{
+    load_noise_profile, load_template, read_samples, save_noise_profile, save_template,
+    SampleStore, XcorrStore,
+};
+use folley::{decode_chunk, Event};
 
 use folley::consts::*;
-use folley_format::DeviceToServer;
+use folley_calc::{
+    dc::DcBlocker,
+    health::{estimate_pair_checked, FrameHealth, PairError, PairEstimate},
+    noise::{is_silent, NoiseProfile, SuppressionParams},
+    scan::gen_skewed_lag_table,
+    template::SpectralTemplate,
+    Channels, DcRemoval,
+};
+use folley_format::{
+    chunk::{Gap, SampleChunk, Timeline},
+    device_to_server::{CommandResult, MicArraySample, PairXcorr, PanTiltStatus, Xcorr},
+    hello::{Hello, Incompatible},
+    log::Level,
+    motion::{MotionEnd, MotionLimits},
+    status::{DeviceConfig, DeviceStatus},
+    stream::Stream,
+    DeviceToServer,
+};
 use serialport::{SerialPortType, UsbPortInfo};
 use std::io::{self, BufRead};
-use std::sync::mpsc;
+use std::path::Path;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{mpsc, Mutex};
 use std::thread;
+use std::time::Duration;
 
 use lazy_static::lazy_static;
 
 lazy_static! {
-    static ref LAG_TABLE: [u32; LAG_TABLE_SIZE] =
-        folley_calc::gen_lag_table::<T_S_US, D_MICS_MM, LAG_TABLE_SIZE>();
+    static ref X_LAG_TABLE: [u32; LAG_TABLE_SIZE] =
+        gen_skewed_lag_table::<T_S_US, D_MICS_MM, LAG_TABLE_SIZE>(SCAN_TIMING.skew_ns(0, 1));
+    static ref Y_LAG_TABLE: [u32; LAG_TABLE_SIZE] =
+        gen_skewed_lag_table::<T_S_US, D_MICS_MM, LAG_TABLE_SIZE>(SCAN_TIMING.skew_ns(2, 3));
+    static ref DC_BLOCKERS: Mutex<[DcBlocker; 4]> =
+        Mutex::new([DcBlocker::with_cutoff(DC_BLOCK_CUTOFF_HZ, T_S_US); 4]);
+    static ref NOISE_PROFILE: Mutex<Box<NoiseProfile<SAMPLE_BUF_SIZE>>> =
+        Mutex::new(Box::new(NoiseProfile::new()));
+    static ref TEMPLATE: Mutex<Option<SpectralTemplate<TEMPLATE_BANDS>>> = Mutex::new(None);
+    static ref DEVICE: Mutex<Option<Hello>> = Mutex::new(None);
+    /// Where to write the cross-correlations of the host and the device, if anywhere
+    static ref XCORR_STORE: Mutex<Option<XcorrStore>> = Mutex::new(None);
 }
 
+/// Decimation of the sample stream the device was asked for, which leaves gaps on purpose
+static SAMPLE_DECIMATION: AtomicU16 = AtomicU16::new(1);
+
 fn handle_message(msg: DeviceToServer) {
     use DeviceToServer::*;
     match msg {
-        Samples(samples) => { 
-            let mut buf = [0i64; XCORR_SIZE];
-            let channels = folley_calc::Channels::from_samples(samples);
-            let x_angle = folley_calc::calc_angle::<
-                T_S_US,
-                D_MICS_MM,
-                XCORR_SIZE,
-                SAMPLE_BUF_SIZE,
-            >(&channels.ch1, &channels.ch2, &mut buf, &LAG_TABLE);
-            let y_angle = folley_calc::calc_angle::<
-                T_S_US,
-                D_MICS_MM,
-                XCORR_SIZE,
-                SAMPLE_BUF_SIZE,
-            >(&channels.ch1, &channels.ch2, &mut buf, &LAG_TABLE);
-            println!("X {}, Y: {}", x_angle, y_angle);            
+        Hello(hello) => {
+            println!("Device identified itself: {:?}", hello);
+            match hello.check_compatible(T_S_US, D_MICS_MM, SAMPLE_BUF_SIZE as u32) {
+                // Reported as an event before the hello
+                Err(Incompatible::ProtocolVersion { .. }) | Ok(()) => {}
+                Err(e) => warn_acquisition_mismatch(e),
+            }
+            *DEVICE.lock().unwrap() = Some(hello);
+        }
+        Detection(detection) => {
+            println!(
+                "Device detected a sound in frame {} at {} us: azimuth {}°, elevation {}° (lags {}, {}; confidence {}‰)",
+                detection.frame,
+                detection.timestamp_us,
+                detection.azimuth.degrees(),
+                detection.elevation.degrees(),
+                detection.azimuth_lag,
+                detection.elevation_lag,
+                detection.confidence_permille,
+            );
+        }
+        Telemetry(telemetry) => print_telemetry(&telemetry),
+        PanTilt { goal, actual } => print_pan_tilt(goal, actual),
+        Log { level, code, args } => {
+            println!("Device {:?}: {}", level, code.display(&args));
         }
+        Xcorr(xcorr) => store_device_xcorr(&xcorr),
+        ModeChanged(mode) => println!("Device switched to {} mode", mode.name()),
+        MotionDone { id, end, position } => println!(
+            "Motion of command {} {}: pan/tilt at {}°/{}°",
+            id,
+            match end {
+                MotionEnd::Reached => "done",
+                MotionEnd::Interrupted => "interrupted",
+            },
+            position.pan_deg.0,
+            position.tilt_deg.0,
+        ),
+        // The library keeps track of the link
+        Heartbeat(_) => {}
         m => {
             println!("Unhandled message: {:?}", m);
         }
     }
 }
 
+/// Prepare the samples of a frame for finding directions in it. The DC blockers and the
+/// noise profile carry state over from one frame to the next, so frames have to be
+/// prepared in the order they arrive.
+fn prepare_frame(
+    samples: &[MicArraySample],
+) -> Option<(Box<Channels<i16, SAMPLE_BUF_SIZE>>, FrameHealth)> {
+    match *DEVICE.lock().unwrap() {
+        None => {
+            println!("Ignoring samples, the device has not identified itself yet");
+            return None;
+        }
+        Some(hello) if !host_can_process(&hello) => return None,
+        Some(_) => {}
+    }
+    if samples.len() > SAMPLE_BUF_SIZE {
+        return None;
+    }
+    let mut health = FrameHealth::analyze(samples, &HEALTH_LIMITS);
+    let mut channels = Box::new(Channels::from_samples_padded(
+        samples,
+        DcRemoval::Blocker(&mut DC_BLOCKERS.lock().unwrap()),
+    ));
+    health.count_saturation(&channels);
+
+    let params = SuppressionParams::default();
+    let mut noise_profile = NOISE_PROFILE.lock().unwrap();
+    if is_silent(&health, SILENCE_MAX_RMS) {
+        noise_profile.learn(&channels, &params);
+    }
+    noise_profile.suppress(&mut channels, &params);
+    Some((channels, health))
+}
+
+/// Find the directions of the sound in a frame prepared by [prepare_frame], which starts
+/// at sample `start_index`
+fn find_directions(
+    start_index: u64,
+    channels: &Channels<i16, SAMPLE_BUF_SIZE>,
+    health: &FrameHealth,
+) {
+    if let Some(template) = TEMPLATE.lock().unwrap().as_ref() {
+        let score = template.score_channels(channels);
+        if score < MIN_TEMPLATE_SCORE_PERMILLE {
+            println!("Frame does not match template (score: {})", score);
+            return;
+        }
+    }
+
+    let mut x_buf = [0i64; XCORR_SIZE];
+    let x_angle = estimate_pair_checked::<_, T_S_US, D_MICS_MM, XCORR_SIZE, SAMPLE_BUF_SIZE>(
+        channels,
+        0,
+        1,
+        health,
+        &HEALTH_LIMITS,
+        &mut x_buf,
+        &X_LAG_TABLE,
+    );
+    let mut y_buf = [0i64; XCORR_SIZE];
+    let y_angle = estimate_pair_checked::<_, T_S_US, D_MICS_MM, XCORR_SIZE, SAMPLE_BUF_SIZE>(
+        channels,
+        2,
+        3,
+        health,
+        &HEALTH_LIMITS,
+        &mut y_buf,
+        &Y_LAG_TABLE,
+    );
+    if let Some(store) = XCORR_STORE.lock().unwrap().as_mut() {
+        let mut store_pair = |pair, estimate: &Result<PairEstimate, PairError>, buf: &[i64]| {
+            let lag = estimate.as_ref().ok().map(|e| e.lag);
+            let values = buf.iter().map(|&v| v as f32);
+            store.store("host", start_index, pair, lag, values)
+        };
+        store_pair("x", &x_angle, &x_buf).unwrap();
+        store_pair("y", &y_angle, &y_buf).unwrap();
+    }
+    println!(
+        "X {:?}, Y: {:?}",
+        x_angle.map(|e| e.bearing),
+        y_angle.map(|e| e.bearing)
+    );
+}
+
+/// Whether the host can interpret the samples of the device using its own constants
+fn host_can_process(hello: &Hello) -> bool {
+    hello
+        .check_compatible(T_S_US, D_MICS_MM, SAMPLE_BUF_SIZE as u32)
+        .is_ok()
+}
+
+/// Sample period the device currently samples with
+fn device_sample_period_us() -> u32 {
+    DEVICE
+        .lock()
+        .unwrap()
+        .and_then(|hello| hello.acquisition)
+        .map_or(T_S_US, |acquisition| acquisition.sample_period_us)
+}
+
+fn print_telemetry(telemetry: &folley_format::device_to_server::Telemetry) {
+    let link = &telemetry.link;
+    println!(
+        "Device up {:.1} s: {} frames processed, {} dropped; {} spawn failures; {} tx busy waits",
+        telemetry.uptime_ms as f64 / 1000.,
+        telemetry.frames_processed,
+        telemetry.frames_dropped,
+        telemetry.spawn_failures,
+        telemetry.tx_busy_waits,
+    );
+    println!(
+        "\tReceived {} frames: {} corrupt, {} overflows, {} deserialize errors, {} dropped",
+        link.frames, link.corrupt, link.overflows, link.deser_errors, link.dropped,
+    );
+    if let Some((goal, actual)) = telemetry.pan_tilt_goal.zip(telemetry.pan_tilt_actual) {
+        print!("\t");
+        print_pan_tilt(goal, actual);
+    }
+}
+
+fn print_status(status: &DeviceStatus) {
+    println!(
+        "Device up {:.1} s in {} mode: sampling {}{}",
+        status.uptime_ms as f64 / 1000.,
+        status.mode.name(),
+        if status.sampling_enabled {
+            "enabled"
+        } else {
+            "disabled"
+        },
+        if status.safe_state {
+            ", in safe state after losing the link"
+        } else {
+            ""
+        },
+    );
+    if let Some((goal, actual)) = status.pan_tilt_goal.zip(status.pan_tilt_actual) {
+        print!("\t");
+        print_pan_tilt(goal, actual);
+    }
+}
+
+fn print_config(config: &DeviceConfig) {
+    match &config.acquisition {
+        Some(acquisition) => println!("Acquisition: {:?}", acquisition),
+        None => println!("Device does not sample"),
+    }
+    if let Some(calibration) = &config.calibration {
+        println!(
+            "\tDC block cutoff {:?} Hz; pair skews {} ns, {} ns; noise profile learned from {} frames of at most {} RMS",
+            calibration.dc_block_cutoff_hz,
+            calibration.pair_skew_ns[0],
+            calibration.pair_skew_ns[1],
+            calibration.noise_frames,
+            calibration.silence_max_rms,
+        );
+    }
+    match config.heartbeat_interval_ms {
+        0 => println!("\tNo heartbeats"),
+        ms => println!("\tHeartbeats every {} ms", ms),
+    }
+    if let Some(MotionLimits {
+        pan: Some(pan),
+        tilt: Some(tilt),
+    }) = config.motion_limits
+    {
+        println!(
+            "\tPan up to {}°/s at {}°/s², tilt up to {}°/s at {}°/s² (0 is at once)",
+            pan.max_speed_deg_s,
+            pan.acceleration_deg_s2,
+            tilt.max_speed_deg_s,
+            tilt.acceleration_deg_s2,
+        );
+    }
+}
+
+fn store_device_xcorr(xcorr: &Xcorr) {
+    println!(
+        "Device cross-correlated frame {} (sample {}): lags {:?}, {:?}",
+        xcorr.frame, xcorr.start_index, xcorr.azimuth.lag, xcorr.elevation.lag,
+    );
+    if let Some(store) = XCORR_STORE.lock().unwrap().as_mut() {
+        let mut store_pair = |pair, xcorr_pair: &PairXcorr| {
+            let lag = xcorr_pair.lag.map(i32::from);
+            let values = xcorr_pair.values.iter().copied();
+            store.store("device", xcorr.start_index, pair, lag, values)
+        };
+        store_pair("x", &xcorr.azimuth).unwrap();
+        store_pair("y", &xcorr.elevation).unwrap();
+    }
+}
+
+fn print_pan_tilt(goal: PanTiltStatus, actual: PanTiltStatus) {
+    println!(
+        "Pan/tilt at {}°/{}°, heading for {}°/{}°",
+        actual.pan_deg.0, actual.tilt_deg.0, goal.pan_deg.0, goal.tilt_deg.0,
+    );
+}
+
+fn report_gap(gap: Gap, chunk: &SampleChunk) {
+    match gap {
+        Gap::None => {}
+        Gap::Paused { us } => println!(
+            "Sampling resumed at sample {} after a pause of {} us",
+            chunk.start_index, us
+        ),
+        Gap::Dropped { .. } if SAMPLE_DECIMATION.load(Ordering::Relaxed) > 1 => {}
+        Gap::Dropped { samples } => eprintln!(
+            "Lost {} samples before sample {}",
+            samples, chunk.start_index
+        ),
+        Gap::Restarted => println!("Device restarted sampling"),
+    }
+}
+
+fn warn_acquisition_mismatch(e: Incompatible) {
+    eprintln!(
+        "Device samples differently than this build expects ({:?}), only showing the detections of the device",
+        e
+    );
+}
+
+/// Write out what was stored so far, before exiting
+fn flush_stores(samples: Option<&mut SampleStore<64>>) {
+    if let Some(store) = samples {
+        store.flush().ok();
+    }
+    if let Some(store) = XCORR_STORE.lock().unwrap().as_mut() {
+        store.flush().ok();
+    }
+}
+
+/// Read a recording made with the `--outfile` option, and prepare its frames
+/// the same way incoming frames are prepared.
+fn read_recording<P: AsRef<Path>>(
+    recording: P,
+) -> io::Result<Vec<Box<Channels<i16, SAMPLE_BUF_SIZE>>>> {
+    let samples = read_samples(recording)?;
+    let mut dc_blockers = [DcBlocker::with_cutoff(DC_BLOCK_CUTOFF_HZ, T_S_US); 4];
+
+    Ok(samples
+        .chunks_exact(SAMPLE_BUF_SIZE)
+        .map(|frame| {
+            let mut buf = [[0i16; 4]; SAMPLE_BUF_SIZE];
+            buf.copy_from_slice(frame);
+            Box::new(Channels::from_samples_with(
+                buf,
+                DcRemoval::Blocker(&mut dc_blockers),
+            ))
+        })
+        .collect())
+}
+
+/// Learn a noise profile from a recording made while no sound of interest was present.
+fn learn_noise_profile<P: AsRef<Path>>(
+    recording: P,
+) -> io::Result<Box<NoiseProfile<SAMPLE_BUF_SIZE>>> {
+    let params = SuppressionParams {
+        max_learn_frames: u32::MAX,
+        ..SuppressionParams::default()
+    };
+    let mut profile = Box::new(NoiseProfile::new());
+    read_recording(recording)?
+        .iter()
+        .for_each(|channels| profile.learn(channels, &params));
+    Ok(profile)
+}
+
+/// Build a spectral template from a recording of the sound of interest, using all channels.
+fn build_template<P: AsRef<Path>>(recording: P) -> io::Result<SpectralTemplate<TEMPLATE_BANDS>> {
+    let mut template = SpectralTemplate::new();
+    read_recording(recording)?.iter().for_each(|channels| {
+        (0..4).for_each(|ch| template.add_frame(channels.channel(ch)));
+    });
+    Ok(template)
+}
+
 fn run<const N: usize>(mut tx_port: TxPort<N>) {
     use folley::cmd::Action::*;
     let stdin = io::stdin();
@@ -52,10 +389,31 @@ fn run<const N: usize>(mut tx_port: TxPort<N>) {
     print!("--> ");
     for line in stdin.lock().lines().filter_map(|r| r.ok()) {
         match cmd.parse_line(&line) {
-            SendMessage(msg) => {
-                tx_port.write_message(&msg).unwrap();
-            }
-            a => eprintln!("Unknown action {:?}", a),
+            SendMessage(msg) => match tx_port.request(&msg, REQUEST_TIMEOUT, REQUEST_RETRIES) {
+                Ok(CommandResult::Configured(acquisition)) => {
+                    println!("Configured: {:?}", acquisition);
+                    if let Some(hello) = DEVICE.lock().unwrap().as_mut() {
+                        hello.acquisition = Some(acquisition);
+                        if let Err(e) =
+                            hello.check_compatible(T_S_US, D_MICS_MM, SAMPLE_BUF_SIZE as u32)
+                        {
+                            warn_acquisition_mismatch(e);
+                        }
+                    }
+                }
+                Ok(CommandResult::Status(status)) => print_status(&status),
+                Ok(CommandResult::Config(config)) => print_config(&config),
+                Ok(result) => {
+                    if let Some(subscribe) = msg.subscribe {
+                        if subscribe.stream == Stream::Samples {
+                            SAMPLE_DECIMATION.store(subscribe.decimation, Ordering::Relaxed);
+                        }
+                    }
+                    println!("Done: {:?}", result)
+                }
+                Err(e) => eprintln!("Command failed: {:?}", e),
+            },
+            PrintErr(e) => eprintln!("{}", e),
         }
         print!("--> ");
     }
@@ -72,6 +430,61 @@ fn main() {
                 .takes_value(true)
                 .help("The path of the file to write to"),
         )
+        .arg(
+            Arg::with_name("NOISE_PROFILE")
+                .short("n")
+                .long("noise-profile")
+                .required(false)
+                .takes_value(true)
+                .help("The path of the noise profile to load, or to write when learning noise"),
+        )
+        .arg(
+            Arg::with_name("LEARN_NOISE")
+                .long("learn-noise")
+                .required(false)
+                .takes_value(true)
+                .requires("NOISE_PROFILE")
+                .help("Learn the noise profile from a baseline recording, save it and exit"),
+        )
+        .arg(
+            Arg::with_name("TEMPLATE")
+                .short("t")
+                .long("template")
+                .required(false)
+                .takes_value(true)
+                .help("The path of the template frames are matched against, or to write when building one"),
+        )
+        .arg(
+            Arg::with_name("BUILD_TEMPLATE")
+                .long("build-template")
+                .required(false)
+                .takes_value(true)
+                .requires("TEMPLATE")
+                .help("Build a template from a recording of the sound of interest, save it and exit"),
+        )
+        .arg(
+            Arg::with_name("LOG_LEVEL")
+                .long("log-level")
+                .required(false)
+                .takes_value(true)
+                .possible_values(&["trace", "debug", "info", "warn", "error"])
+                .default_value("info")
+                .help("Only show messages the device logs at this level or above"),
+        )
+        .arg(
+            Arg::with_name("XCORR_OUT")
+                .long("xcorr-out")
+                .required(false)
+                .takes_value(true)
+                .help("The path of the file to write the cross-correlations of the host and the device to, for comparison. Subscribe to the xcorr stream to receive those of the device"),
+        )
+        .arg(
+            Arg::with_name("HEARTBEAT")
+                .long("heartbeat")
+                .required(false)
+                .takes_value(true)
+                .help("Interval in milliseconds at which to exchange heartbeats with the device, or 0 to not exchange any"),
+        )
         .arg(
             Arg::with_name("PORT")
                 .index(1)
@@ -79,28 +492,102 @@ fn main() {
                 .help("The path to the serial port to listen to"),
         )
         .get_matches();
+
+    if let Some(recording) = matches.value_of("LEARN_NOISE") {
+        let path = matches.value_of("NOISE_PROFILE").unwrap();
+        let profile = learn_noise_profile(recording).unwrap();
+        save_noise_profile(path, &*profile).unwrap();
+        println!("Learned noise profile from {} frames", profile.frames());
+        return;
+    }
+    if let Some(recording) = matches.value_of("BUILD_TEMPLATE") {
+        let path = matches.value_of("TEMPLATE").unwrap();
+        let template = build_template(recording).unwrap();
+        save_template(path, &template).unwrap();
+        println!("Built template from {} frames", template.frames());
+        return;
+    }
+    if let Some(path) = matches.value_of("NOISE_PROFILE") {
+        *NOISE_PROFILE.lock().unwrap() = load_noise_profile(path).unwrap();
+    }
+    if let Some(path) = matches.value_of("TEMPLATE") {
+        *TEMPLATE.lock().unwrap() = Some(load_template(path).unwrap());
+    }
+
     let mut store = matches
         .value_of("OUT_FILE")
         .map(|p| SampleStore::new(p).unwrap());
 
-    let (tx, rx) = mpsc::channel::<DeviceToServer>();
+    if let Some(path) = matches.value_of("XCORR_OUT") {
+        *XCORR_STORE.lock().unwrap() = Some(XcorrStore::new(path).unwrap());
+    }
+
+    let log_level = Level::parse(matches.value_of("LOG_LEVEL").unwrap()).unwrap();
+
+    let heartbeat_interval = matches
+        .value_of("HEARTBEAT")
+        .map_or(HEARTBEAT_INTERVAL, |ms| {
+            Duration::from_millis(ms.parse().expect("Heartbeat interval must be a number"))
+        });
+
+    let (tx, rx) = mpsc::channel::<Event>();
 
     let rx_thread = thread::spawn(move || {
-        for msg in rx.into_iter() {
-            match msg {
-                DeviceToServer::Samples(samples) => {
-                    store
-                        .as_mut()
-                        .map(|s: &mut SampleStore<64>| s.store(&samples).unwrap());
+        let mut timeline = Timeline::new();
+        for event in rx.into_iter() {
+            let msg = match event {
+                Event::Message(msg) => msg,
+                Event::LinkLost => {
+                    eprintln!(
+                        "Lost the link to the device, it will stop streaming and park the bracket"
+                    );
+                    continue;
+                }
+                Event::LinkRestored => {
+                    eprintln!(
+                        "Link to the device restored, start sampling and subscribe again as needed"
+                    );
+                    continue;
+                }
+                Event::Incompatible(e) => {
+                    eprintln!("Device is incompatible with this build: {:?}", e);
+                    flush_stores(store.as_mut());
+                    std::process::exit(1);
+                }
+                Event::Disconnected(e) => {
+                    eprintln!("Disconnected from the device: {}", e);
+                    flush_stores(store.as_mut());
+                    std::process::exit(1);
                 }
-                _ => {}
             };
+            if let DeviceToServer::Log { level, .. } = &msg {
+                if *level < log_level {
+                    continue;
+                }
+            }
+            if let DeviceToServer::Chunk(chunk) = &msg {
+                report_gap(timeline.push(chunk, device_sample_period_us()), chunk);
+                let samples = match decode_chunk(chunk) {
+                    Some(samples) => samples,
+                    None => continue,
+                };
+                if let Some(store) = store.as_mut() {
+                    let store: &mut SampleStore<64> = store;
+                    store.store(&samples).unwrap();
+                }
+                // Only the preparation depends on the order of the frames
+                if let Some((channels, health)) = prepare_frame(&samples) {
+                    let start_index = chunk.start_index;
+                    thread::spawn(move || find_directions(start_index, &channels, &health));
+                }
+                continue;
+            }
             thread::spawn(|| handle_message(msg));
         }
     });
 
     if let Some(port_name) = matches.value_of("PORT") {
-        if let Ok(tx_port) = folley::connect(port_name, tx) {
+        if let Ok(tx_port) = folley::connect(port_name, tx, heartbeat_interval) {
             run(tx_port);
             rx_thread.join().ok();
             return;
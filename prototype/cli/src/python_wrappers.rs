@@ -1,9 +1,19 @@
-use std::sync::{mpsc, Mutex};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    mpsc, Mutex,
+};
 
+use crate::consts::{HEARTBEAT_INTERVAL, RESAMPLER_TAPS, T_S_US};
+use crate::Event;
+use folley_calc::resample::Resampler;
+use folley_format::{chunk::Timeline, device_to_server::Detection};
 use once_cell::sync::Lazy;
 use pyo3::prelude::*;
 static SAMPLES: Lazy<Mutex<[Vec<i16>; 4]>> =
     Lazy::new(|| Mutex::new([vec![], vec![], vec![], vec![]]));
+static DETECTIONS: Lazy<Mutex<Vec<Detection>>> = Lazy::new(|| Mutex::new(vec![]));
+static TIMELINE: Lazy<Mutex<Timeline>> = Lazy::new(|| Mutex::new(Timeline::new()));
+static CONNECTED: AtomicBool = AtomicBool::new(false);
 
 #[pyfunction]
 fn init(port_name: String, compress_factor: usize) -> PyResult<()> {
@@ -14,38 +24,51 @@ fn init(port_name: String, compress_factor: usize) -> PyResult<()> {
         compress_factor > 0,
         "Compress factor must be greater than 0"
     );
-    let (tx, rx) = mpsc::channel::<DeviceToServer>();
+    let (tx, rx) = mpsc::channel::<Event>();
 
-    let _tx_port = crate::connect(&port_name, tx)?;
+    let _tx_port = crate::connect(&port_name, tx, HEARTBEAT_INTERVAL)?;
+    CONNECTED.store(true, Ordering::Relaxed);
 
     thread::spawn(move || {
-        for msg in rx.into_iter() {
-            if let DeviceToServer::Samples(samples) = msg {
-                let mut compressed =
-                    Vec::with_capacity((samples.len() + compress_factor) / compress_factor);
-                for i in 0..samples.len() / compress_factor {
-                    let y = &samples[i..i + compress_factor]
-                        .iter()
-                        .fold([0, 0, 0, 0], |r, s| {
-                            [
-                                r[0] + s[0] as i32,
-                                r[1] + s[1] as i32,
-                                r[2] + s[2] as i32,
-                                r[3] + s[3] as i32,
-                            ]
-                        });
-                    let cf = compress_factor as i32;
-                    compressed.push([
-                        (y[0] / cf) as i16,
-                        (y[1] / cf) as i16,
-                        (y[2] / cf) as i16,
-                        (y[3] / cf) as i16,
-                    ]);
+        let mut decimator = Resampler::<RESAMPLER_TAPS>::decimator(compress_factor);
+        // This module never reconfigures the device, so its period only changes when
+        // it says hello again, e.g. after a reboot
+        let mut sample_period_us = T_S_US;
+        for event in rx.into_iter() {
+            let msg = match event {
+                Event::Message(msg) => msg,
+                Event::LinkRestored => {
+                    CONNECTED.store(true, Ordering::Relaxed);
+                    continue;
                 }
-                let mut buf = SAMPLES.lock().unwrap();
-                for i in 0..4 {
-                    buf[i].extend(compressed.iter().map(|c| c[i]));
+                Event::LinkLost | Event::Incompatible(_) | Event::Disconnected(_) => {
+                    CONNECTED.store(false, Ordering::Relaxed);
+                    continue;
                 }
+            };
+            match msg {
+                DeviceToServer::Hello(hello) => {
+                    if let Some(acquisition) = hello.acquisition {
+                        sample_period_us = acquisition.sample_period_us;
+                    }
+                }
+                DeviceToServer::Chunk(chunk) => {
+                    TIMELINE.lock().unwrap().push(&chunk, sample_period_us);
+                    let samples = match crate::decode_chunk(&chunk) {
+                        Some(samples) => samples,
+                        None => continue,
+                    };
+                    let mut buf = SAMPLES.lock().unwrap();
+                    decimator.process_samples(&samples, |s| {
+                        for i in 0..4 {
+                            buf[i].push(s[i]);
+                        }
+                    });
+                }
+                DeviceToServer::Detection(detection) => {
+                    DETECTIONS.lock().unwrap().push(detection);
+                }
+                _ => {}
             }
         }
     });
@@ -62,9 +85,46 @@ fn get_samples() -> PyResult<[Vec<i16>; 4]> {
     Ok(samples)
 }
 
+/// Total amount of samples that went missing between the chunks received so far
+#[pyfunction]
+fn get_dropped_samples() -> PyResult<u64> {
+    Ok(TIMELINE.lock().unwrap().dropped)
+}
+
+/// Whether the device is connected and still sending heartbeats
+#[pyfunction]
+fn is_connected() -> PyResult<bool> {
+    Ok(CONNECTED.load(Ordering::Relaxed))
+}
+
+/// Take the detections the device reported since the last call, as tuples of
+/// `(frame, timestamp_us, azimuth_deg, elevation_deg, azimuth_lag, elevation_lag, confidence_permille)`
+#[pyfunction]
+#[allow(clippy::type_complexity)]
+fn get_detections() -> PyResult<Vec<(u32, u64, u32, u32, i16, i16, u16)>> {
+    let detections = std::mem::take(&mut *DETECTIONS.lock().unwrap());
+    Ok(detections
+        .into_iter()
+        .map(|d| {
+            (
+                d.frame,
+                d.timestamp_us,
+                d.azimuth.degrees(),
+                d.elevation.degrees(),
+                d.azimuth_lag,
+                d.elevation_lag,
+                d.confidence_permille,
+            )
+        })
+        .collect())
+}
+
 #[pymodule]
 fn folley(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(init, m)?)?;
     m.add_function(wrap_pyfunction!(get_samples, m)?)?;
+    m.add_function(wrap_pyfunction!(get_detections, m)?)?;
+    m.add_function(wrap_pyfunction!(get_dropped_samples, m)?)?;
+    m.add_function(wrap_pyfunction!(is_connected, m)?)?;
     Ok(())
 }
@@ -1,8 +1,21 @@
 use folley_format as format;
-use std::io;
+use std::{
+    collections::HashMap,
+    io,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
 
-use format::{DeviceToServer, ServerToDevice};
-use postcard::CobsAccumulator;
+use format::{
+    device_to_server::{CommandError, CommandResult, Reply},
+    framing::{FrameAccumulator, FrameEncoder, SeqStatus},
+    server_to_device::RequestId,
+    DeviceToServer, ServerToDevice,
+};
 use serialport::SerialPort;
 
 pub struct RxPort {
@@ -14,60 +27,397 @@ impl RxPort {
         Self { port }
     }
 
-    pub fn run_read_task<F: Fn(DeviceToServer) -> (), const N: usize>(&mut self, on_msg: F) {
-        let mut accumulator = CobsAccumulator::<N>::new();
+    /// Read messages from the port until reading fails, returning the error it failed with
+    pub fn run_read_task<F: Fn(DeviceToServer) -> (), const N: usize>(
+        &mut self,
+        on_msg: F,
+    ) -> io::Error {
+        let mut accumulator = FrameAccumulator::<N>::new();
         let mut serial_buf = [0u8; 32];
-        use postcard::FeedResult::*;
+        use format::framing::FeedResult::*;
         loop {
-            let chunk_len = self
-                .port
-                .read(&mut serial_buf)
-                .map(|l| l as isize)
-                .or_else(|e| {
-                    if e.kind() == std::io::ErrorKind::TimedOut {
-                        Ok(-1) // Just a time out,
-                    } else {
-                        Err(e)
+            let chunk_len = match self.port.read(&mut serial_buf) {
+                Ok(len) => len,
+                // Just a time out
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(e) => return e,
+            };
+
+            // A chunk may hold the ends of several frames
+            let mut chunk = &serial_buf[0..chunk_len];
+            while !chunk.is_empty() {
+                chunk = match accumulator.feed(chunk) {
+                    Consumed => &[],
+                    OverFull(rest) => {
+                        eprintln!(
+                            "Accumulator full, dropping contents. {:?}",
+                            accumulator.stats()
+                        );
+                        rest
+                    }
+                    Corrupt(rest) => {
+                        eprintln!("Corrupt frame, throwing it away. {:?}", accumulator.stats());
+                        rest
+                    }
+                    DeserError(rest) => {
+                        eprintln!(
+                            "Deserialize error, throwing away message. {:?}",
+                            accumulator.stats()
+                        );
+                        rest
                     }
-                })
-                .expect("Serial read error");
-            if chunk_len == -1 {
-                continue;
+                    Success {
+                        data,
+                        seq,
+                        remaining,
+                    } => {
+                        if seq != SeqStatus::InOrder {
+                            eprintln!("Received frame: {:?}. {:?}", seq, accumulator.stats());
+                        }
+                        on_msg(data);
+                        remaining
+                    }
+                }
             }
+        }
+    }
+}
 
-            let chunk = &serial_buf[0..chunk_len as usize];
-            match accumulator.feed(chunk) {
-                Consumed => {} // Do nothing
-                OverFull(c) => eprintln!("Accumulator full, dropping contents: {:?}", c),
-                DeserError(c) => eprintln!(
-                    "Deserialize error, throwing away message: {:?} ({:?})",
-                    c, chunk
-                ),
-                Success { data, .. } => on_msg(data),
-            }
+/// Commands that are awaiting a reply from the device
+#[derive(Debug, Default)]
+pub struct PendingReplies {
+    pending: Mutex<HashMap<RequestId, mpsc::Sender<Reply>>>,
+}
+
+impl PendingReplies {
+    fn register(&self, id: RequestId) -> mpsc::Receiver<Reply> {
+        let (tx, rx) = mpsc::channel();
+        self.pending.lock().unwrap().insert(id, tx);
+        rx
+    }
+
+    fn forget(&self, id: RequestId) {
+        self.pending.lock().unwrap().remove(&id);
+    }
+
+    /// Hand an `Ack` or `Nack` to the command awaiting it.
+    /// Other messages are given back, so they can be handled elsewhere.
+    pub fn deliver(&self, msg: DeviceToServer) -> Option<DeviceToServer> {
+        let (id, reply) = match msg {
+            DeviceToServer::Ack { id, result } => (id, Ok(result)),
+            DeviceToServer::Nack { id, error } => (id, Err(error)),
+            msg => return Some(msg),
+        };
+        // Kept until the [PendingReply] is dropped, as the device replies to every attempt
+        if let Some(tx) = self.pending.lock().unwrap().get(&id) {
+            tx.send(reply).ok();
         }
+        None
     }
 }
 
-pub struct TxPort<const N: usize> {
+#[derive(Debug)]
+pub enum RequestError {
+    /// The device refused the command
+    Refused(CommandError),
+    /// No reply arrived in time
+    Timeout,
+    Io(io::Error),
+}
+
+impl From<io::Error> for RequestError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// Handle to the reply of a command that was sent
+pub struct PendingReply {
+    id: RequestId,
+    rx: mpsc::Receiver<Reply>,
+    replies: Arc<PendingReplies>,
+}
+
+impl PendingReply {
+    pub fn id(&self) -> RequestId {
+        self.id
+    }
+
+    /// Wait for the reply, for at most `timeout`
+    pub fn wait(&self, timeout: Duration) -> Result<CommandResult, RequestError> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(Ok(result)) => Ok(result),
+            Ok(Err(error)) => Err(RequestError::Refused(error)),
+            Err(_) => Err(RequestError::Timeout),
+        }
+    }
+}
+
+impl Drop for PendingReply {
+    fn drop(&mut self) {
+        self.replies.forget(self.id);
+    }
+}
+
+/// The serial port and the framing state of the messages sent on it
+struct Writer<const N: usize> {
     port: Box<dyn SerialPort>,
+    encoder: FrameEncoder,
     buf: [u8; N],
 }
 
+/// Time to wait before sending a command again that the device was too busy to take on
+const BUSY_BACKOFF: Duration = Duration::from_millis(20);
+
+/// Sends commands to the device. Clones share the port, so commands can be sent from
+/// several threads.
+#[derive(Clone)]
+pub struct TxPort<const N: usize> {
+    writer: Arc<Mutex<Writer<N>>>,
+    replies: Arc<PendingReplies>,
+    next_id: Arc<AtomicU16>,
+}
+
 impl<const N: usize> TxPort<N> {
-    pub fn new(port: Box<dyn SerialPort>) -> Self {
-        Self {
+    /// Create a port to send commands on. Replies to the commands are
+    /// expected to be handed to `replies` by whoever reads the port.
+    pub fn new(port: Box<dyn SerialPort>, replies: Arc<PendingReplies>) -> Self {
+        let writer = Writer {
             port,
+            encoder: FrameEncoder::new(),
             buf: [0u8; N],
+        };
+        Self {
+            writer: Arc::new(Mutex::new(writer)),
+            replies,
+            next_id: Arc::new(AtomicU16::new(0)),
+        }
+    }
+
+    /// Send a command without waiting for the reply. Returns the ID the command was sent with.
+    pub fn write_message(&mut self, msg: &ServerToDevice) -> Result<RequestId, io::Error> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.write_with_id(msg, id)?;
+        Ok(id)
+    }
+
+    /// Send a command, returning a handle to await the reply with
+    pub fn send(&mut self, msg: &ServerToDevice) -> Result<PendingReply, io::Error> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        // Register before sending, so the reply can't arrive before anyone is waiting for it
+        let rx = self.replies.register(id);
+        let pending = PendingReply {
+            id,
+            rx,
+            replies: self.replies.clone(),
+        };
+        self.write_with_id(msg, id)?;
+        Ok(pending)
+    }
+
+    /// Send a command and wait for the reply, sending it again up to `retries` times if
+    /// no reply arrives within `timeout` or the device is busy. Each attempt uses the same
+    /// request ID, so a late reply to an earlier attempt is accepted as well, and the device
+    /// replies to a command it already handled again rather than carrying it out twice.
+    pub fn request(
+        &mut self,
+        msg: &ServerToDevice,
+        timeout: Duration,
+        retries: u32,
+    ) -> Result<CommandResult, RequestError> {
+        let pending = self.send(msg)?;
+        let mut failure = RequestError::Timeout;
+        for attempt in 0..=retries {
+            if attempt > 0 {
+                self.write_with_id(msg, pending.id())?;
+            }
+            failure = match pending.wait(timeout) {
+                Err(RequestError::Timeout) => RequestError::Timeout,
+                Err(RequestError::Refused(CommandError::Busy)) => {
+                    // Give the device a moment to work through its queue
+                    thread::sleep(BUSY_BACKOFF);
+                    RequestError::Refused(CommandError::Busy)
+                }
+                reply => return reply,
+            };
         }
+        Err(failure)
     }
 
-    pub fn write_message(&mut self, msg: &ServerToDevice) -> Result<(), io::Error> {
-        while let 1.. = self.port.bytes_to_write().unwrap() {
+    fn write_with_id(&mut self, msg: &ServerToDevice, id: RequestId) -> Result<(), io::Error> {
+        let mut writer = self.writer.lock().unwrap();
+        let Writer { port, encoder, buf } = &mut *writer;
+        while let 1.. = port.bytes_to_write()? {
             // There are still bytes awaiting transmission
             // Wait for current write task to finish
         }
-        let msg = postcard::to_slice_cobs(msg, &mut self.buf).unwrap();
-        self.port.write(&msg).map(|_| {})
+        let msg = ServerToDevice { id, ..*msg };
+        let msg = encoder.encode(&msg, buf).unwrap();
+        port.write(msg).map(|_| {})
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::serial::*;
+    use format::{framing::FeedResult, mode::Mode};
+    use std::{io::Read, thread};
+
+    /// A port to send commands on, and the IDs of the commands as the device receives them
+    #[cfg(unix)]
+    fn connect() -> (TxPort<64>, Arc<PendingReplies>, mpsc::Receiver<RequestId>) {
+        let (host, mut device) = serialport::TTYPort::pair().unwrap();
+        let replies = Arc::new(PendingReplies::default());
+        let (ids_tx, ids) = mpsc::channel();
+        thread::spawn(move || {
+            let mut accumulator = FrameAccumulator::<64>::new();
+            let mut buf = [0u8; 64];
+            loop {
+                let len = match device.read(&mut buf) {
+                    Ok(len) => len,
+                    Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                    // The host end was closed
+                    Err(_) => return,
+                };
+                let mut chunk = &buf[..len];
+                while !chunk.is_empty() {
+                    chunk = match accumulator.feed::<ServerToDevice>(chunk) {
+                        FeedResult::Consumed => &[],
+                        FeedResult::Success {
+                            data, remaining, ..
+                        } => {
+                            ids_tx.send(data.id).ok();
+                            remaining
+                        }
+                        _ => panic!("damaged command"),
+                    }
+                }
+            }
+        });
+        let tx_port = TxPort::new(Box::new(host), replies.clone());
+        (tx_port, replies, ids)
+    }
+
+    #[test]
+    fn test_deliver() {
+        let replies = PendingReplies::default();
+        let rx = replies.register(3);
+
+        // Replies to commands no one waits for are dropped, other messages are given back
+        let error = CommandError::Busy;
+        assert!(replies
+            .deliver(DeviceToServer::Nack { id: 2, error })
+            .is_none());
+        let result = CommandResult::Done;
+        assert!(replies
+            .deliver(DeviceToServer::Ack { id: 3, result })
+            .is_none());
+        assert_eq!(rx.try_recv().unwrap(), Ok(CommandResult::Done));
+        let mode = format::mode::Mode::Idle;
+        assert!(matches!(
+            replies.deliver(DeviceToServer::ModeChanged(mode)),
+            Some(DeviceToServer::ModeChanged(_))
+        ));
+
+        replies.forget(3);
+        assert!(replies.pending.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_request_timeout() {
+        let (mut tx_port, replies, ids) = connect();
+        let timeout = Duration::from_millis(20);
+        let reply = tx_port.request(&ServerToDevice::default(), timeout, 2);
+        assert!(matches!(reply, Err(RequestError::Timeout)));
+
+        // Every attempt is sent with the same ID
+        let sent: Vec<_> = (0..3)
+            .map(|_| ids.recv_timeout(Duration::from_secs(1)).unwrap())
+            .collect();
+        assert_eq!(sent, [sent[0]; 3]);
+        assert!(replies.pending.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_request_late_reply() {
+        let (mut tx_port, replies, ids) = connect();
+        let request = thread::spawn(move || {
+            tx_port.request(&ServerToDevice::default(), Duration::from_millis(50), 3)
+        });
+
+        // The device only gets around to replying once the command is sent again
+        let id = ids.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(ids.recv_timeout(Duration::from_secs(1)).unwrap(), id);
+        let result = CommandResult::Done;
+        assert!(replies
+            .deliver(DeviceToServer::Ack { id, result })
+            .is_none());
+
+        assert!(matches!(request.join().unwrap(), Ok(CommandResult::Done)));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_request_refused() {
+        let (mut tx_port, replies, ids) = connect();
+        let request = thread::spawn(move || {
+            tx_port.request(&ServerToDevice::default(), Duration::from_secs(1), 3)
+        });
+
+        let id = ids.recv_timeout(Duration::from_secs(1)).unwrap();
+        let error = CommandError::WrongMode(Mode::Idle);
+        assert!(replies
+            .deliver(DeviceToServer::Nack { id, error })
+            .is_none());
+
+        let reply = request.join().unwrap();
+        assert!(matches!(
+            reply,
+            Err(RequestError::Refused(CommandError::WrongMode(_)))
+        ));
+        // A refusal is final, so the command isn't sent again
+        assert!(ids.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_request_busy() {
+        let (mut tx_port, replies, ids) = connect();
+        let request = thread::spawn(move || {
+            let busy = tx_port.request(&ServerToDevice::default(), Duration::from_secs(1), 1);
+            let done = tx_port.request(&ServerToDevice::default(), Duration::from_secs(1), 1);
+            (busy, done)
+        });
+        let error = CommandError::Busy;
+        let busy = |id| {
+            assert!(replies
+                .deliver(DeviceToServer::Nack { id, error })
+                .is_none());
+        };
+
+        // A busy device is asked again, until the retries run out
+        let first = ids.recv_timeout(Duration::from_secs(1)).unwrap();
+        busy(first);
+        assert_eq!(ids.recv_timeout(Duration::from_secs(1)).unwrap(), first);
+        busy(first);
+
+        // or until it takes on the command
+        let second = ids.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_ne!(second, first);
+        busy(second);
+        assert_eq!(ids.recv_timeout(Duration::from_secs(1)).unwrap(), second);
+        let result = CommandResult::Done;
+        assert!(replies
+            .deliver(DeviceToServer::Ack { id: second, result })
+            .is_none());
+
+        let (busy, done) = request.join().unwrap();
+        assert!(matches!(
+            busy,
+            Err(RequestError::Refused(CommandError::Busy))
+        ));
+        assert!(matches!(done, Ok(CommandResult::Done)));
     }
 }
@@ -1,9 +1,10 @@
 use std::{
     fs::File,
-    io::{self, BufWriter, Write},
+    io::{self, BufRead, BufReader, BufWriter, Write},
     path::Path,
 };
 
+use folley_calc::{noise::NoiseProfile, template::SpectralTemplate};
 use folley_format::device_to_server::MicArraySample;
 
 pub struct SampleStore<const N: usize> {
@@ -27,4 +28,165 @@ impl<const N: usize> SampleStore<N> {
             )
         })
     }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Writes cross-correlations to a CSV file, with a line per microphone pair per frame:
+/// `source,start_index,pair,lag,values...`. The lag is empty if the pair wasn't used.
+pub struct XcorrStore {
+    writer: BufWriter<File>,
+}
+
+impl XcorrStore {
+    pub fn new<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Ok(Self {
+            writer: BufWriter::new(file),
+        })
+    }
+
+    pub fn store(
+        &mut self,
+        source: &str,
+        start_index: u64,
+        pair: &str,
+        lag: Option<i32>,
+        values: impl IntoIterator<Item = f32>,
+    ) -> io::Result<()> {
+        let lag = lag.map_or(String::new(), |lag| lag.to_string());
+        write!(
+            &mut self.writer,
+            "{},{},{},{}",
+            source, start_index, pair, lag
+        )?;
+        values
+            .into_iter()
+            .try_for_each(|v| write!(&mut self.writer, ",{}", v))?;
+        writeln!(&mut self.writer)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+fn invalid_data<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// Read samples that were written by a [SampleStore]
+pub fn read_samples<P: AsRef<Path>>(path: P) -> io::Result<Vec<MicArraySample>> {
+    BufReader::new(File::open(path)?)
+        .lines()
+        .filter(|l| !matches!(l, Ok(l) if l.trim().is_empty()))
+        .map(|line| {
+            let line = line?;
+            let mut sample = [0i16; 4];
+            let mut values = line.split(',');
+            sample.iter_mut().try_for_each(|s| {
+                *s = values
+                    .next()
+                    .ok_or_else(|| invalid_data(format!("Too few values in line '{}'", line)))?
+                    .trim()
+                    .parse()
+                    .map_err(invalid_data)?;
+                Ok::<_, io::Error>(())
+            })?;
+            Ok(sample)
+        })
+        .collect()
+}
+
+/// Write a noise profile to a file. The first line contains the amount of frames
+/// the profile was learned from, followed by a line per frequency bin containing
+/// the noise power of each channel.
+pub fn save_noise_profile<P: AsRef<Path>, const LEN: usize>(
+    path: P,
+    profile: &NoiseProfile<LEN>,
+) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(&mut writer, "{}", profile.frames())?;
+    let power = profile.power();
+    (0..LEN).try_for_each(|bin| {
+        writeln!(
+            &mut writer,
+            "{},{},{},{}",
+            power[0][bin], power[1][bin], power[2][bin], power[3][bin]
+        )
+    })?;
+    writer.flush()
+}
+
+/// Read a noise profile that was written by [save_noise_profile]
+pub fn load_noise_profile<P: AsRef<Path>, const LEN: usize>(
+    path: P,
+) -> io::Result<Box<NoiseProfile<LEN>>> {
+    let mut lines = BufReader::new(File::open(path)?).lines();
+    let frames = lines
+        .next()
+        .ok_or_else(|| invalid_data("Empty noise profile"))??
+        .trim()
+        .parse()
+        .map_err(invalid_data)?;
+
+    let mut power = Box::new([[0f32; LEN]; 4]);
+    (0..LEN).try_for_each(|bin| {
+        let line = lines
+            .next()
+            .ok_or_else(|| invalid_data(format!("Noise profile has less than {} bins", LEN)))??;
+        let mut values = line.split(',');
+        power.iter_mut().try_for_each(|ch| {
+            ch[bin] = values
+                .next()
+                .ok_or_else(|| invalid_data(format!("Too few values in line '{}'", line)))?
+                .trim()
+                .parse()
+                .map_err(invalid_data)?;
+            Ok::<_, io::Error>(())
+        })
+    })?;
+    Ok(Box::new(NoiseProfile::from_power(*power, frames)))
+}
+
+/// Write a spectral template to a file. The first line contains the amount of frames
+/// the template was built from, followed by a line per band containing its power.
+pub fn save_template<P: AsRef<Path>, const BANDS: usize>(
+    path: P,
+    template: &SpectralTemplate<BANDS>,
+) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(&mut writer, "{}", template.frames())?;
+    template
+        .power()
+        .iter()
+        .try_for_each(|p| writeln!(&mut writer, "{}", p))?;
+    writer.flush()
+}
+
+/// Read a spectral template that was written by [save_template]
+pub fn load_template<P: AsRef<Path>, const BANDS: usize>(
+    path: P,
+) -> io::Result<SpectralTemplate<BANDS>> {
+    let mut lines = BufReader::new(File::open(path)?).lines();
+    let frames = lines
+        .next()
+        .ok_or_else(|| invalid_data("Empty template"))??
+        .trim()
+        .parse()
+        .map_err(invalid_data)?;
+
+    let mut power = [0f32; BANDS];
+    power.iter_mut().try_for_each(|p| {
+        *p = lines
+            .next()
+            .ok_or_else(|| invalid_data(format!("Template has less than {} bands", BANDS)))??
+            .trim()
+            .parse()
+            .map_err(invalid_data)?;
+        Ok::<_, io::Error>(())
+    })?;
+    Ok(SpectralTemplate::from_power(power, frames))
 }
@@ -0,0 +1,31 @@
+use cortex_m::peripheral::DWT;
+
+/// Frequency of the cycle counter, in cycles per microsecond
+pub const CYCLES_PER_US: u64 = 64;
+
+/// Extends the 32 bit DWT cycle counter to a 64 bit time since boot. The counter wraps
+/// roughly every 67 seconds, so [Clock::now_us] needs to be called at least that often.
+pub struct Clock {
+    cycles: u64,
+    last: u32,
+}
+
+impl Clock {
+    pub const fn new() -> Self {
+        Self { cycles: 0, last: 0 }
+    }
+
+    /// Microseconds since the cycle counter was enabled
+    pub fn now_us(&mut self) -> u64 {
+        let now = DWT::get_cycle_count();
+        self.cycles += now.wrapping_sub(self.last) as u64;
+        self.last = now;
+        self.cycles / CYCLES_PER_US
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -7,45 +7,72 @@ use defmt_rtt as _; // global logger
 use panic_probe as _;
 
 pub mod consts {
-    use folley_calc::max_lags_size;
+    use folley_calc::{health::HealthLimits, max_lags_size};
+    use folley_format::hello::{Features, Version};
+
+    /// Version of this firmware, as reported to the host
+    pub const FIRMWARE_VERSION: Version = Version::parse(env!("CARGO_PKG_VERSION"));
+    /// Features this firmware always has. The pan/tilt bracket is only added to them
+    /// when it is found at startup.
+    pub const FEATURES: Features = Features::MIC_ARRAY
+        .union(Features::UART)
+        .with_if(Features::FPU, cfg!(feature = "fpu"));
 
     /// Sample period in microseconds
     pub const T_S_US: u32 = 37;
     /// Distance between two mics in millimeters
     pub const D_MICS_MM: u32 = 125;
 
+    /// Supply voltage in millivolts, which the SAADC reference may be derived from
+    pub const VDD_MV: u32 = 3000;
+
     /// Size of a set of samples
     pub const SAMPLE_BUF_SIZE: usize = 1024;
     /// Amount of lags evaluated in the cross correlation
     pub const XCORR_LEN: usize = max_lags_size(T_S_US, D_MICS_MM);
+    /// Sample periods in microseconds the host can configure. The cross correlation
+    /// only covers all physically possible lags at periods of at least [T_S_US].
+    pub const SAMPLE_PERIOD_RANGE_US: (u32, u32) = (T_S_US, 1000);
+    /// Frame lengths the host can configure, up to the size of a set of samples
+    pub const FRAME_LEN_RANGE: (u32, u32) = (128, SAMPLE_BUF_SIZE as u32);
+    /// Cutoff frequency in Hz of the DC blocking filter applied to each channel.
+    /// If `None`, the mean of each set of samples is subtracted instead.
+    pub const DC_BLOCK_CUTOFF_HZ: Option<u32> = Some(20);
+    /// Thresholds for the channel health analysis
+    pub const HEALTH_LIMITS: HealthLimits = HealthLimits::saadc_12bit();
+    /// Frames in which all channels have an RMS value of at most this amount of ADC counts
+    /// are considered silent, and are used to learn the noise profile.
+    pub const SILENCE_MAX_RMS: u32 = 8;
+    /// Interval in milliseconds at which telemetry is sent to the host
+    pub const TELEMETRY_PERIOD_MS: u32 = 1000;
+    /// Interval in milliseconds at which the device checks whether the host went silent
+    pub const HEARTBEAT_POLL_MS: u32 = 100;
+    /// Heartbeat intervals in milliseconds the host can pick, besides 0 to stop them
+    pub const HEARTBEAT_INTERVAL_RANGE_MS: (u32, u32) = (HEARTBEAT_POLL_MS, 60_000);
+    /// Interval in milliseconds at which the pan/tilt bracket is moved a step
+    pub const PAN_TILT_STEP_MS: u32 = 20;
+    /// Presses of a dev kit button within this many milliseconds of the last one are
+    /// taken to be contact bounce
+    pub const BUTTON_DEBOUNCE_MS: u32 = 200;
+    /// Number of commands whose replies are kept, to reply again when the host retries
+    /// one of them
+    pub const RECENT_REPLIES: usize = 8;
+
+    /// Sample type the cross correlation is calculated in
+    #[cfg(not(feature = "fpu"))]
+    pub type ProcessingSample = i16;
+    /// Sample type the cross correlation is calculated in
+    #[cfg(feature = "fpu")]
+    pub type ProcessingSample = f32;
 }
 
-#[cfg(feature = "mic_array")]
+pub mod clock;
+pub mod log;
 pub mod mic_array;
-#[cfg(feature = "pan_tilt")]
 pub mod pan_tilt;
-#[cfg(feature = "uart")]
+pub mod telemetry;
 pub mod uarte;
 
-/// Workaround for RTIC not being able to
-/// conditionally compile resources
-pub mod stubs {
-    use core::marker::PhantomData;
-
-    pub struct Uarte<U, T, P>(PhantomData<U>, PhantomData<T>, PhantomData<P>);
-    pub struct CobsAccumulator<const N: usize>;
-
-    pub struct MicArray<M1, M2, M3, M4, T, P>(
-        PhantomData<M1>,
-        PhantomData<M2>,
-        PhantomData<M3>,
-        PhantomData<M4>,
-        PhantomData<T>,
-        PhantomData<P>,
-    );
-    pub struct PanTilt<T>(PhantomData<T>);
-}
-
 // same panicking *behavior* as `panic-probe` but doesn't print a panic message
 // this prevents the panic message being printed *twice* when `defmt::panic` is invoked
 #[defmt::panic_handler]
@@ -0,0 +1,50 @@
+//! Mirrors selected defmt log messages to the host as
+//! [DeviceToServer::Log](folley_format::DeviceToServer::Log), for devices without a
+//! debug probe attached. Messages are queued so that [mirror!](crate::mirror) works
+//! in tasks of any priority, and are sent by the task bound to [FLUSH_INTERRUPT].
+
+use heapless::mpmc::Q16;
+use nrf52840_hal::pac::Interrupt;
+
+pub use folley_format::log::{Level, LogCode};
+use folley_format::{log::LogArgs, DeviceToServer};
+
+use crate::telemetry::Counter;
+
+/// Interrupt of the task that sends the queued messages to the host
+pub const FLUSH_INTERRUPT: Interrupt = Interrupt::SWI5_EGU5;
+
+static QUEUE: Q16<(Level, LogCode, LogArgs)> = Q16::new();
+/// Messages that were only logged with defmt, because the queue was full
+pub static DROPPED: Counter = Counter::new();
+
+/// Queue a message for the host, and have the flush task send it
+pub fn push(level: Level, code: LogCode, args: &[i64]) {
+    if QUEUE.enqueue((level, code, LogArgs::new(args))).is_err() {
+        DROPPED.increment();
+    }
+    cortex_m::peripheral::NVIC::pend(FLUSH_INTERRUPT);
+}
+
+/// Take the oldest queued message
+pub fn pop() -> Option<DeviceToServer> {
+    QUEUE
+        .dequeue()
+        .map(|(level, code, args)| DeviceToServer::Log { level, code, args })
+}
+
+/// Log a message with defmt, and mirror it to the host as the given [LogCode] with
+/// numeric arguments:
+/// `mirror!(warn, LogCode::CommandRefused, [id], "Refusing command {}: {}", id, error)`
+#[macro_export]
+macro_rules! mirror {
+    (@level trace) => { $crate::log::Level::Trace };
+    (@level debug) => { $crate::log::Level::Debug };
+    (@level info) => { $crate::log::Level::Info };
+    (@level warn) => { $crate::log::Level::Warn };
+    (@level error) => { $crate::log::Level::Error };
+    ($level:ident, $code:expr, [$($arg:expr),* $(,)?], $($defmt:tt)+) => {{
+        defmt::$level!($($defmt)+);
+        $crate::log::push($crate::mirror!(@level $level), $code, &[$($arg as i64),*]);
+    }};
+}
@@ -1,42 +1,48 @@
 #![no_std]
 #![no_main]
 
-use folley_calc::Channels;
+use folley_calc::{dc::DcBlocker, health::FrameHealth, Channels};
 use folley_firmware as firmware;
 use nrf52840_hal as hal;
 
 #[allow(unused_imports)]
 use hal::prelude::*;
 
-use folley_format::{DeviceToServer, ServerToDevice};
+use folley_format::{
+    device_to_server::{CommandError, CommandResult, RecentReplies, Reply},
+    hello::{Features, Hello, Identify},
+    server_to_device::RequestId,
+    status::{DeviceConfig, DeviceStatus, Query},
+    units::{DegreeOffset, Degrees},
+    DeviceToServer, ServerToDevice, PROTOCOL_VERSION,
+};
 use hal::{
     gpio::{
         p0::{self, P0_03, P0_04, P0_28, P0_29},
         Disconnected,
     },
+    gpiote::Gpiote,
     pac::{TIMER0, TIMER1, TIMER2, TWIM0, UARTE0},
     ppi::{self, Ppi0, Ppi3},
     Clocks, Twim,
 };
+use rtic::Mutex;
 
-#[cfg(feature = "pan_tilt")]
-use firmware::pan_tilt::PanTilt;
-#[cfg(not(feature = "pan_tilt"))]
-use firmware::stubs::PanTilt;
-
-#[cfg(not(feature = "uart"))]
-use firmware::stubs::{CobsAccumulator, Uarte};
-#[cfg(feature = "uart")]
-use firmware::uarte::{Baudrate, Parity, Pins as UartePins, Uarte};
-#[cfg(feature = "uart")]
-use postcard::CobsAccumulator;
-
-#[cfg(feature = "mic_array")]
+use firmware::clock::Clock;
 use firmware::mic_array::{MicArray, Pins as MicArrayPins};
-#[cfg(not(feature = "mic_array"))]
-use firmware::stubs::MicArray;
+use firmware::pan_tilt::{PanTilt, PAN_RANGE};
+use firmware::uarte::{Baudrate, Parity, Pins as UartePins, Uarte};
+use folley_calc::noise::NoiseProfile;
+use folley_format::compression::SampleEncoding;
+use folley_format::framing::FrameAccumulator;
+use folley_format::heartbeat::{Heartbeat, LinkEvent, LinkMonitor, MISSED_BEATS};
+use folley_format::mode::Mode;
+use folley_format::motion::{facing_offsets, Motion, MotionEnd};
+use folley_format::stream::{Stream, Subscriptions};
 
 use firmware::consts::*;
+use firmware::log::LogCode;
+use firmware::mirror;
 
 type MicArrayInstance = MicArray<
     P0_03<Disconnected>,
@@ -47,6 +53,55 @@ type MicArrayInstance = MicArray<
     Ppi3,
 >;
 
+type PanTiltInstance = PanTilt<Twim<TWIM0>>;
+
+fn millis(ms: u32) -> rtic::cyccnt::Duration {
+    use firmware::clock::CYCLES_PER_US;
+    use rtic::cyccnt::U32Ext;
+
+    (ms * 1000 * CYCLES_PER_US as u32).cycles()
+}
+
+/// Switch to `mode`, starting or stopping sampling and the bracket as it needs.
+/// Returns whether the mode changed.
+fn enter_mode(
+    mode: Mode,
+    current: &mut impl Mutex<T = Mode>,
+    sampling_enabled: &mut impl Mutex<T = bool>,
+    mic_array: &mut impl Mutex<T = MicArrayInstance>,
+    pan_tilt: &mut impl Mutex<T = Option<PanTiltInstance>>,
+) -> bool {
+    if current.lock(|current| core::mem::replace(current, mode)) == mode {
+        return false;
+    }
+    defmt::info!("Switching to {} mode", mode);
+
+    let was_enabled = sampling_enabled.lock(|e| core::mem::replace(e, mode.samples()));
+    match (was_enabled, mode.samples()) {
+        (false, true) => mic_array.lock(|m| m.start_sampling_task()),
+        (true, false) => mic_array.lock(|m| m.stop_sampling_task()),
+        _ => {}
+    }
+    pan_tilt.lock(|pan_tilt| {
+        if let Some(pan_tilt) = pan_tilt {
+            match mode {
+                // Sweeping back and forth is kept up by step_pan_tilt
+                Mode::Scan => pan_tilt.pan_to_deg(PAN_RANGE.1),
+                _ => pan_tilt.hold(),
+            }
+        }
+    });
+    true
+}
+
+/// The message that replies `reply` to the command with `id`
+fn reply_message(id: RequestId, reply: Reply) -> DeviceToServer {
+    match reply {
+        Ok(result) => DeviceToServer::Ack { id, result },
+        Err(error) => DeviceToServer::Nack { id, error },
+    }
+}
+
 #[rtic::app(
     device=nrf52840_hal::pac,
     peripherals=true,
@@ -54,35 +109,68 @@ type MicArrayInstance = MicArray<
 )]
 const APP: () = {
     struct Resources {
-        #[cfg(feature = "uart")]
-        accumulator: CobsAccumulator<32>,
-        #[cfg(feature = "uart")]
+        accumulator: FrameAccumulator<64>,
         uarte0: Uarte<UARTE0, TIMER0, Ppi0>,
-        #[cfg(feature = "mic_array")]
         mic_array: MicArrayInstance,
-        #[cfg(feature = "pan_tilt")]
-        pan_tilt: PanTilt<Twim<TWIM0>>,
-        #[cfg(feature = "pan_tilt")]
+        /// `None` if no bracket was found at startup
+        pan_tilt: Option<PanTiltInstance>,
         timer1: hal::Timer<TIMER1, hal::timer::Periodic>,
-        #[cfg(feature = "mic_array")]
-        lag_table: [u32; XCORR_LEN],
+        /// Reports presses of the dev kit buttons
+        gpiote: Gpiote,
+        x_lag_table: [u32; XCORR_LEN],
+        y_lag_table: [u32; XCORR_LEN],
+        dc_blockers: [DcBlocker; 4],
+        #[init(NoiseProfile::new())]
+        noise_profile: NoiseProfile<SAMPLE_BUF_SIZE>,
+        #[init(SampleEncoding::Raw)]
+        sample_encoding: SampleEncoding,
+        /// What the device does with its microphone array and bracket
+        mode: Mode,
+        /// Whether the microphone array should be sampled, as opposed to whether a frame
+        /// is being sampled right now
+        #[init(true)]
+        sampling_enabled: bool,
+        /// The streams the host wants to receive
+        #[init(Subscriptions::new())]
+        subscriptions: Subscriptions,
+        /// When the host was last heard from
+        #[init(LinkMonitor::new())]
+        link: LinkMonitor,
+        /// The replies to the last commands that were handled, which are sent again
+        /// instead of handling a command twice when the host retries it
+        #[init(RecentReplies::new())]
+        recent_replies: RecentReplies<RECENT_REPLIES>,
+        #[init(Clock::new())]
+        clock: Clock,
+        /// Number of frames sampled since boot
+        #[init(0)]
+        frame: u32,
+        /// Number of samples acquired since boot
+        #[init(0)]
+        sample_index: u64,
+        hello: Hello,
     }
 
     // Initialize peripherals, before interrupts are unmasked
     // Returns all resources that need to be dynamically instantiated
-    #[init(spawn = [read_uarte0, send_message])]
-    #[allow(unused_variables)]
+    #[init(
+        spawn = [read_uarte0, send_message],
+        schedule = [send_telemetry, send_heartbeat]
+    )]
     fn init(ctx: init::Context) -> init::LateResources {
+        // Timestamps and the uptime are taken from the cycle counter
+        let mut core = ctx.core;
+        core.DCB.enable_trace();
+        core.DWT.enable_cycle_counter();
+
         // Initialize UARTE0
         // Initialize port0
         let port0 = p0::Parts::new(ctx.device.P0);
-        #[cfg_attr(not(any(feature = "uart", feature = "mic_array")), allow(unused_mut))]
-        let mut ppi = ppi::Parts::new(ctx.device.PPI);
+        let ppi = ppi::Parts::new(ctx.device.PPI);
 
         let clocks = Clocks::new(ctx.device.CLOCK);
         clocks.enable_ext_hfosc();
 
-        #[cfg(feature = "uart")]
         let (uarte0, accumulator) = {
             use hal::gpio::Level;
             use hal::timer::Timer;
@@ -116,12 +204,10 @@ const APP: () = {
                 timer0,
                 ppi.ppi0,
             );
-            ctx.spawn.send_message(DeviceToServer::Sync).ok();
-            let accumulator = CobsAccumulator::new();
+            let accumulator = FrameAccumulator::new();
             (uarte0, accumulator)
         };
 
-        #[cfg(feature = "pan_tilt")]
         let (pan_tilt, timer1) = {
             use hal::timer::Timer;
             use hal::twim::Pins as TwimPins;
@@ -129,20 +215,44 @@ const APP: () = {
             let scl = port0.p0_30.into_floating_input().degrade();
             let sda = port0.p0_31.into_floating_input().degrade();
 
-            let mut timer1 = Timer::periodic(ctx.device.TIMER1);
-            timer1.start(20_000u32); // 100 ms
-            timer1.enable_interrupt();
-
             let twim0_pins = TwimPins { scl, sda };
-            let pan_tilt = PanTilt::new(ctx.device.TWIM0, twim0_pins, 0, 0);
+            let pan_tilt =
+                PanTilt::new(ctx.device.TWIM0, twim0_pins, Degrees::ZERO, Degrees::ZERO).ok();
+
+            let mut timer1 = Timer::periodic(ctx.device.TIMER1);
+            timer1.start(PAN_TILT_STEP_MS * 1000);
+            if pan_tilt.is_some() {
+                timer1.enable_interrupt();
+            } else {
+                defmt::warn!("No pan/tilt bracket found, carrying on without it");
+            }
 
             (pan_tilt, timer1)
         };
 
-        #[cfg(feature = "mic_array")]
-        let mic_array = {
+        let gpiote = {
+            let gpiote = Gpiote::new(ctx.device.GPIOTE);
+
+            // Button 1 switches to the next mode, button 2 back to idle
+            let btn1_pin = port0.p0_11.into_pullup_input().degrade();
+            gpiote
+                .channel1()
+                .input_pin(&btn1_pin)
+                .hi_to_lo()
+                .enable_interrupt();
+            let btn2_pin = port0.p0_12.into_pullup_input().degrade();
+            gpiote
+                .channel2()
+                .input_pin(&btn2_pin)
+                .hi_to_lo()
+                .enable_interrupt();
+            gpiote
+        };
+
+        let (mic_array, x_lag_table, y_lag_table, acquisition) = {
             use embedded_hal::timer::CountDown;
-            use hal::gpiote::Gpiote;
+            use firmware::mic_array::{adc_scale, scan_timing};
+            use folley_calc::scan::gen_skewed_lag_table;
             use hal::saadc::{Gain, Oversample, Resistor, Resolution, SaadcConfig, Time};
             use hal::timer::Timer;
 
@@ -161,43 +271,71 @@ const APP: () = {
                 ..SaadcConfig::default()
             };
 
-            let gpiote = Gpiote::new(ctx.device.GPIOTE);
             let mut timer2 = Timer::periodic(ctx.device.TIMER2);
-
-            let btn1_pin = port0.p0_11.into_pullup_input().degrade();
-            gpiote.channel1().input_pin(&btn1_pin).hi_to_lo();
-            ppi.ppi1.set_task_endpoint(timer2.task_stop());
-            ppi.ppi1.set_event_endpoint(gpiote.channel1().event());
-            ppi.ppi1.enable();
-
-            let btn2_pin = port0.p0_12.into_pullup_input().degrade();
-            gpiote.channel2().input_pin(&btn2_pin).hi_to_lo();
-            ppi.ppi2.set_task_endpoint(timer2.task_start());
-            ppi.ppi2.set_event_endpoint(gpiote.channel2().event());
-            ppi.ppi2.enable();
-
             timer2.start(T_S_US);
 
+            let adc_scale = adc_scale(&saadc_config, VDD_MV);
+            defmt::info!(
+                "SAADC full scale: {} uV",
+                adc_scale.to_microvolts(1 << adc_scale.resolution_bits)
+            );
+            let scan_timing = scan_timing(&saadc_config);
+            debug_assert!(scan_timing.scan_time_ns() <= T_S_US * 1000);
+            let x_lag_table =
+                gen_skewed_lag_table::<T_S_US, D_MICS_MM, XCORR_LEN>(scan_timing.skew_ns(0, 1));
+            let y_lag_table =
+                gen_skewed_lag_table::<T_S_US, D_MICS_MM, XCORR_LEN>(scan_timing.skew_ns(2, 3));
+
             let mut mic_array =
                 MicArray::new(ctx.device.SAADC, mic_pins, saadc_config, timer2, ppi.ppi3);
 
+            // Both modes the device starts in sample the microphone array
             mic_array.start_sampling_task();
-            mic_array
+
+            let acquisition = folley_format::hello::Acquisition {
+                sample_period_us: T_S_US,
+                mic_distance_mm: D_MICS_MM,
+                frame_len: SAMPLE_BUF_SIZE as u32,
+                adc_scale,
+                oversample_log2: scan_timing.oversample_log2,
+                acquisition_time_us: scan_timing.acq_time_us,
+            };
+            (mic_array, x_lag_table, y_lag_table, acquisition)
+        };
+
+        // Track sounds if there's a bracket to point at them, otherwise only measure
+        let mode = if pan_tilt.is_some() {
+            Mode::AutoTrack
+        } else {
+            Mode::Measure
         };
+        let hello = Hello {
+            protocol_version: PROTOCOL_VERSION,
+            firmware_version: FIRMWARE_VERSION,
+            features: FEATURES.with_if(Features::PAN_TILT, pan_tilt.is_some()),
+            acquisition: Some(acquisition),
+        };
+        defmt::info!("{} in {} mode", hello, mode);
+        ctx.spawn.send_message(DeviceToServer::Hello(hello)).ok();
+        ctx.schedule
+            .send_telemetry(ctx.start + millis(TELEMETRY_PERIOD_MS))
+            .ok();
+        ctx.schedule
+            .send_heartbeat(ctx.start + millis(HEARTBEAT_POLL_MS))
+            .ok();
 
         init::LateResources {
-            #[cfg(feature = "uart")]
             uarte0,
-            #[cfg(feature = "uart")]
             accumulator,
-            #[cfg(feature = "mic_array")]
             mic_array,
-            #[cfg(feature = "pan_tilt")]
             pan_tilt,
-            #[cfg(feature = "pan_tilt")]
             timer1,
-            #[cfg(feature = "mic_array")]
-            lag_table: folley_calc::gen_lag_table::<T_S_US, D_MICS_MM, XCORR_LEN>(),
+            gpiote,
+            x_lag_table,
+            y_lag_table,
+            dc_blockers: [DcBlocker::with_cutoff(DC_BLOCK_CUTOFF_HZ.unwrap_or(0), T_S_US); 4],
+            mode,
+            hello,
         }
     }
 
@@ -210,70 +348,325 @@ const APP: () = {
         }
     }
 
-    #[task(capacity = 5, priority = 10, resources = [pan_tilt, mic_array], spawn = [send_message])]
-    #[cfg_attr(not(feature = "pan_tilt"), allow(unused_mut))]
-    #[cfg_attr(
-        not(any(feature = "mic_array", feature = "pan_tilt")),
-        allow(unused_variables)
+    #[task(
+        capacity = 5,
+        priority = 10,
+        resources = [
+            pan_tilt,
+            mic_array,
+            hello,
+            x_lag_table,
+            y_lag_table,
+            dc_blockers,
+            noise_profile,
+            sample_encoding,
+            subscriptions,
+            clock,
+            link,
+            mode,
+            sampling_enabled,
+            recent_replies
+        ],
+        spawn = [send_message]
     )]
     fn handle_message(mut ctx: handle_message::Context, msg: ServerToDevice) {
+        use firmware::pan_tilt::{check_limits, check_range, TILT_RANGE};
+        use folley_calc::scan::gen_skewed_lag_table_for;
+
         let ServerToDevice {
-            #[cfg(feature = "pan_tilt")]
+            id,
             pan_degrees,
-            #[cfg(feature = "pan_tilt")]
             tilt_degrees,
-            #[cfg(feature = "mic_array")]
             set_sampling_enabled,
-            ..
+            identify,
+            configure,
+            sample_encoding,
+            subscribe,
+            heartbeat,
+            query,
+            set_mode,
+            motion,
+            motion_limits,
         } = msg;
 
-        #[cfg(feature = "pan_tilt")]
+        // A host that identifies itself may have restarted and numbers its commands anew
+        let mut recent_replies = ctx.resources.recent_replies;
+        let repeated = recent_replies.lock(|replies| {
+            if identify.is_some() {
+                replies.clear();
+            }
+            replies.get(id)
+        });
+        if let Some(reply) = repeated {
+            defmt::debug!("Command {} was already handled, replying again", id);
+            ctx.spawn.send_message(reply_message(id, reply)).ok();
+            return;
+        }
+
+        // Refuse the whole command if any part of it can't be carried out
+        let moves_bracket = pan_degrees.is_some() || tilt_degrees.is_some() || motion.is_some();
+        let stops = matches!(motion, Some(Motion::Stop));
+        let missing = Features::empty()
+            .with_if(Features::PAN_TILT, moves_bracket)
+            .with_if(Features::PAN_TILT, motion_limits.is_some())
+            .with_if(Features::MIC_ARRAY, set_sampling_enabled.is_some())
+            .with_if(Features::MIC_ARRAY, configure.is_some())
+            .with_if(Features::MIC_ARRAY, sample_encoding.is_some())
+            .with_if(
+                subscribe.map_or(Features::empty(), |s| s.stream.features()),
+                // Unsubscribing from a stream the device doesn't have is fine
+                subscribe.map_or(false, |s| s.decimation > 0),
+            )
+            .with_if(
+                set_mode.map_or(Features::empty(), Mode::features),
+                set_mode.is_some(),
+            )
+            .without(ctx.resources.hello.features);
+        let checked = if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(CommandError::Unsupported(missing))
+        };
+        // The rest of the command is carried out in the mode it switches to
+        let mode = set_mode.unwrap_or_else(|| ctx.resources.mode.lock(|m| *m));
+        // Stopping is fine in any mode, but one that moves the bracket by itself would
+        // just start it again, so the host takes over instead
+        let set_mode = match mode {
+            Mode::AutoTrack | Mode::Scan if stops => Some(Mode::ManualControl),
+            _ => set_mode,
+        };
+        let checked = if (moves_bracket && !stops && mode != Mode::ManualControl)
+            || (set_sampling_enabled == Some(true) && !mode.samples())
         {
-            let pan_tilt_status = ctx.resources.pan_tilt.lock(|pan_tilt| {
-                if let Some(deg) = pan_degrees {
-                    defmt::debug!("Pan to {} degrees", deg);
-                    pan_tilt.pan_to_deg(deg);
-                }
-                if let Some(deg) = tilt_degrees {
-                    defmt::debug!("Tilt to {} degrees", deg);
-                    pan_tilt.tilt_to_deg(deg);
-                }
-                pan_tilt.status()
-            });
+            checked.and(Err(CommandError::WrongMode(mode)))
+        } else {
+            checked
+        };
+        let (min_ms, max_ms) = HEARTBEAT_INTERVAL_RANGE_MS;
+        let checked = match heartbeat {
+            Some(h) if h.interval_ms != 0 && (h.interval_ms < min_ms || h.interval_ms > max_ms) => {
+                checked.and(Err(CommandError::HeartbeatInterval { min_ms, max_ms }))
+            }
+            _ => checked,
+        };
+        let mut pan_tilt = ctx.resources.pan_tilt;
+        let goal = pan_tilt.lock(|pan_tilt| {
+            let pan_tilt = pan_tilt.as_ref().filter(|_| moves_bracket)?;
+            let mut goal = pan_tilt.status();
+            goal.pan_deg = pan_degrees.unwrap_or(goal.pan_deg);
+            goal.tilt_deg = tilt_degrees.unwrap_or(goal.tilt_deg);
+            Some(motion.map_or(goal, |motion| pan_tilt.goal_of(motion, goal)))
+        });
+        let checked = checked
+            .and(goal.map_or(Ok(()), |goal| check_range(goal.pan_deg, PAN_RANGE)))
+            .and(goal.map_or(Ok(()), |goal| check_range(goal.tilt_deg, TILT_RANGE)))
+            .and(motion_limits.as_ref().map_or(Ok(()), check_limits));
+        let reconfiguration =
+            configure
+                .zip(ctx.resources.hello.acquisition)
+                .map(|(configure, acquisition)| {
+                    let acquisition = acquisition.configured(&configure);
+                    firmware::mic_array::check_acquisition(&acquisition)
+                        .map(|settings| (acquisition, settings))
+                        .map_err(CommandError::InvalidConfig)
+                });
+        let checked = match &reconfiguration {
+            Some(Err(error)) => checked.and(Err(*error)),
+            _ => checked,
+        };
+        if let Err(error) = checked {
+            mirror!(
+                warn,
+                LogCode::CommandRefused,
+                [id],
+                "Refusing command {}: {}",
+                id,
+                error
+            );
+            recent_replies.lock(|replies| replies.record(id, Err(error)));
             ctx.spawn
-                .send_message(DeviceToServer::PanTilt(pan_tilt_status))
+                .send_message(DeviceToServer::Nack { id, error })
                 .ok();
-        };
-        #[cfg(feature = "mic_array")]
-        {
-            let mut mic_array = ctx.resources.mic_array;
-            match set_sampling_enabled {
-                Some(true) => mic_array.lock(|m| m.start_sampling_task()),
-                Some(false) => mic_array.lock(|m| m.stop_sampling_task()),
-                None => {}
+            return;
+        }
+
+        if let Some(Identify { protocol_version }) = identify {
+            if protocol_version != PROTOCOL_VERSION {
+                mirror!(
+                    warn,
+                    LogCode::ProtocolMismatch,
+                    [protocol_version, PROTOCOL_VERSION],
+                    "Host speaks protocol version {}, this device speaks {}",
+                    protocol_version,
+                    PROTOCOL_VERSION
+                );
+            }
+            ctx.spawn
+                .send_message(DeviceToServer::Hello(*ctx.resources.hello))
+                .ok();
+        }
+
+        let mut mic_array = ctx.resources.mic_array;
+        let mut sampling_enabled = ctx.resources.sampling_enabled;
+        if let Some(mode) = set_mode {
+            if enter_mode(
+                mode,
+                &mut ctx.resources.mode,
+                &mut sampling_enabled,
+                &mut mic_array,
+                &mut pan_tilt,
+            ) {
+                ctx.spawn
+                    .send_message(DeviceToServer::ModeChanged(mode))
+                    .ok();
+            }
+        }
+
+        let mut result = CommandResult::Done;
+        if let Some(limits) = motion_limits {
+            defmt::debug!("Moving the bracket within {}", limits);
+            pan_tilt.lock(|p| p.as_mut().map(|p| p.set_limits(&limits)));
+        }
+        // Only present if the command moves the bracket, and there is one
+        if let Some(goal) = goal {
+            let moved = pan_tilt.lock(|pan_tilt| {
+                let pan_tilt = pan_tilt.as_mut()?;
+                if let Some(Motion::Stop) = motion {
+                    defmt::debug!("Stopping the bracket");
+                    pan_tilt.hold();
+                } else {
+                    defmt::debug!("Moving the bracket to {}", goal);
+                    pan_tilt.pan_to_deg(goal.pan_deg);
+                    pan_tilt.tilt_to_deg(goal.tilt_deg);
+                }
+                let interrupted = pan_tilt.track_motion(id);
+                Some((pan_tilt.status(), interrupted, pan_tilt.position()))
+            });
+            if let Some((status, interrupted, position)) = moved {
+                if let Some(id) = interrupted {
+                    let end = MotionEnd::Interrupted;
+                    ctx.spawn
+                        .send_message(DeviceToServer::MotionDone { id, end, position })
+                        .ok();
+                }
+                result = CommandResult::PanTilt(status);
             }
         }
+        let mut reconfigured = false;
+        if let Some(Ok((acquisition, settings))) = reconfiguration {
+            mirror!(
+                info,
+                LogCode::Reconfigured,
+                [acquisition.sample_period_us, acquisition.frame_len],
+                "Reconfiguring acquisition: {}",
+                acquisition
+            );
+            mic_array.lock(|m| m.reconfigure(&settings));
+
+            let period_us = acquisition.sample_period_us;
+            let scan_timing = settings.scan_timing();
+            *ctx.resources.x_lag_table =
+                gen_skewed_lag_table_for(period_us, D_MICS_MM, scan_timing.skew_ns(0, 1));
+            *ctx.resources.y_lag_table =
+                gen_skewed_lag_table_for(period_us, D_MICS_MM, scan_timing.skew_ns(2, 3));
+            let cutoff_hz = DC_BLOCK_CUTOFF_HZ.unwrap_or(0);
+            ctx.resources
+                .dc_blockers
+                .lock(|b| *b = [DcBlocker::with_cutoff(cutoff_hz, period_us); 4]);
+            // The noise spectrum depends on the sample period and frame length
+            ctx.resources.noise_profile.reset();
+
+            ctx.resources.hello.acquisition = Some(acquisition);
+            result = CommandResult::Configured(acquisition);
+            reconfigured = true;
+        }
+        if let Some(enabled) = set_sampling_enabled {
+            sampling_enabled.lock(|e| *e = enabled);
+        }
+        match set_sampling_enabled {
+            Some(true) => mic_array.lock(|m| m.start_sampling_task()),
+            Some(false) => mic_array.lock(|m| m.stop_sampling_task()),
+            // Reconfiguring stops sampling
+            None if reconfigured && sampling_enabled.lock(|e| *e) => {
+                mic_array.lock(|m| m.start_sampling_task())
+            }
+            None => {}
+        }
+        if let Some(encoding) = sample_encoding {
+            defmt::debug!("Sending samples as {}", encoding);
+            ctx.resources.sample_encoding.lock(|e| *e = encoding);
+        }
+        if let Some(subscribe) = subscribe {
+            defmt::debug!("Subscribing to {}", subscribe);
+            ctx.resources
+                .subscriptions
+                .lock(|subscriptions| subscriptions.subscribe(subscribe));
+        }
+        if let Some(Heartbeat { interval_ms }) = heartbeat {
+            let now_ms = ctx.resources.clock.lock(|clock| clock.now_us()) / 1000;
+            ctx.resources.link.lock(|link| {
+                if link.interval_ms() != interval_ms {
+                    defmt::debug!("Expecting heartbeats every {} ms", interval_ms);
+                    link.set_interval(interval_ms, now_ms);
+                }
+            });
+        }
+        // Answer after the rest of the command was carried out, so the answer reflects it
+        if let Some(query) = query {
+            result = match query {
+                Query::Status => {
+                    let (pan_tilt_goal, pan_tilt_actual) = pan_tilt.lock(|pan_tilt| {
+                        let pan_tilt = pan_tilt.as_ref();
+                        (pan_tilt.map(|p| p.status()), pan_tilt.map(|p| p.position()))
+                    });
+
+                    CommandResult::Status(DeviceStatus {
+                        uptime_ms: ctx.resources.clock.lock(|clock| clock.now_us()) / 1000,
+                        mode: ctx.resources.mode.lock(|m| *m),
+                        sampling_enabled: sampling_enabled.lock(|e| *e),
+                        safe_state: ctx.resources.link.lock(|link| link.is_lost()),
+                        pan_tilt_goal,
+                        pan_tilt_actual,
+                    })
+                }
+                Query::Config => {
+                    let acquisition = ctx.resources.hello.acquisition;
+                    let calibration = acquisition.and_then(|acquisition| {
+                        let noise_frames = ctx.resources.noise_profile.frames();
+                        firmware::mic_array::calibration(&acquisition, noise_frames).ok()
+                    });
+
+                    CommandResult::Config(DeviceConfig {
+                        acquisition,
+                        calibration,
+                        heartbeat_interval_ms: ctx.resources.link.lock(|link| link.interval_ms()),
+                        motion_limits: pan_tilt.lock(|p| p.as_ref().map(|p| p.limits())),
+                    })
+                }
+            };
+        }
+        recent_replies.lock(|replies| replies.record(id, Ok(result)));
+        ctx.spawn
+            .send_message(DeviceToServer::Ack { id, result })
+            .ok();
     }
 
     #[task(capacity = 10, resources = [uarte0], priority  = 99)]
-    #[cfg_attr(not(feature = "uart"), allow(unused_variables, unused_mut))]
     fn send_message(mut ctx: send_message::Context, msg: DeviceToServer) {
-        #[cfg(feature = "uart")]
-        {
-            use firmware::uarte::StartTxResult::Busy;
+        use firmware::uarte::StartTxResult::Busy;
 
-            while let Busy = ctx
-                .resources
-                .uarte0
-                .lock(|uarte0| uarte0.try_start_tx(&msg))
-            {
-                // while let Busy = ctx.resources.uarte0.try_start_tx(bytes){
-                defmt::trace!("Waiting for currently running tx task to finish");
-                // Go to sleep to avoid busy waiting
-                cortex_m::asm::wfi();
-            }
-            defmt::debug!("Sent!");
+        while let Busy = ctx
+            .resources
+            .uarte0
+            .lock(|uarte0| uarte0.try_start_tx(&msg))
+        {
+            // while let Busy = ctx.resources.uarte0.try_start_tx(bytes){
+            defmt::trace!("Waiting for currently running tx task to finish");
+            firmware::telemetry::TX_BUSY_WAITS.increment();
+            // Go to sleep to avoid busy waiting
+            cortex_m::asm::wfi();
         }
+        defmt::debug!("Sent!");
     }
 
     #[task(
@@ -282,151 +675,546 @@ const APP: () = {
         resources = [uarte0],
         spawn = [read_uarte0],
     )]
-    #[cfg_attr(not(feature = "uart"), allow(unused_variables, unused_mut))]
     fn on_uarte0(mut ctx: on_uarte0::Context) {
-        #[cfg(feature = "uart")]
-        {
-            use firmware::uarte::UarteEvent::*;
-            defmt::trace!("Running task on_uarte0");
+        use firmware::uarte::UarteEvent::*;
+        defmt::trace!("Running task on_uarte0");
 
-            ctx.resources.uarte0.lock(|uarte0| {
-                if let Some(EndRx) = uarte0.get_clear_event() {
-                    ctx.spawn.read_uarte0().ok();
-                }
-            });
-        }
+        ctx.resources.uarte0.lock(|uarte0| {
+            if let Some(EndRx) = uarte0.get_clear_event() {
+                ctx.spawn.read_uarte0().ok();
+            }
+        });
     }
 
     #[task(
         priority = 101,
-        resources = [uarte0, accumulator],
-        spawn = [handle_message],
+        resources = [uarte0, accumulator, clock, link, recent_replies],
+        spawn = [handle_message, send_message],
     )]
-    #[cfg_attr(not(feature = "uart"), allow(unused_variables))]
-    fn read_uarte0(ctx: read_uarte0::Context) {
-        #[cfg(feature = "uart")]
-        {
-            use postcard::FeedResult::*;
-
-            // We have ownership declared in the resources
-            let chunk = ctx.resources.uarte0.get_rx_chunk();
-            match ctx.resources.accumulator.feed(chunk) {
-                Consumed => {}
-                OverFull(_) => defmt::warn!("Accumulator full, dropping contents"),
-                DeserError(_) => defmt::error!("Deserialize error, throwing away message"),
-                Success { data, .. } => ctx
-                    .spawn
-                    .handle_message(data)
-                    .expect("Could not start handle_message task, please increase its capacity."),
+    fn read_uarte0(mut ctx: read_uarte0::Context) {
+        use folley_format::framing::{FeedResult::*, SeqStatus};
+
+        // We have ownership declared in the resources
+        let mut chunk = ctx.resources.uarte0.get_rx_chunk();
+        let accumulator = ctx.resources.accumulator;
+        // A chunk may hold the ends of several frames
+        while !chunk.is_empty() {
+            chunk = match accumulator.feed(chunk) {
+                Consumed => &[],
+                OverFull(rest) => {
+                    let stats = accumulator.stats();
+                    mirror!(
+                        warn,
+                        LogCode::AccumulatorFull,
+                        [stats.overflows],
+                        "Accumulator full, dropping contents. {}",
+                        stats
+                    );
+                    rest
+                }
+                Corrupt(rest) => {
+                    let stats = accumulator.stats();
+                    mirror!(
+                        warn,
+                        LogCode::CorruptFrame,
+                        [stats.corrupt],
+                        "Corrupt frame, throwing it away. {}",
+                        stats
+                    );
+                    rest
+                }
+                DeserError(rest) => {
+                    let stats = accumulator.stats();
+                    mirror!(
+                        error,
+                        LogCode::DeserializeError,
+                        [stats.deser_errors],
+                        "Deserialize error, throwing away message. {}",
+                        stats
+                    );
+                    rest
+                }
+                Success {
+                    data,
+                    seq,
+                    remaining,
+                } => {
+                    let now_ms = ctx.resources.clock.lock(|clock| clock.now_us()) / 1000;
+                    if let Some(LinkEvent::Restored) = ctx.resources.link.seen(now_ms) {
+                        mirror!(info, LogCode::LinkRestored, [], "Heard from the host again");
+                    }
+                    // The host restarted, so its command IDs start over as well
+                    if seq == SeqStatus::Resync {
+                        ctx.resources.recent_replies.clear();
+                    }
+                    if seq != SeqStatus::InOrder {
+                        let stats = accumulator.stats();
+                        mirror!(
+                            warn,
+                            LogCode::FrameOutOfSequence,
+                            [stats.dropped, stats.out_of_order],
+                            "Received frame: {}. {}",
+                            seq,
+                            stats
+                        );
+                    }
+                    if let Err(msg) = ctx.spawn.handle_message(data) {
+                        mirror!(
+                            warn,
+                            LogCode::CommandQueueFull,
+                            [msg.id],
+                            "Too many commands queued, refusing command {}",
+                            msg.id
+                        );
+                        firmware::telemetry::SPAWN_FAILURES.increment();
+                        let error = CommandError::Busy;
+                        ctx.spawn
+                            .send_message(DeviceToServer::Nack { id: msg.id, error })
+                            .ok();
+                    }
+                    remaining
+                }
             }
         }
     }
 
-    #[task(binds = SAADC, priority = 255, resources = [mic_array], spawn = [on_samples, send_message])]
-    #[cfg_attr(not(feature = "mic_array"), allow(unused_variables))]
+    #[task(binds = SAADC, priority = 255, resources = [mic_array, dc_blockers, clock, frame, sample_index, sample_encoding, subscriptions], spawn = [on_samples, send_message])]
     fn on_saadc(ctx: on_saadc::Context) {
-        #[cfg(feature = "mic_array")]
-        {
-            let mic_array = ctx.resources.mic_array;
+        use firmware::telemetry::{FRAMES_DROPPED, SPAWN_FAILURES};
+        use folley_calc::DcRemoval;
 
-            mic_array.stop_sampling_task();
+        let mic_array = ctx.resources.mic_array;
 
-            let channels = {
-                let samples = mic_array.get_newest_samples();
+        mic_array.stop_sampling_task();
+        let timestamp_us = ctx.resources.clock.now_us();
+        let frame = *ctx.resources.frame;
+        *ctx.resources.frame = frame.wrapping_add(1);
 
-                #[cfg(feature = "uart")]
-                {
-                    for c in samples.chunks(SAMPLE_BUF_SIZE) {
-                        let msg = DeviceToServer::Samples(c.try_into().unwrap());
-                        ctx.spawn.send_message(DeviceToServer::Sync).ok();
-                        if let Err(_) = ctx.spawn.send_message(msg) {
-                            defmt::warn!("Error spawning send_message task");
-                        }
-                        ctx.spawn.send_message(DeviceToServer::Sync).ok();
-                    }
+        let sample_period_us = mic_array.sample_period_us();
+
+        let (channels, health, start_index) = {
+            let samples = mic_array.get_newest_samples();
+            let mut health = FrameHealth::analyze(&samples[..], &HEALTH_LIMITS);
+            let start_index = *ctx.resources.sample_index;
+            *ctx.resources.sample_index += samples.len() as u64;
+
+            if ctx.resources.subscriptions.tick(Stream::Samples) {
+                use folley_format::chunk::{ChannelMask, SampleChunk};
+
+                // The timestamp is taken after the last sample of the frame
+                let elapsed_us = samples.len().saturating_sub(1) as u64 * sample_period_us as u64;
+                let msg = DeviceToServer::Chunk(SampleChunk::encode(
+                    start_index,
+                    timestamp_us.saturating_sub(elapsed_us),
+                    ChannelMask::ALL,
+                    samples,
+                    *ctx.resources.sample_encoding,
+                ));
+                if ctx.spawn.send_message(msg).is_err() {
+                    mirror!(
+                        warn,
+                        LogCode::SendFailed,
+                        [],
+                        "Error spawning send_message task"
+                    );
+                    SPAWN_FAILURES.increment();
                 }
-                Channels::<SAMPLE_BUF_SIZE>::from_samples((*samples).try_into().unwrap())
+            }
+            let dc_removal = match DC_BLOCK_CUTOFF_HZ {
+                Some(_) => DcRemoval::Blocker(ctx.resources.dc_blockers),
+                None => DcRemoval::FrameMean,
             };
+            let channels =
+                Channels::<i16, SAMPLE_BUF_SIZE>::from_samples_padded(samples, dc_removal);
+            health.count_saturation(&channels);
+            (channels, health, start_index)
+        };
+
+        if ctx
+            .spawn
+            .on_samples(channels, health, frame, start_index, timestamp_us)
+            .is_err()
+        {
+            mirror!(
+                warn,
+                LogCode::FrameDropped,
+                [frame],
+                "Could not spawn on_samples task"
+            );
+            SPAWN_FAILURES.increment();
+            FRAMES_DROPPED.increment();
+        };
+    }
+
+    #[task(
+        priority = 10,
+        resources = [x_lag_table, y_lag_table, noise_profile, subscriptions, mode],
+        spawn = [start_sampling, move_bracket, send_message]
+    )]
+    fn on_samples(
+        mut ctx: on_samples::Context,
+        mut channels: Channels<i16, SAMPLE_BUF_SIZE>,
+        health: FrameHealth,
+        frame: u32,
+        start_index: u64,
+        timestamp_us: u64,
+    ) {
+        use firmware::telemetry::{FRAMES_PROCESSED, SPAWN_FAILURES};
+        use folley_calc::health::estimate_pair_checked;
+        use folley_calc::noise::{is_silent, SuppressionParams};
+        use folley_calc::sample::Sample;
+        use folley_format::device_to_server::Detection;
+
+        let mut subscriptions = ctx.resources.subscriptions;
+        let noise_profile = ctx.resources.noise_profile;
+        let params = SuppressionParams::default();
+        if is_silent(&health, SILENCE_MAX_RMS) {
+            defmt::debug!("Silent frame, learning noise profile");
+            noise_profile.learn(&channels, &params);
+        }
+        noise_profile.suppress(&mut channels, &params);
 
-            if let Err(_) = ctx.spawn.on_samples(channels) {
-                defmt::warn!("Could not spawn on_samples task");
+        let channels = channels.map(ProcessingSample::from);
+        let mut x_buf = [<ProcessingSample as Sample>::Acc::default(); XCORR_LEN];
+        let x_angle = estimate_pair_checked::<_, T_S_US, D_MICS_MM, XCORR_LEN, SAMPLE_BUF_SIZE>(
+            &channels,
+            0,
+            1,
+            &health,
+            &HEALTH_LIMITS,
+            &mut x_buf,
+            ctx.resources.x_lag_table,
+        );
+        let mut y_buf = [<ProcessingSample as Sample>::Acc::default(); XCORR_LEN];
+        let y_angle = estimate_pair_checked::<_, T_S_US, D_MICS_MM, XCORR_LEN, SAMPLE_BUF_SIZE>(
+            &channels,
+            2,
+            3,
+            &health,
+            &HEALTH_LIMITS,
+            &mut y_buf,
+            ctx.resources.y_lag_table,
+        );
+
+        if subscriptions.lock(|s| s.tick(Stream::Xcorr)) {
+            use folley_calc::health::{PairError, PairEstimate};
+            use folley_format::device_to_server::{PairXcorr, Xcorr};
+
+            let pair = |estimate: &Result<PairEstimate, PairError>, buf: &[_]| PairXcorr {
+                lag: estimate.as_ref().ok().map(|e| e.lag as i16),
+                values: buf
+                    .iter()
+                    .map(|&acc| ProcessingSample::acc_to_f32(acc))
+                    .collect(),
+            };
+            let xcorr = Xcorr {
+                frame,
+                start_index,
+                azimuth: pair(&x_angle, &x_buf),
+                elevation: pair(&y_angle, &y_buf),
             };
+            if ctx
+                .spawn
+                .send_message(DeviceToServer::Xcorr(xcorr))
+                .is_err()
+            {
+                SPAWN_FAILURES.increment();
+            }
+        }
+
+        match (x_angle, y_angle) {
+            (Ok(x), Ok(y)) => {
+                defmt::info!("x: {}\t\ty: {}", x.bearing.degrees(), y.bearing.degrees());
+                let detection = Detection {
+                    frame,
+                    timestamp_us,
+                    azimuth: x.bearing,
+                    elevation: y.bearing,
+                    azimuth_lag: x.lag as i16,
+                    elevation_lag: y.lag as i16,
+                    confidence_permille: x.correlation_permille.min(y.correlation_permille) as u16,
+                };
+
+                if subscriptions.lock(|s| s.tick(Stream::Detections))
+                    && ctx
+                        .spawn
+                        .send_message(DeviceToServer::Detection(detection))
+                        .is_err()
+                {
+                    mirror!(
+                        warn,
+                        LogCode::SendFailed,
+                        [],
+                        "Error spawning send_message task"
+                    );
+                    SPAWN_FAILURES.increment();
+                }
+
+                let (pan_offset, tilt_offset) =
+                    facing_offsets(detection.azimuth, detection.elevation);
+                if ctx.resources.mode.lock(|m| *m) == Mode::AutoTrack
+                    && ctx.spawn.move_bracket(pan_offset, tilt_offset).is_err()
+                {
+                    mirror!(
+                        error,
+                        LogCode::MoveBracketFailed,
+                        [],
+                        "Could not spawn move_bracket task"
+                    );
+                    SPAWN_FAILURES.increment();
+                }
+            }
+            (x_angle, y_angle) => {
+                defmt::warn!("Not using angles. x: {}\t\ty: {}", x_angle, y_angle);
+            }
+        }
+
+        FRAMES_PROCESSED.increment();
+        if ctx.spawn.start_sampling().is_err() {
+            mirror!(
+                error,
+                LogCode::StartSamplingFailed,
+                [],
+                "Could not spawn start_sampling task"
+            );
+            SPAWN_FAILURES.increment();
         }
     }
 
-    #[task(priority = 10, resources = [lag_table], spawn = [start_sampling, move_bracket])]
-    #[cfg_attr(not(feature = "mic_array"), allow(unused_variables))]
-    fn on_samples(ctx: on_samples::Context, channels: Channels<SAMPLE_BUF_SIZE>) {
-        #[cfg(feature = "mic_array")]
+    #[task(
+        priority = 10,
+        resources = [clock, accumulator, pan_tilt, subscriptions],
+        schedule = [send_telemetry],
+        spawn = [send_message]
+    )]
+    fn send_telemetry(mut ctx: send_telemetry::Context) {
+        // Running at least once per wrap of the cycle counter also keeps the clock right
+        let uptime_us = ctx.resources.clock.lock(|clock| clock.now_us());
+        ctx.schedule
+            .send_telemetry(ctx.scheduled + millis(TELEMETRY_PERIOD_MS))
+            .ok();
+
+        if ctx
+            .resources
+            .subscriptions
+            .lock(|s| s.tick(Stream::Telemetry))
         {
-            let mut buf = [0i64; XCORR_LEN];
-            let x_angle = folley_calc::calc_angle::<T_S_US, D_MICS_MM, XCORR_LEN, SAMPLE_BUF_SIZE>(
-                &channels.ch1,
-                &channels.ch2,
-                &mut buf,
-                ctx.resources.lag_table,
-            ) as i32;
-            let y_angle = folley_calc::calc_angle::<T_S_US, D_MICS_MM, XCORR_LEN, SAMPLE_BUF_SIZE>(
-                &channels.ch3,
-                &channels.ch4,
-                &mut buf,
-                ctx.resources.lag_table,
-            ) as i32;
-            defmt::info!("x: {}\t\ty: {}", x_angle, y_angle);
-
-            #[cfg(feature = "pan_tilt")]
-            if let Err(_) = ctx.spawn.move_bracket(x_angle - 90, -(y_angle - 90)) {
-                defmt::error!("Could not spawn move_bracket task");
+            use firmware::telemetry::*;
+            use folley_format::device_to_server::Telemetry;
+
+            let (pan_tilt_goal, pan_tilt_actual) = ctx.resources.pan_tilt.lock(|pan_tilt| {
+                let pan_tilt = pan_tilt.as_ref();
+                (pan_tilt.map(|p| p.status()), pan_tilt.map(|p| p.position()))
+            });
+
+            let telemetry = Telemetry {
+                uptime_ms: uptime_us / 1000,
+                spawn_failures: SPAWN_FAILURES.get(),
+                link: ctx.resources.accumulator.lock(|acc| *acc.stats()),
+                tx_busy_waits: TX_BUSY_WAITS.get(),
+                frames_processed: FRAMES_PROCESSED.get(),
+                frames_dropped: FRAMES_DROPPED.get(),
+                pan_tilt_goal,
+                pan_tilt_actual,
+            };
+            defmt::debug!("{}", telemetry);
+            if ctx
+                .spawn
+                .send_message(DeviceToServer::Telemetry(telemetry))
+                .is_err()
+            {
+                SPAWN_FAILURES.increment();
             }
+        }
+    }
+
+    /// Send heartbeats at the interval the host sends its own, and enter the safe state
+    /// when the host goes silent: stop streaming, go idle and park the bracket
+    #[task(
+        priority = 10,
+        resources = [clock, link, subscriptions, mic_array, sampling_enabled, pan_tilt, mode],
+        schedule = [send_heartbeat],
+        spawn = [send_message]
+    )]
+    fn send_heartbeat(mut ctx: send_heartbeat::Context) {
+        static mut LAST_BEAT_MS: u64 = 0;
 
-            if let Err(_) = ctx.spawn.start_sampling() {
-                defmt::error!("Could not spawn start_sampling task");
+        ctx.schedule
+            .send_heartbeat(ctx.scheduled + millis(HEARTBEAT_POLL_MS))
+            .ok();
+        let now_ms = ctx.resources.clock.lock(|clock| clock.now_us()) / 1000;
+        let (interval_ms, event) = ctx
+            .resources
+            .link
+            .lock(|link| (link.interval_ms(), link.check(now_ms)));
+
+        if let Some(LinkEvent::Lost) = event {
+            let silent_ms = interval_ms * MISSED_BEATS;
+            mirror!(
+                warn,
+                LogCode::LinkLost,
+                [silent_ms],
+                "Heard nothing from the host for {} ms, entering safe state",
+                silent_ms
+            );
+            ctx.resources
+                .subscriptions
+                .lock(|s| *s = Subscriptions::none());
+            let mut pan_tilt = ctx.resources.pan_tilt;
+            if enter_mode(
+                Mode::Idle,
+                &mut ctx.resources.mode,
+                &mut ctx.resources.sampling_enabled,
+                &mut ctx.resources.mic_array,
+                &mut pan_tilt,
+            ) {
+                ctx.spawn
+                    .send_message(DeviceToServer::ModeChanged(Mode::Idle))
+                    .ok();
+            }
+            pan_tilt.lock(|p| p.as_mut().map(|p| p.park()));
+        }
+
+        if interval_ms > 0 && now_ms >= *LAST_BEAT_MS + interval_ms as u64 {
+            *LAST_BEAT_MS = now_ms;
+            let heartbeat = DeviceToServer::Heartbeat(Heartbeat { interval_ms });
+            if ctx.spawn.send_message(heartbeat).is_err() {
+                firmware::telemetry::SPAWN_FAILURES.increment();
             }
         }
     }
 
     #[task(priority = 90, resources = [pan_tilt], spawn = [start_sampling])]
-    #[cfg_attr(not(feature = "pan_tilt"), allow(unused_variables, unused_mut))]
-    fn move_bracket(mut ctx: move_bracket::Context, pan_offset: i32, tilt_offset: i32) {
-        #[cfg(feature = "pan_tilt")]
-        {
-            ctx.resources.pan_tilt.lock(|pan_tilt| {
+    fn move_bracket(
+        mut ctx: move_bracket::Context,
+        pan_offset: DegreeOffset,
+        tilt_offset: DegreeOffset,
+    ) {
+        ctx.resources.pan_tilt.lock(|pan_tilt| {
+            if let Some(pan_tilt) = pan_tilt {
                 pan_tilt.pan_with_deg(pan_offset);
                 pan_tilt.tilt_with_deg(tilt_offset);
-            });
-        }
+            }
+        });
     }
 
-    #[task(binds = TIMER1, priority = 254, resources = [pan_tilt, timer1])]
-    #[cfg_attr(not(feature = "pan_tilt"), allow(unused_variables))]
+    #[task(
+        binds = TIMER1,
+        priority = 254,
+        resources = [pan_tilt, timer1, subscriptions, mode],
+        spawn = [send_message]
+    )]
     fn step_pan_tilt(ctx: step_pan_tilt::Context) {
-        #[cfg(feature = "pan_tilt")]
-        {
-            ctx.resources.pan_tilt.step();
-            let timer1 = ctx.resources.timer1;
+        let timer1 = ctx.resources.timer1;
+        if timer1.event_compare_cc0().read().bits() != 0x00u32 {
+            // Clear event flag
+            timer1.event_compare_cc0().write(|w| unsafe { w.bits(0) })
+        }
+        // The timer interrupt is only enabled if there is a bracket
+        let pan_tilt = match ctx.resources.pan_tilt {
+            Some(pan_tilt) => pan_tilt,
+            None => return,
+        };
+        if let Some((id, end)) = pan_tilt.step() {
+            let position = pan_tilt.position();
+            defmt::debug!("Motion of command {} ended: {}", id, end);
+            if ctx
+                .spawn
+                .send_message(DeviceToServer::MotionDone { id, end, position })
+                .is_err()
+            {
+                firmware::telemetry::SPAWN_FAILURES.increment();
+            }
+        }
+        if *ctx.resources.mode == Mode::Scan && pan_tilt.is_at_goal() {
+            let (min, max) = PAN_RANGE;
+            let pan_deg = pan_tilt.position().pan_deg;
+            pan_tilt.pan_to_deg(if pan_deg >= max { min } else { max });
+        }
 
-            if timer1.event_compare_cc0().read().bits() != 0x00u32 {
-                // Clear event flag
-                timer1.event_compare_cc0().write(|w| unsafe { w.bits(0) })
+        let mut subscriptions = ctx.resources.subscriptions;
+        if subscriptions.lock(|s| s.tick(Stream::PanTilt)) {
+            let (goal, actual) = (pan_tilt.status(), pan_tilt.position());
+            if ctx
+                .spawn
+                .send_message(DeviceToServer::PanTilt { goal, actual })
+                .is_err()
+            {
+                firmware::telemetry::SPAWN_FAILURES.increment();
             }
         }
     }
 
-    #[task(priority = 255, resources = [mic_array])]
-    #[cfg_attr(not(feature = "mic_array"), allow(unused_variables))]
+    /// Button 1 switches to the next mode the device supports, button 2 back to idle
+    #[task(
+        binds = GPIOTE,
+        priority = 10,
+        resources = [gpiote, clock, hello, mode, sampling_enabled, mic_array, pan_tilt],
+        spawn = [send_message]
+    )]
+    fn on_button(mut ctx: on_button::Context) {
+        static mut LAST_PRESS_MS: u64 = 0;
+
+        let gpiote = ctx.resources.gpiote;
+        let (next, idle) = (
+            gpiote.channel1().is_event_triggered(),
+            gpiote.channel2().is_event_triggered(),
+        );
+        gpiote.reset_events();
+
+        // Contacts bounce, so presses in quick succession count as one
+        let now_ms = ctx.resources.clock.lock(|clock| clock.now_us()) / 1000;
+        if now_ms < *LAST_PRESS_MS + BUTTON_DEBOUNCE_MS as u64 {
+            return;
+        }
+        *LAST_PRESS_MS = now_ms;
+
+        let current = ctx.resources.mode.lock(|m| *m);
+        let mode = match (next, idle) {
+            (_, true) => Mode::Idle,
+            (true, false) => current.next(ctx.resources.hello.features),
+            (false, false) => return,
+        };
+        if enter_mode(
+            mode,
+            &mut ctx.resources.mode,
+            &mut ctx.resources.sampling_enabled,
+            &mut ctx.resources.mic_array,
+            &mut ctx.resources.pan_tilt,
+        ) {
+            ctx.spawn
+                .send_message(DeviceToServer::ModeChanged(mode))
+                .ok();
+        }
+    }
+
+    #[task(priority = 255, resources = [mic_array, sampling_enabled])]
     fn start_sampling(ctx: start_sampling::Context) {
-        #[cfg(feature = "mic_array")]
-        {
+        // Sampling may have been stopped while the last frame was processed
+        if *ctx.resources.sampling_enabled {
             ctx.resources.mic_array.start_sampling_task();
         }
     }
 
+    /// Send the log messages that were queued for the host
+    #[task(binds = SWI5_EGU5, priority = 1, spawn = [send_message])]
+    fn flush_log(ctx: flush_log::Context) {
+        while let Some(msg) = firmware::log::pop() {
+            if ctx.spawn.send_message(msg).is_err() {
+                // Leave the rest for the next time a message is queued
+                firmware::log::DROPPED.increment();
+                firmware::telemetry::SPAWN_FAILURES.increment();
+                break;
+            }
+        }
+    }
+
+    // SWI5_EGU5 is taken by flush_log
     extern "C" {
         fn SWI0_EGU0();
         fn SWI1_EGU1();
         fn SWI2_EGU2();
         fn SWI3_EGU3();
         fn SWI4_EGU4();
-        fn SWI5_EGU5();
     }
 };
@@ -1,17 +1,22 @@
 use core::{
     marker::PhantomData,
     mem,
-    ops::Deref,
     sync::atomic::{compiler_fence, Ordering},
 };
 
 use embedded_hal::adc::Channel;
 use embedded_hal::timer::Cancel;
-use folley_format::device_to_server::MicArraySample;
+use folley_calc::scan::ScanTiming;
+use folley_format::{
+    device_to_server::{ConfigError, MicArraySample},
+    hello::Acquisition,
+    status::Calibration,
+    units::{AdcReference, AdcScale},
+};
 use nrf52840_hal::{
     pac::SAADC,
     ppi::ConfigurablePpi,
-    saadc::SaadcConfig,
+    saadc::{Gain, Oversample, Reference, Resolution, SaadcConfig, Time},
     timer::{Instance, Periodic},
     Saadc, Timer,
 };
@@ -48,6 +53,155 @@ where
     }
 }
 
+/// Derive the timing of a single SAADC scan from the configuration passed to [MicArray::new].
+/// Mic n is configured as SAADC channel n, and is therefore the nth channel in the scan.
+pub fn scan_timing(config: &SaadcConfig) -> ScanTiming {
+    ScanTiming::new(
+        acq_time_us(config.time),
+        config.oversample as u32,
+        [0, 1, 2, 3],
+    )
+}
+
+fn acq_time_us(time: Time) -> u32 {
+    match time {
+        Time::_3US => 3,
+        Time::_5US => 5,
+        Time::_10US => 10,
+        Time::_15US => 15,
+        Time::_20US => 20,
+        Time::_40US => 40,
+    }
+}
+
+/// Acquisition parameters of a [MicArray] that can be changed at runtime,
+/// see [MicArray::reconfigure].
+pub struct Settings {
+    pub resolution: Resolution,
+    pub oversample: Oversample,
+    pub gain: Gain,
+    pub time: Time,
+    pub sample_period_us: u32,
+    pub frame_len: usize,
+}
+
+impl Settings {
+    pub fn scan_timing(&self) -> ScanTiming {
+        ScanTiming::new(acq_time_us(self.time), self.oversample as u32, [0, 1, 2, 3])
+    }
+}
+
+/// Check whether the microphone array can sample as described by `acquisition`, and
+/// translate it to the settings to do so. The reference can't be changed at runtime.
+pub fn check_acquisition(acquisition: &Acquisition) -> Result<Settings, ConfigError> {
+    use crate::consts::{FRAME_LEN_RANGE, SAMPLE_PERIOD_RANGE_US};
+
+    let (min_us, max_us) = SAMPLE_PERIOD_RANGE_US;
+    if !(min_us..=max_us).contains(&acquisition.sample_period_us) {
+        return Err(ConfigError::SamplePeriod { min_us, max_us });
+    }
+    let (min, max) = FRAME_LEN_RANGE;
+    if !(min..=max).contains(&acquisition.frame_len) {
+        return Err(ConfigError::FrameLen { min, max });
+    }
+    let scale = &acquisition.adc_scale;
+    let gain = match (scale.gain_num, scale.gain_den) {
+        (1, 6) => Gain::GAIN1_6,
+        (1, 5) => Gain::GAIN1_5,
+        (1, 4) => Gain::GAIN1_4,
+        (1, 3) => Gain::GAIN1_3,
+        (1, 2) => Gain::GAIN1_2,
+        (1, 1) => Gain::GAIN1,
+        (2, 1) => Gain::GAIN2,
+        (4, 1) => Gain::GAIN4,
+        _ => return Err(ConfigError::Gain),
+    };
+    let resolution = match scale.resolution_bits {
+        8 => Resolution::_8BIT,
+        10 => Resolution::_10BIT,
+        12 => Resolution::_12BIT,
+        14 => Resolution::_14BIT,
+        _ => return Err(ConfigError::Resolution),
+    };
+    let oversample = match acquisition.oversample_log2 {
+        0 => Oversample::BYPASS,
+        1 => Oversample::OVER2X,
+        2 => Oversample::OVER4X,
+        3 => Oversample::OVER8X,
+        4 => Oversample::OVER16X,
+        5 => Oversample::OVER32X,
+        6 => Oversample::OVER64X,
+        7 => Oversample::OVER128X,
+        8 => Oversample::OVER256X,
+        _ => return Err(ConfigError::Oversample),
+    };
+    let time = match acquisition.acquisition_time_us {
+        3 => Time::_3US,
+        5 => Time::_5US,
+        10 => Time::_10US,
+        15 => Time::_15US,
+        20 => Time::_20US,
+        40 => Time::_40US,
+        _ => return Err(ConfigError::AcquisitionTime),
+    };
+    let settings = Settings {
+        resolution,
+        oversample,
+        gain,
+        time,
+        sample_period_us: acquisition.sample_period_us,
+        frame_len: acquisition.frame_len as usize,
+    };
+    let scan_time_ns = settings.scan_timing().scan_time_ns();
+    if scan_time_ns > acquisition.sample_period_us * 1000 {
+        return Err(ConfigError::ScanTooLong { scan_time_ns });
+    }
+    Ok(settings)
+}
+
+/// The corrections applied to samples acquired as described by `acquisition`, with a
+/// noise profile learned from `noise_frames` frames
+pub fn calibration(
+    acquisition: &Acquisition,
+    noise_frames: u32,
+) -> Result<Calibration, ConfigError> {
+    use crate::consts::{DC_BLOCK_CUTOFF_HZ, SILENCE_MAX_RMS};
+
+    let scan_timing = check_acquisition(acquisition)?.scan_timing();
+    Ok(Calibration {
+        dc_block_cutoff_hz: DC_BLOCK_CUTOFF_HZ,
+        pair_skew_ns: [scan_timing.skew_ns(0, 1), scan_timing.skew_ns(2, 3)],
+        silence_max_rms: SILENCE_MAX_RMS,
+        noise_frames,
+    })
+}
+
+/// Derive the scale to convert samples to volts from the configuration passed to [MicArray::new].
+/// The channels are measured single-ended.
+pub fn adc_scale(config: &SaadcConfig, vdd_mv: u32) -> AdcScale {
+    let reference = match config.reference {
+        Reference::INTERNAL => AdcReference::Internal,
+        Reference::VDD1_4 => AdcReference::Vdd4 { vdd_mv },
+    };
+    let (gain_num, gain_den) = match config.gain {
+        Gain::GAIN1_6 => (1, 6),
+        Gain::GAIN1_5 => (1, 5),
+        Gain::GAIN1_4 => (1, 4),
+        Gain::GAIN1_3 => (1, 3),
+        Gain::GAIN1_2 => (1, 2),
+        Gain::GAIN1 => (1, 1),
+        Gain::GAIN2 => (2, 1),
+        Gain::GAIN4 => (4, 1),
+    };
+    let resolution_bits = match config.resolution {
+        Resolution::_8BIT => 8,
+        Resolution::_10BIT => 10,
+        Resolution::_12BIT => 12,
+        Resolution::_14BIT => 14,
+    };
+    AdcScale::single_ended(reference, gain_num, gain_den, resolution_bits)
+}
+
 pub struct MicArray<M1, M2, M3, M4, T, P>
 where
     M1: Channel<Saadc, ID = u8>,
@@ -60,6 +214,7 @@ where
     buffer: SaadcBuffer,
     timer: T,
     ppi_channel: PhantomData<P>,
+    frame_len: usize,
 }
 
 impl<M1, M2, M3, M4, T, P> MicArray<M1, M2, M3, M4, T, P>
@@ -137,7 +292,22 @@ where
 
         saadc.intenset.write(|w| w.end().set_bit());
         compiler_fence(Ordering::SeqCst);
-        // Calibrate
+        Self::calibrate(&saadc);
+
+        // Only start after all initalization is done.
+        compiler_fence(Ordering::SeqCst);
+
+        Self {
+            saadc,
+            pins: PhantomData,
+            buffer,
+            timer,
+            ppi_channel: PhantomData,
+            frame_len: SampleBuffer::size(),
+        }
+    }
+
+    fn calibrate(saadc: &SAADC) {
         saadc.events_calibratedone.reset();
         saadc
             .tasks_calibrateoffset
@@ -148,26 +318,67 @@ where
             .events_calibratedone()
             .bit_is_clear()
         {}
+    }
 
-        // Only start after all initalization is done.
+    /// Change the acquisition parameters to settings obtained from [check_acquisition].
+    /// Stops sampling, dropping the current frame, and recalibrates the SAADC.
+    /// Call [MicArray::start_sampling_task] to resume.
+    pub fn reconfigure(&mut self, settings: &Settings) {
+        self.stop_sampling_task();
+        self.saadc.events_stopped.reset();
+        self.saadc.tasks_stop.write(|w| w.tasks_stop().set_bit());
+        while self
+            .saadc
+            .events_stopped
+            .read()
+            .events_stopped()
+            .bit_is_clear()
+        {}
+        self.saadc.events_end.reset();
+
+        self.saadc
+            .resolution
+            .write(|w| w.val().variant(settings.resolution));
+        self.saadc
+            .oversample
+            .write(|w| w.oversample().variant(settings.oversample));
+        self.saadc.ch.iter().take(4).for_each(|ch| {
+            ch.config.modify(|_, w| {
+                w.gain().variant(settings.gain);
+                w.tacq().variant(settings.time);
+                w
+            })
+        });
+
+        assert!(settings.frame_len <= SampleBuffer::size());
+        self.frame_len = settings.frame_len;
+        self.saadc.result.maxcnt.write(|w| unsafe {
+            w.bits((mem::size_of::<MicArraySample>() / 2 * self.frame_len) as u32)
+        });
+
+        // The timer runs at 1 MHz, and is cleared on reaching the compare value
+        let timer = self.timer.as_timer0();
+        timer.tasks_clear.write(|w| w.tasks_clear().set_bit());
+        timer.cc[0].write(|w| unsafe { w.bits(settings.sample_period_us) });
+
+        compiler_fence(Ordering::SeqCst);
+        Self::calibrate(&self.saadc);
         compiler_fence(Ordering::SeqCst);
+    }
 
-        Self {
-            saadc,
-            pins: PhantomData,
-            buffer,
-            timer,
-            ppi_channel: PhantomData,
-        }
+    /// Time between two samples, as set on the timer triggering them
+    pub fn sample_period_us(&self) -> u32 {
+        self.timer.as_timer0().cc[0].read().bits()
     }
 
-    pub fn get_newest_samples(&mut self) -> &mut <SampleBuffer as Deref>::Target {
+    /// Get the frame that was sampled last, which holds `frame_len` samples
+    pub fn get_newest_samples(&mut self) -> &mut [MicArraySample] {
         self.buffer.swap();
         self.saadc
             .result
             .ptr
             .write(|w| unsafe { w.bits(self.buffer.write_buf().as_ptr() as u32) });
-        &mut self.buffer.read_buf().0
+        &mut self.buffer.read_buf().0[..self.frame_len]
     }
 
     pub fn start_sampling_task(&mut self) {
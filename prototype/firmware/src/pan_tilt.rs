@@ -1,111 +1,249 @@
-use folley_format::device_to_server::PanTiltStatus;
+use folley_calc::motion::Axis;
+use folley_format::{
+    device_to_server::{CommandError, PanTiltStatus},
+    motion::{facing_offsets, AxisLimits, Motion, MotionEnd, MotionLimits},
+    server_to_device::RequestId,
+    units::{DegreeOffset, Degrees},
+};
 use nrf52840_hal::{
-    twim::{Frequency, Instance, Pins},
+    twim::{self, Frequency, Instance, Pins},
     Twim,
 };
 
-use pwm_pca9685::{Address, Channel, Pca9685};
+use pwm_pca9685::{Address, Channel, Error, Pca9685};
+
+use crate::consts::PAN_TILT_STEP_MS;
 
 pub struct PanTilt<TWIM> {
     pwm: Pca9685<TWIM>,
-    pan_deg_goal: i32,
-    tilt_deg_goal: i32,
-    tilt_deg: i32,
-    pan_deg: i32,
+    pan: Axis,
+    tilt: Axis,
+    /// Motion the host wants to hear the end of
+    motion: Option<PendingMotion>,
 }
 
-const TILT_LIMIT_DEG: i32 = 90;
+#[derive(Clone, Copy)]
+struct PendingMotion {
+    id: RequestId,
+    goal: PanTiltStatus,
+}
+
+const TILT_LIMIT_DEG: Degrees = Degrees(90);
 const TILT_0_DEG: u16 = 760;
 const TILT_180_DEG: u16 = 2900;
 
-fn tilt_deg_to_off_val(degrees: i32) -> u16 {
-    (TILT_180_DEG - TILT_0_DEG) / 180 * (degrees.min(180) as u16) + TILT_0_DEG
+fn tilt_deg_to_off_val(degrees: Degrees) -> u16 {
+    (TILT_180_DEG - TILT_0_DEG) / 180 * (degrees.0.clamp(0, 180) as u16) + TILT_0_DEG
 }
 
-const PAN_LIMIT_DEG: i32 = 180;
+const PAN_LIMIT_DEG: Degrees = Degrees(180);
 const PAN_0_DEG: u16 = 760;
 const PAN_360_DEG: u16 = 2930;
 
-fn pan_deg_to_off_val(degrees: i32) -> u16 {
-    (PAN_360_DEG - PAN_0_DEG) / 180 * (degrees.min(180) as u16) + PAN_0_DEG
+fn pan_deg_to_off_val(degrees: Degrees) -> u16 {
+    (PAN_360_DEG - PAN_0_DEG) / 180 * (degrees.0.clamp(0, 180) as u16) + PAN_0_DEG
+}
+
+/// Range of angles the bracket can pan to
+pub const PAN_RANGE: (Degrees, Degrees) = (Degrees::ZERO, PAN_LIMIT_DEG);
+/// Range of angles the bracket can tilt to
+pub const TILT_RANGE: (Degrees, Degrees) = (Degrees::ZERO, TILT_LIMIT_DEG);
+
+/// Position the bracket returns to when the device enters its safe state
+pub const PARK_POSITION: PanTiltStatus = PanTiltStatus {
+    pan_deg: Degrees::ZERO,
+    tilt_deg: Degrees::ZERO,
+};
+
+/// Limits both axes start out with: a degree per step, from standstill to full speed at once
+pub const DEFAULT_LIMITS: AxisLimits = AxisLimits {
+    max_speed_deg_s: 1000 / PAN_TILT_STEP_MS,
+    acceleration_deg_s2: 0,
+};
+/// Maximum speeds in degrees per second the host can pick
+pub const MAX_SPEED_RANGE_DEG_S: (u32, u32) = (1, 250);
+
+/// Check whether the maximum speeds requested by the host lie within [MAX_SPEED_RANGE_DEG_S]
+pub fn check_limits(limits: &MotionLimits) -> Result<(), CommandError> {
+    let (min_deg_s, max_deg_s) = MAX_SPEED_RANGE_DEG_S;
+    let in_range = |axis: Option<AxisLimits>| {
+        axis.map_or(true, |a| {
+            (min_deg_s..=max_deg_s).contains(&a.max_speed_deg_s)
+        })
+    };
+    if in_range(limits.pan) && in_range(limits.tilt) {
+        Ok(())
+    } else {
+        Err(CommandError::MaxSpeed {
+            min_deg_s,
+            max_deg_s,
+        })
+    }
+}
+
+/// Check whether an angle requested by the host lies within `range`
+pub fn check_range(degrees: Degrees, (min, max): (Degrees, Degrees)) -> Result<(), CommandError> {
+    if degrees < min || degrees > max {
+        Err(CommandError::AngleOutOfRange { min, max })
+    } else {
+        Ok(())
+    }
 }
 
 impl<T: Instance> PanTilt<Twim<T>> {
-    pub fn new(twim: T, pins: Pins, pan_deg: i32, tilt_deg: i32) -> Self {
+    /// Fails if the PWM controller of the bracket doesn't answer
+    pub fn new(
+        twim: T,
+        pins: Pins,
+        pan_deg: Degrees,
+        tilt_deg: Degrees,
+    ) -> Result<Self, Error<twim::Error>> {
         let twim0 = Twim::new(twim, pins, Frequency::K400);
 
-        let mut pwm =
-            Pca9685::new(twim0, Address::default()).expect("Error initializing PWM controller");
-        pwm.enable().unwrap();
-        pwm.set_prescale(20).unwrap();
-        pwm.set_channel_on(Channel::C14, 0).unwrap();
-        pwm.set_channel_off(Channel::C14, 0).unwrap();
-        pwm.set_channel_on(Channel::C15, 0).unwrap();
-        pwm.set_channel_off(Channel::C15, 0).unwrap();
+        let mut pwm = Pca9685::new(twim0, Address::default())?;
+        pwm.enable()?;
+        pwm.set_prescale(20)?;
+        pwm.set_channel_on(Channel::C14, 0)?;
+        pwm.set_channel_off(Channel::C14, 0)?;
+        pwm.set_channel_on(Channel::C15, 0)?;
+        pwm.set_channel_off(Channel::C15, 0)?;
 
         let mut pan_tilt = Self {
             pwm,
-            pan_deg_goal: pan_deg,
-            tilt_deg_goal: tilt_deg,
-            pan_deg: 0,
-            tilt_deg: 0,
+            pan: Axis::new(DEFAULT_LIMITS),
+            tilt: Axis::new(DEFAULT_LIMITS),
+            motion: None,
         };
 
         pan_tilt.pan_to_deg(pan_deg);
         pan_tilt.tilt_to_deg(tilt_deg);
 
-        pan_tilt
+        Ok(pan_tilt)
     }
 
     pub fn status(&self) -> PanTiltStatus {
         PanTiltStatus {
-            pan_deg: self.pan_deg_goal,
-            tilt_deg: self.tilt_deg_goal,
+            pan_deg: self.pan.goal,
+            tilt_deg: self.tilt.goal,
         }
     }
 
-    pub fn tilt_to_deg(&mut self, degrees: i32) {
+    /// Position the bracket is at right now, on its way to the goal
+    pub fn position(&self) -> PanTiltStatus {
+        PanTiltStatus {
+            pan_deg: self.pan.position(),
+            tilt_deg: self.tilt.position(),
+        }
+    }
+
+    pub fn tilt_to_deg(&mut self, degrees: Degrees) {
         let degrees = degrees.min(TILT_LIMIT_DEG);
-        self.tilt_deg_goal = degrees;
-        defmt::trace!("Tilt goal: {} degrees", degrees);   
+        self.tilt.goal = degrees;
+        defmt::trace!("Tilt goal: {} degrees", degrees);
     }
 
-    pub fn pan_to_deg(&mut self, degrees: i32) {
+    pub fn pan_to_deg(&mut self, degrees: Degrees) {
         let degrees = degrees.min(PAN_LIMIT_DEG);
-        self.pan_deg_goal =degrees;
+        self.pan.goal = degrees;
         defmt::trace!("Pan goal: {} degrees", degrees);
-        
     }
 
-    pub fn tilt_with_deg(&mut self, degrees: i32) {
-        let degrees = (degrees + self.tilt_deg as i32).max(0);
-        self.tilt_to_deg(degrees as i32);
+    /// Head back to [PARK_POSITION]
+    pub fn park(&mut self) {
+        self.pan_to_deg(PARK_POSITION.pan_deg);
+        self.tilt_to_deg(PARK_POSITION.tilt_deg);
+    }
+
+    /// Stop where the bracket is right now, without slowing down first
+    pub fn hold(&mut self) {
+        self.pan.hold();
+        self.tilt.hold();
+    }
+
+    /// Whether the bracket reached its goal and came to a halt
+    pub fn is_at_goal(&self) -> bool {
+        self.pan.is_at_goal() && self.tilt.is_at_goal()
+    }
+
+    pub fn tilt_with_deg(&mut self, offset: DegreeOffset) {
+        let degrees = (self.tilt.position() + offset).max(Degrees::ZERO);
+        self.tilt_to_deg(degrees);
     }
 
-    pub fn pan_with_deg(&mut self, degrees: i32) {
-        let degrees = (degrees + self.pan_deg as i32).max(0);
-        self.pan_to_deg(degrees as i32);
+    pub fn pan_with_deg(&mut self, offset: DegreeOffset) {
+        let degrees = (self.pan.position() + offset).max(Degrees::ZERO);
+        self.pan_to_deg(degrees);
     }
 
-    pub fn step(&mut self) {
-        fn next_angle(current: i32, goal: i32) -> i32 {
-            use core::cmp::Ordering::*;
-            match current.cmp(&goal) {
-                Greater => current -1,
-                Less => current + 1,
-                Equal => current,
+    /// Where `motion` would send the bracket if it were heading for `heading`
+    pub fn goal_of(&self, motion: Motion, heading: PanTiltStatus) -> PanTiltStatus {
+        let offset =
+            |from: PanTiltStatus, (pan, tilt): (DegreeOffset, DegreeOffset)| PanTiltStatus {
+                pan_deg: from.pan_deg + pan,
+                tilt_deg: from.tilt_deg + tilt,
+            };
+        match motion {
+            Motion::By { pan, tilt } => offset(heading, (pan, tilt)),
+            Motion::Home => PARK_POSITION,
+            Motion::Stop => self.position(),
+            Motion::LookAt { azimuth, elevation } => {
+                offset(self.position(), facing_offsets(azimuth, elevation))
             }
         }
+    }
+
+    pub fn limits(&self) -> MotionLimits {
+        MotionLimits {
+            pan: Some(self.pan.limits),
+            tilt: Some(self.tilt.limits),
+        }
+    }
+
+    pub fn set_limits(&mut self, limits: &MotionLimits) {
+        self.pan.limits = limits.pan.unwrap_or(self.pan.limits);
+        self.tilt.limits = limits.tilt.unwrap_or(self.tilt.limits);
+    }
+
+    /// Report the end of the way to the current goal as the end of the motion of command
+    /// `id`. Returns the command whose motion this interrupts, if any.
+    pub fn track_motion(&mut self, id: RequestId) -> Option<RequestId> {
+        let goal = self.status();
+        self.motion
+            .replace(PendingMotion { id, goal })
+            .map(|m| m.id)
+    }
 
-        let tilt_deg = next_angle(self.tilt_deg, self.tilt_deg_goal);
-        let pan_deg = next_angle(self.pan_deg, self.pan_deg_goal);
+    /// Move both axes for one step. Returns the command whose motion ended, if any.
+    pub fn step(&mut self) -> Option<(RequestId, MotionEnd)> {
+        self.pan.step(PAN_TILT_STEP_MS);
+        self.tilt.step(PAN_TILT_STEP_MS);
+        let PanTiltStatus { pan_deg, tilt_deg } = self.position();
 
         let tilt_val = tilt_deg_to_off_val(tilt_deg);
-        self.pwm.set_channel_off(Channel::C14, tilt_val as u16).unwrap();
+        self.pwm
+            .set_channel_off(Channel::C14, tilt_val as u16)
+            .unwrap();
         let pan_val = pan_deg_to_off_val(pan_deg);
-        self.pwm.set_channel_off(Channel::C15, pan_val as u16).unwrap();
-        self.tilt_deg = tilt_deg;
-        self.pan_deg = pan_deg;
-        defmt::debug!("Pan: {} ({}), tilt: {} ({})", pan_deg, self.pan_deg_goal, tilt_deg,  self.tilt_deg_goal);
+        self.pwm
+            .set_channel_off(Channel::C15, pan_val as u16)
+            .unwrap();
+        defmt::debug!(
+            "Pan: {} ({}), tilt: {} ({})",
+            pan_deg,
+            self.pan.goal,
+            tilt_deg,
+            self.tilt.goal
+        );
+
+        let motion = self.motion?;
+        let end = if self.status() != motion.goal {
+            MotionEnd::Interrupted
+        } else if self.is_at_goal() {
+            MotionEnd::Reached
+        } else {
+            return None;
+        };
+        self.motion = None;
+        Some((motion.id, end))
     }
 }
@@ -0,0 +1,36 @@
+//! Counters of events the host can't see otherwise, reported in
+//! [Telemetry](folley_format::device_to_server::Telemetry)
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Event counter that tasks of any priority can increment without locking
+pub struct Counter(AtomicU32);
+
+impl Counter {
+    pub const fn new() -> Self {
+        Self(AtomicU32::new(0))
+    }
+
+    pub fn increment(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u32 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for Counter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Tasks that could not be spawned because their queue was full
+pub static SPAWN_FAILURES: Counter = Counter::new();
+/// Times a message had to wait for the previous one to be sent
+pub static TX_BUSY_WAITS: Counter = Counter::new();
+/// Frames of samples that were processed
+pub static FRAMES_PROCESSED: Counter = Counter::new();
+/// Frames of samples that were thrown away because processing fell behind
+pub static FRAMES_DROPPED: Counter = Counter::new();
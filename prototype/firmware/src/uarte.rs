@@ -1,6 +1,6 @@
 use core::marker::PhantomData;
 
-use folley_format::DeviceToServer;
+use folley_format::{framing::FrameEncoder, DeviceToServer};
 use nrf52840_hal as hal;
 
 pub use hal::uarte::{Baudrate, Instance as UarteInstance, Parity, Pins, Uarte as HalUarte};
@@ -33,6 +33,7 @@ pub struct Uarte<U, T, P> {
     endtx_raised: bool,
     timer: PhantomData<T>,
     ppi_channel: PhantomData<P>,
+    encoder: FrameEncoder,
     tx_buf: [u8; 10000],
 }
 
@@ -91,6 +92,7 @@ where
             endtx_raised: false,
             timer: PhantomData,
             ppi_channel: PhantomData,
+            encoder: FrameEncoder::new(),
             tx_buf: [0; 10000],
         }
     }
@@ -114,7 +116,7 @@ where
         }
         core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::AcqRel);
 
-        match postcard::to_slice_cobs(&msg, &mut self.tx_buf) {
+        match self.encoder.encode(msg, &mut self.tx_buf) {
             Ok(bytes) => {
                 defmt::trace!("TX contents: {:?}. chunk_len: {}", bytes, bytes.len());
 
@@ -0,0 +1,309 @@
+//! Chunks of samples the device sends as it samples the microphone array.
+//!
+//! Every chunk carries the index of its first sample, counting all samples acquired since
+//! boot, and the time at which that sample was taken. Consecutive chunks continue where the
+//! previous one ended, so the host can reassemble a continuous timeline with [Timeline] and
+//! tell dropped chunks apart from pauses in sampling.
+
+#[cfg(feature = "defmt")]
+use defmt::Format;
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    compression::{CompressedSamples, DecodeError, SampleEncoding, MAX_FRAME_LEN},
+    device_to_server::MicArraySample,
+};
+
+/// Upper bound of the amount of values in a [ChunkPayload::Raw]
+pub const MAX_RAW_LEN: usize = MAX_FRAME_LEN * 4;
+
+/// Set of microphone channels, one bit per channel
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub struct ChannelMask(pub u8);
+
+impl ChannelMask {
+    pub const ALL: Self = Self(0b1111);
+    pub const NONE: Self = Self(0);
+
+    pub const fn single(ch: usize) -> Self {
+        Self(1 << ch)
+    }
+
+    pub const fn with(self, ch: usize) -> Self {
+        Self(self.0 | 1 << ch)
+    }
+
+    pub fn contains(self, ch: usize) -> bool {
+        ch < 4 && self.0 & (1 << ch) != 0
+    }
+
+    /// Amount of channels in the set
+    pub fn count(self) -> usize {
+        (self.0 & Self::ALL.0).count_ones() as usize
+    }
+
+    /// The channels in the set, in ascending order
+    pub fn iter(self) -> impl Iterator<Item = usize> {
+        (0..4).filter(move |&ch| self.contains(ch))
+    }
+}
+
+impl Default for ChannelMask {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+// Both variants are about as large, there's just no allocator to box them with
+#[allow(clippy::large_enum_variant)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ChunkPayload {
+    /// The samples of the channels in the mask, interleaved
+    Raw(Vec<i16, MAX_RAW_LEN>),
+    /// The channels in the mask, compressed
+    Rice(CompressedSamples),
+}
+
+/// A run of consecutive samples of the channels in `channels`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SampleChunk {
+    /// Index of the first sample in the chunk, counting all samples acquired since boot
+    pub start_index: u64,
+    /// Time at which the first sample in the chunk was taken, in microseconds since boot
+    pub timestamp_us: u64,
+    pub channels: ChannelMask,
+    pub payload: ChunkPayload,
+}
+
+#[cfg(feature = "defmt")]
+impl Format for SampleChunk {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "SampleChunk {{ start_index: {}, timestamp_us: {}, channels: {}, len: {} }}",
+            self.start_index,
+            self.timestamp_us,
+            self.channels,
+            self.len()
+        )
+    }
+}
+
+impl SampleChunk {
+    /// Put the given channels of at most [MAX_FRAME_LEN] samples in a chunk
+    pub fn encode(
+        start_index: u64,
+        timestamp_us: u64,
+        channels: ChannelMask,
+        samples: &[MicArraySample],
+        encoding: SampleEncoding,
+    ) -> Self {
+        assert!(samples.len() <= MAX_FRAME_LEN);
+        let payload = match encoding {
+            SampleEncoding::Raw => ChunkPayload::Raw(
+                samples
+                    .iter()
+                    .flat_map(|s| channels.iter().map(move |ch| s[ch]))
+                    .collect(),
+            ),
+            SampleEncoding::Rice => {
+                ChunkPayload::Rice(CompressedSamples::encode(samples, channels))
+            }
+        };
+        Self {
+            start_index,
+            timestamp_us,
+            channels,
+            payload,
+        }
+    }
+
+    /// Amount of samples in the chunk
+    pub fn len(&self) -> usize {
+        match &self.payload {
+            ChunkPayload::Raw(data) => match self.channels.count() {
+                0 => 0,
+                n => data.len() / n,
+            },
+            ChunkPayload::Rice(compressed) => compressed.len as usize,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Index of the sample following the last one in the chunk
+    pub fn end_index(&self) -> u64 {
+        self.start_index + self.len() as u64
+    }
+
+    /// Write the samples into `out`, returning the amount of samples written. Channels
+    /// that are not in the chunk are set to zero.
+    pub fn decode(&self, out: &mut [MicArraySample]) -> Result<usize, DecodeError> {
+        let len = self.len();
+        let out = out.get_mut(..len).ok_or(DecodeError::TooLong)?;
+        out.iter_mut().for_each(|s| *s = [0; 4]);
+        match &self.payload {
+            ChunkPayload::Raw(data) => {
+                let mut data = data.iter();
+                out.iter_mut().for_each(|s| {
+                    self.channels.iter().for_each(|ch| {
+                        // The length of the chunk is derived from the length of the data
+                        s[ch] = *data.next().unwrap();
+                    })
+                });
+                Ok(len)
+            }
+            ChunkPayload::Rice(compressed) => compressed.decode(out, self.channels),
+        }
+    }
+}
+
+/// How a chunk relates to the one received before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub enum Gap {
+    /// The chunk directly follows the previous one, or is the first one
+    None,
+    /// The chunk follows the previous one, but sampling was paused in between
+    Paused { us: u64 },
+    /// Chunks holding this many samples went missing
+    Dropped { samples: u64 },
+    /// The sample index went back, so the device restarted
+    Restarted,
+}
+
+/// Follows the chunks the device sends, to find out where the timeline has gaps
+#[derive(Debug, Default, Clone)]
+pub struct Timeline {
+    /// Index and expected timestamp of the sample following the last chunk
+    next: Option<(u64, u64)>,
+    /// Total amount of samples in chunks that went missing
+    pub dropped: u64,
+}
+
+impl Timeline {
+    pub const fn new() -> Self {
+        Self {
+            next: None,
+            dropped: 0,
+        }
+    }
+
+    /// Add the next chunk that was received. `sample_period_us` is the sample period
+    /// the chunk was acquired with.
+    pub fn push(&mut self, chunk: &SampleChunk, sample_period_us: u32) -> Gap {
+        let gap = match self.next {
+            None => Gap::None,
+            Some((index, _)) if chunk.start_index < index => Gap::Restarted,
+            Some((index, _)) if chunk.start_index > index => {
+                let samples = chunk.start_index - index;
+                self.dropped += samples;
+                Gap::Dropped { samples }
+            }
+            // Allow for a sample period of jitter before calling it a pause
+            Some((_, timestamp_us))
+                if chunk.timestamp_us > timestamp_us + sample_period_us as u64 =>
+            {
+                Gap::Paused {
+                    us: chunk.timestamp_us - timestamp_us,
+                }
+            }
+            Some(_) => Gap::None,
+        };
+        let duration_us = chunk.len() as u64 * sample_period_us as u64;
+        self.next = Some((chunk.end_index(), chunk.timestamp_us + duration_us));
+        gap
+    }
+
+    /// Index of the sample the next chunk is expected to start with
+    pub fn next_index(&self) -> Option<u64> {
+        self.next.map(|(index, _)| index)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::chunk::*;
+
+    fn ramp() -> [MicArraySample; 300] {
+        let mut samples = [[0i16; 4]; 300];
+        samples.iter_mut().enumerate().for_each(|(n, s)| {
+            *s = [n as i16, -(n as i16), 2 * n as i16, 4095 - n as i16];
+        });
+        samples
+    }
+
+    #[test]
+    fn test_channel_mask() {
+        assert_eq!(ChannelMask::ALL.count(), 4);
+        assert_eq!(ChannelMask::NONE.count(), 0);
+        let mask = ChannelMask::single(0).with(2);
+        assert!(mask.contains(0) && mask.contains(2));
+        assert!(!mask.contains(1) && !mask.contains(3) && !mask.contains(7));
+        let mut channels = mask.iter();
+        assert_eq!((channels.next(), channels.next()), (Some(0), Some(2)));
+        assert_eq!(channels.next(), None);
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let samples = ramp();
+        for encoding in [SampleEncoding::Raw, SampleEncoding::Rice] {
+            let chunk = SampleChunk::encode(1000, 5, ChannelMask::ALL, &samples, encoding);
+            assert_eq!((chunk.len(), chunk.end_index()), (300, 1300));
+            let mut out = [[1i16; 4]; MAX_FRAME_LEN];
+            assert_eq!(chunk.decode(&mut out), Ok(300));
+            assert_eq!(&out[..300], &samples[..]);
+
+            // Channels left out of the chunk come out as zeros
+            let mask = ChannelMask::single(1).with(3);
+            let chunk = SampleChunk::encode(0, 0, mask, &samples[..50], encoding);
+            assert_eq!(chunk.len(), 50);
+            assert_eq!(chunk.decode(&mut out), Ok(50));
+            out[..50].iter().zip(&samples[..]).for_each(|(out, s)| {
+                assert_eq!(*out, [0, s[1], 0, s[3]]);
+            });
+
+            let mut short = [[0i16; 4]; 10];
+            assert_eq!(chunk.decode(&mut short), Err(DecodeError::TooLong));
+        }
+
+        let empty = SampleChunk::encode(7, 0, ChannelMask::NONE, &samples, SampleEncoding::Raw);
+        assert!(empty.is_empty());
+        assert_eq!(empty.end_index(), 7);
+    }
+
+    #[test]
+    fn test_timeline() {
+        let samples = ramp();
+        let chunk = |start_index, timestamp_us, len| {
+            SampleChunk::encode(
+                start_index,
+                timestamp_us,
+                ChannelMask::ALL,
+                &samples[..len],
+                SampleEncoding::Raw,
+            )
+        };
+        let mut timeline = Timeline::new();
+        assert_eq!(timeline.push(&chunk(0, 100, 100), 20), Gap::None);
+        assert_eq!(timeline.next_index(), Some(100));
+        // Some jitter in the timestamp is fine
+        assert_eq!(timeline.push(&chunk(100, 2110, 100), 20), Gap::None);
+        assert_eq!(
+            timeline.push(&chunk(300, 8100, 50), 20),
+            Gap::Dropped { samples: 100 }
+        );
+        assert_eq!(
+            timeline.push(&chunk(350, 20000, 50), 20),
+            Gap::Paused { us: 10900 }
+        );
+        assert_eq!(timeline.push(&chunk(0, 50, 50), 20), Gap::Restarted);
+        assert_eq!(timeline.push(&chunk(50, 1050, 50), 20), Gap::None);
+        assert_eq!(timeline.dropped, 100);
+    }
+}
@@ -0,0 +1,358 @@
+//! Lossless compression of frames of samples.
+//!
+//! Each channel is predicted from its previous sample, and the prediction residuals are
+//! zigzag mapped and Rice coded in blocks of [BLOCK_LEN] samples. Every block starts with
+//! the 4 bit Rice parameter `k` that yields the shortest code for that block. A residual `u`
+//! is coded as `u >> k` in unary (ones terminated by a zero) followed by the `k` lowest bits
+//! of `u`. Quotients of [ESCAPE] or more are coded as [ESCAPE] ones followed by all 16 bits
+//! of `u`. The channels in the [ChannelMask] of the chunk are coded one after another,
+//! most significant bit first.
+
+#[cfg(feature = "defmt")]
+use defmt::Format;
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+use crate::{chunk::ChannelMask, device_to_server::MicArraySample};
+
+/// Maximum amount of samples in a frame
+pub const MAX_FRAME_LEN: usize = 1024;
+/// Amount of samples of a channel sharing a Rice parameter
+pub const BLOCK_LEN: usize = 64;
+/// Unary quotients this long are followed by the raw residual
+pub const ESCAPE: u32 = 16;
+/// Bits taken up by the Rice parameter of a block
+const K_BITS: u32 = 4;
+/// Largest Rice parameter. Coding a block with it never takes more than 17 bits per sample.
+const MAX_K: u32 = (1 << K_BITS) - 1;
+
+/// Upper bound of the size of a compressed frame of [MAX_FRAME_LEN] samples
+pub const MAX_COMPRESSED_LEN: usize = {
+    let blocks = 4 * ((MAX_FRAME_LEN + BLOCK_LEN - 1) / BLOCK_LEN);
+    let block_bits = K_BITS as usize + (MAX_K as usize + 2) * BLOCK_LEN;
+    (blocks * block_bits + 7) / 8
+};
+
+/// The way the device sends the samples in a [crate::chunk::SampleChunk]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub enum SampleEncoding {
+    /// Uncompressed, see [crate::chunk::ChunkPayload::Raw]
+    Raw,
+    /// Compressed, see [crate::chunk::ChunkPayload::Rice]
+    Rice,
+}
+
+impl Default for SampleEncoding {
+    fn default() -> Self {
+        Self::Raw
+    }
+}
+
+/// The reason a compressed frame could not be decoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub enum DecodeError {
+    /// The data ended before all samples were decoded
+    Truncated,
+    /// The frame holds more samples than fit in the output
+    TooLong,
+}
+
+/// A frame of samples, compressed as described in the [module documentation](self)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CompressedSamples {
+    /// Amount of samples in the frame
+    pub len: u16,
+    pub data: Vec<u8, MAX_COMPRESSED_LEN>,
+}
+
+#[cfg(feature = "defmt")]
+impl Format for CompressedSamples {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "CompressedSamples {{ len: {}, data: {} bytes }}",
+            self.len,
+            self.data.len()
+        )
+    }
+}
+
+impl CompressedSamples {
+    /// Compress the given channels of a frame of at most [MAX_FRAME_LEN] samples
+    pub fn encode(samples: &[MicArraySample], channels: ChannelMask) -> Self {
+        assert!(samples.len() <= MAX_FRAME_LEN);
+        let mut writer = BitWriter::new();
+        let mut residuals = [0u16; BLOCK_LEN];
+
+        channels.iter().for_each(|ch| {
+            let mut prev = 0i16;
+            samples.chunks(BLOCK_LEN).for_each(|block| {
+                let residuals = &mut residuals[..block.len()];
+                residuals.iter_mut().zip(block).for_each(|(r, s)| {
+                    *r = zigzag(s[ch].wrapping_sub(prev));
+                    prev = s[ch];
+                });
+                let k = best_k(residuals);
+                writer.write(k, K_BITS);
+                residuals.iter().for_each(|&u| writer.write_rice(u, k));
+            });
+        });
+
+        Self {
+            len: samples.len() as u16,
+            data: writer.finish(),
+        }
+    }
+
+    /// Decompress the frame into `out`, returning the amount of samples written.
+    /// `channels` needs to be the mask the frame was compressed with; other channels are
+    /// left alone.
+    pub fn decode(
+        &self,
+        out: &mut [MicArraySample],
+        channels: ChannelMask,
+    ) -> Result<usize, DecodeError> {
+        let len = self.len as usize;
+        let out = out.get_mut(..len).ok_or(DecodeError::TooLong)?;
+        let mut reader = BitReader::new(&self.data);
+
+        for ch in channels.iter() {
+            let mut prev = 0i16;
+            for block in out.chunks_mut(BLOCK_LEN) {
+                let k = reader.read(K_BITS)?;
+                for s in block {
+                    s[ch] = prev.wrapping_add(unzigzag(reader.read_rice(k)?));
+                    prev = s[ch];
+                }
+            }
+        }
+        Ok(len)
+    }
+}
+
+fn zigzag(v: i16) -> u16 {
+    ((v << 1) ^ (v >> 15)) as u16
+}
+
+fn unzigzag(u: u16) -> i16 {
+    (u >> 1) as i16 ^ -((u & 1) as i16)
+}
+
+/// Bits taken up by the Rice code of `u`
+fn rice_len(u: u16, k: u32) -> u32 {
+    let q = u as u32 >> k;
+    if q < ESCAPE {
+        q + 1 + k
+    } else {
+        ESCAPE + 16
+    }
+}
+
+/// Pick the Rice parameter that codes `residuals` in the fewest bits. Only the parameters
+/// around the one suggested by the mean residual are tried, along with [MAX_K] to bound
+/// the size of the block.
+fn best_k(residuals: &[u16]) -> u32 {
+    let cost = |k: u32| residuals.iter().map(|&u| rice_len(u, k)).sum::<u32>();
+    let sum: u32 = residuals.iter().map(|&u| u as u32).sum();
+    let mean = sum / residuals.len().max(1) as u32;
+    let guess = (32 - mean.leading_zeros()).min(MAX_K);
+
+    let (below, above) = (guess.saturating_sub(1), (guess + 1).min(MAX_K));
+    [below, guess, above, MAX_K]
+        .iter()
+        .copied()
+        .min_by_key(|&k| cost(k))
+        .unwrap_or(MAX_K)
+}
+
+struct BitWriter {
+    data: Vec<u8, MAX_COMPRESSED_LEN>,
+    acc: u32,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        Self {
+            data: Vec::new(),
+            acc: 0,
+            bits: 0,
+        }
+    }
+
+    /// Write the `n` lowest bits of `value`, with `n` at most 16
+    fn write(&mut self, value: u32, n: u32) {
+        self.acc = (self.acc << n) | (value & ((1 << n) - 1));
+        self.bits += n;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            // The size of the data is bounded by MAX_COMPRESSED_LEN
+            self.data.push((self.acc >> self.bits) as u8).unwrap();
+        }
+    }
+
+    fn write_rice(&mut self, u: u16, k: u32) {
+        let q = u as u32 >> k;
+        if q < ESCAPE {
+            self.write((1 << q) - 1, q);
+            self.write(0, 1);
+            self.write(u as u32, k);
+        } else {
+            self.write((1 << ESCAPE) - 1, ESCAPE);
+            self.write(u as u32, 16);
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8, MAX_COMPRESSED_LEN> {
+        if self.bits > 0 {
+            self.write(0, 8 - self.bits);
+        }
+        self.data
+    }
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn read_bit(&mut self) -> Result<u32, DecodeError> {
+        let byte = self.data.get(self.pos / 8).ok_or(DecodeError::Truncated)?;
+        let bit = (byte >> (7 - self.pos % 8)) & 1;
+        self.pos += 1;
+        Ok(bit as u32)
+    }
+
+    fn read(&mut self, n: u32) -> Result<u32, DecodeError> {
+        (0..n).try_fold(0, |value, _| Ok((value << 1) | self.read_bit()?))
+    }
+
+    fn read_rice(&mut self, k: u32) -> Result<u16, DecodeError> {
+        let mut q = 0;
+        while q < ESCAPE && self.read_bit()? == 1 {
+            q += 1;
+        }
+        if q == ESCAPE {
+            return Ok(self.read(16)? as u16);
+        }
+        Ok(((q << k) | self.read(k)?) as u16)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::compression::*;
+
+    /// 12 bit samples of a triangle wave with some noise, like the SAADC produces
+    fn tone() -> [MicArraySample; MAX_FRAME_LEN] {
+        let mut state = 1u32;
+        let mut samples = [[0i16; 4]; MAX_FRAME_LEN];
+        samples.iter_mut().enumerate().for_each(|(n, s)| {
+            s.iter_mut().enumerate().for_each(|(ch, s)| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                let noise = ((state >> 16) % 17) as i16 - 8;
+                let phase = ((n + 3 * ch) % 128) as i16;
+                let triangle = if phase < 64 { phase } else { 128 - phase };
+                *s = 2048 + (triangle - 32) * 18 + noise;
+            });
+        });
+        samples
+    }
+
+    fn round_trip(samples: &[MicArraySample]) -> CompressedSamples {
+        let compressed = CompressedSamples::encode(samples, ChannelMask::ALL);
+        let mut out = [[0i16; 4]; MAX_FRAME_LEN];
+        assert_eq!(
+            compressed.decode(&mut out, ChannelMask::ALL),
+            Ok(samples.len())
+        );
+        assert_eq!(&out[..samples.len()], samples);
+        compressed
+    }
+
+    #[test]
+    fn test_zigzag() {
+        [0, 1, -1, 2047, -2048, i16::MAX, i16::MIN]
+            .iter()
+            .for_each(|&v| assert_eq!(unzigzag(zigzag(v)), v));
+        assert_eq!((zigzag(0), zigzag(-1), zigzag(1)), (0, 1, 2));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let samples = tone();
+        let compressed = round_trip(&samples);
+        // Far smaller than the two bytes per sample of the raw frame
+        assert!(
+            compressed.data.len() < MAX_FRAME_LEN * 4,
+            "{}",
+            compressed.data.len()
+        );
+
+        // Frames that don't fill the last block, and empty ones
+        round_trip(&samples[..300]);
+        round_trip(&[]);
+
+        // Only the channels in the mask are coded, the others are left alone
+        let channels = ChannelMask::single(1).with(3);
+        let compressed = CompressedSamples::encode(&samples[..100], channels);
+        let mut out = [[0i16; 4]; MAX_FRAME_LEN];
+        assert_eq!(compressed.decode(&mut out, channels), Ok(100));
+        out[..100].iter().zip(&samples[..100]).for_each(|(out, s)| {
+            assert_eq!(*out, [0, s[1], 0, s[3]]);
+        });
+        assert!(out[100..].iter().all(|s| *s == [0; 4]));
+    }
+
+    #[test]
+    fn test_worst_case() {
+        // Alternating extremes only leave large residuals
+        let mut samples = [[0i16; 4]; MAX_FRAME_LEN];
+        samples
+            .iter_mut()
+            .enumerate()
+            .for_each(|(n, s)| *s = [if n % 2 == 0 { i16::MIN } else { i16::MAX }; 4]);
+        let compressed = round_trip(&samples);
+        assert!(compressed.data.len() <= MAX_COMPRESSED_LEN);
+
+        // Mostly small residuals, with the occasional escape
+        samples
+            .iter_mut()
+            .enumerate()
+            .for_each(|(n, s)| *s = [if n % 50 == 0 { 30000 } else { (n % 3) as i16 }; 4]);
+        round_trip(&samples);
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let compressed = CompressedSamples::encode(&tone()[..200], ChannelMask::ALL);
+        let mut out = [[0i16; 4]; 100];
+        assert_eq!(
+            compressed.decode(&mut out, ChannelMask::ALL),
+            Err(DecodeError::TooLong)
+        );
+
+        let mut truncated = compressed.clone();
+        truncated.data.truncate(compressed.data.len() / 2);
+        let mut out = [[0i16; 4]; 200];
+        assert_eq!(
+            truncated.decode(&mut out, ChannelMask::ALL),
+            Err(DecodeError::Truncated)
+        );
+
+        // Survives the trip over the wire
+        let mut buf = [0u8; MAX_COMPRESSED_LEN + 8];
+        let bytes = postcard::to_slice(&compressed, &mut buf).unwrap();
+        assert_eq!(
+            postcard::from_bytes::<CompressedSamples>(bytes).unwrap(),
+            compressed
+        );
+    }
+}
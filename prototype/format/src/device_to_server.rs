@@ -1,24 +1,283 @@
 #[cfg(feature = "defmt")]
 use defmt::Format;
+use heapless::Vec;
 use serde::{Deserialize, Serialize};
 
-use serde_big_array::big_array;
-big_array! { BigArray; }
+use crate::{
+    chunk::SampleChunk,
+    framing::LinkStats,
+    heartbeat::Heartbeat,
+    hello::{Acquisition, Features, Hello},
+    log::{Level, LogArgs, LogCode},
+    mode::Mode,
+    motion::MotionEnd,
+    server_to_device::RequestId,
+    status::{DeviceConfig, DeviceStatus},
+    units::{Bearing, Degrees},
+};
 
 #[derive(Serialize, Deserialize, Debug)]
 #[cfg_attr(feature = "defmt", derive(Format))]
 pub enum DeviceToServer {
-    Sync,
-    PanTilt(PanTiltStatus),
-    #[serde(with = "BigArray")]
-    Samples([MicArraySample; 1024]),
+    Hello(Hello),
+    /// The command with the given ID was carried out
+    Ack {
+        id: RequestId,
+        result: CommandResult,
+    },
+    /// The command with the given ID was refused, and none of its parts were carried out
+    Nack {
+        id: RequestId,
+        error: CommandError,
+    },
+    /// Direction of a sound, as calculated by the device from a single frame
+    Detection(Detection),
+    /// Samples of the microphone array, continuing where the previous chunk ended
+    Chunk(SampleChunk),
+    /// Sent periodically to show how the device is doing
+    Telemetry(Telemetry),
+    /// Where the pan/tilt bracket is heading, and where it is now
+    PanTilt {
+        goal: PanTiltStatus,
+        actual: PanTiltStatus,
+    },
+    /// A message the device logged, see [crate::log]
+    Log {
+        level: Level,
+        code: LogCode,
+        args: LogArgs,
+    },
+    /// Sent at the interval of the host's heartbeats, see [crate::heartbeat]
+    Heartbeat(Heartbeat),
+    /// Cross-correlations a detection was calculated from
+    Xcorr(Xcorr),
+    /// The device switched to another operating mode, see [crate::mode]
+    ModeChanged(Mode),
+    /// A motion of the bracket the command with the given ID asked for ended, see
+    /// [crate::motion]
+    MotionDone {
+        id: RequestId,
+        end: MotionEnd,
+        position: PanTiltStatus,
+    },
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 #[cfg_attr(feature = "defmt", derive(Format))]
 pub struct PanTiltStatus {
-    pub pan_deg: i32,
-    pub tilt_deg: i32,
+    pub pan_deg: Degrees,
+    pub tilt_deg: Degrees,
+}
+
+/// Direction of a sound the device found in a frame of samples
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub struct Detection {
+    /// Number of the frame the detection was made in, counting from boot
+    pub frame: u32,
+    /// Time at which sampling of the frame completed, in microseconds since boot
+    pub timestamp_us: u64,
+    /// Bearing relative to the axis through the first microphone pair
+    pub azimuth: Bearing,
+    /// Bearing relative to the axis through the second microphone pair
+    pub elevation: Bearing,
+    /// Lag between the channels of the first pair, in samples
+    pub azimuth_lag: i16,
+    /// Lag between the channels of the second pair, in samples
+    pub elevation_lag: i16,
+    /// Normalized cross-correlation peak of the least correlated pair, in per-mille
+    pub confidence_permille: u16,
+}
+
+/// Upper bound of the amount of lags in a [PairXcorr]
+pub const MAX_XCORR_LEN: usize = 64;
+
+/// Cross-correlation of the channels of a microphone pair
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PairXcorr {
+    /// Lag the device picked, in samples, or `None` if it didn't use the pair
+    pub lag: Option<i16>,
+    /// Correlation at each lag, from the most negative lag to the most positive one.
+    /// All zeros if the pair was too unhealthy to correlate.
+    pub values: Vec<f32, MAX_XCORR_LEN>,
+}
+
+#[cfg(feature = "defmt")]
+impl Format for PairXcorr {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "PairXcorr {{ lag: {}, values: {} }}",
+            self.lag,
+            &self.values[..]
+        )
+    }
+}
+
+/// Cross-correlations the device calculated for a frame of samples
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub struct Xcorr {
+    /// Number of the frame, counting from boot
+    pub frame: u32,
+    /// Index of the first sample of the frame, which matches the `start_index` of the
+    /// chunk holding the frame
+    pub start_index: u64,
+    /// The pair the azimuth is calculated from
+    pub azimuth: PairXcorr,
+    /// The pair the elevation is calculated from
+    pub elevation: PairXcorr,
+}
+
+/// Counters and state of the device. Counters count from boot.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub struct Telemetry {
+    /// Time since boot, in milliseconds
+    pub uptime_ms: u64,
+    /// Tasks that could not be spawned because their queue was full
+    pub spawn_failures: u32,
+    /// Frames the device received from the host, including accumulator overflows and
+    /// deserialize errors
+    pub link: LinkStats,
+    /// Times a message had to wait for the previous one to be sent
+    pub tx_busy_waits: u32,
+    /// Frames of samples that were processed
+    pub frames_processed: u32,
+    /// Frames of samples that were thrown away, because processing fell behind
+    pub frames_dropped: u32,
+    /// Position the pan/tilt bracket is heading for, if the device has one
+    pub pan_tilt_goal: Option<PanTiltStatus>,
+    /// Position of the pan/tilt bracket right now, if the device has one
+    pub pan_tilt_actual: Option<PanTiltStatus>,
+}
+
+/// Outcome of a command that was carried out
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub enum CommandResult {
+    Done,
+    /// The command moved the pan/tilt bracket, which is now heading for this position
+    PanTilt(PanTiltStatus),
+    /// The command changed the acquisition parameters, which are now as follows
+    Configured(Acquisition),
+    /// The reply to [crate::status::Query::Status]
+    Status(DeviceStatus),
+    /// The reply to [crate::status::Query::Config]
+    Config(DeviceConfig),
+}
+
+/// Reason a command was refused
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub enum CommandError {
+    /// An angle lies outside of the range the bracket can move in
+    AngleOutOfRange { min: Degrees, max: Degrees },
+    /// The device lacks these features, which the command needs
+    Unsupported(Features),
+    /// The device can't take on more commands right now, try again later
+    Busy,
+    /// The device can't sample the microphone array as requested
+    InvalidConfig(ConfigError),
+    /// The heartbeat interval lies outside of the range the device supports
+    HeartbeatInterval { min_ms: u32, max_ms: u32 },
+    /// The device can't do this in the mode it is in
+    WrongMode(Mode),
+    /// The maximum speed lies outside of the range the bracket supports
+    MaxSpeed { min_deg_s: u32, max_deg_s: u32 },
+}
+
+/// Reply of the device to a command
+pub type Reply = Result<CommandResult, CommandError>;
+
+/// The replies to the last `N` commands a device handled. Hosts send a command again
+/// with the same ID when its reply doesn't arrive, and the device replies again instead
+/// of carrying out the command twice. Several replies are kept, because heartbeats and
+/// other commands may arrive between an attempt and the next one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecentReplies<const N: usize> {
+    replies: [Option<(RequestId, Reply)>; N],
+    next: usize,
+}
+
+impl<const N: usize> RecentReplies<N> {
+    pub const fn new() -> Self {
+        Self {
+            replies: [None; N],
+            next: 0,
+        }
+    }
+
+    /// The reply to the command with `id`, if it was handled recently
+    pub fn get(&self, id: RequestId) -> Option<Reply> {
+        self.replies
+            .iter()
+            .flatten()
+            .find(|(handled, _)| *handled == id)
+            .map(|(_, reply)| *reply)
+    }
+
+    /// Remember the reply to the command with `id`, forgetting the oldest reply
+    pub fn record(&mut self, id: RequestId, reply: Reply) {
+        self.replies[self.next] = Some((id, reply));
+        self.next = (self.next + 1) % N;
+    }
+
+    /// Forget all replies, e.g. because the host restarted and numbers its commands anew
+    pub fn clear(&mut self) {
+        *self = Self::new();
+    }
+}
+
+impl<const N: usize> Default for RecentReplies<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reason a change to the acquisition parameters was refused
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub enum ConfigError {
+    /// The sample period lies outside of the range the device supports
+    SamplePeriod { min_us: u32, max_us: u32 },
+    /// The frame length lies outside of the range the device supports
+    FrameLen { min: u32, max: u32 },
+    /// The ADC doesn't support this gain
+    Gain,
+    /// The ADC doesn't support this resolution
+    Resolution,
+    /// The ADC doesn't support this amount of oversampling
+    Oversample,
+    /// The ADC doesn't support this acquisition time
+    AcquisitionTime,
+    /// Scanning all channels would take longer than the sample period
+    ScanTooLong { scan_time_ns: u32 },
 }
 
 pub type MicArraySample = [i16; 4];
+
+#[cfg(test)]
+mod test {
+    use crate::device_to_server::*;
+
+    #[test]
+    fn test_recent_replies() {
+        let mut replies = RecentReplies::<3>::new();
+        replies.record(7, Ok(CommandResult::Done));
+        // A heartbeat and a command from elsewhere arrive before command 7 is sent again
+        replies.record(8, Ok(CommandResult::Done));
+        replies.record(100, Err(CommandError::WrongMode(Mode::Idle)));
+        assert_eq!(replies.get(7), Some(Ok(CommandResult::Done)));
+        assert!(replies.get(100).unwrap().is_err());
+        assert_eq!(replies.get(9), None);
+
+        // Only the last few are kept
+        replies.record(9, Ok(CommandResult::Done));
+        assert_eq!(replies.get(7), None);
+        assert!(replies.get(8).is_some());
+
+        replies.clear();
+        assert_eq!(replies.get(9), None);
+    }
+}
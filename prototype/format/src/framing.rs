@@ -0,0 +1,501 @@
+//! Framing of messages on the serial link.
+//!
+//! Each message is serialized with postcard, prefixed with a little endian `u16` sequence number
+//! and followed by the little endian CRC-32 of both. The result is COBS encoded and terminated
+//! by a zero byte. Each direction of the link has its own sequence counter.
+
+#[cfg(feature = "defmt")]
+use defmt::Format;
+use postcard::flavors::{Cobs, SerFlavor, Slice};
+use serde::{Deserialize, Serialize};
+
+/// Bytes taken up by the sequence number
+pub const SEQ_LEN: usize = 2;
+/// Bytes taken up by the checksum
+pub const CRC_LEN: usize = 4;
+
+/// Frames that arrive at most this amount of frames late are counted as out of order.
+/// Frames that are even later are taken as a sign that the sender restarted.
+const REORDER_WINDOW: u16 = 64;
+
+/// Maximum size of an encoded frame carrying a payload of `payload_len` bytes,
+/// including the zero terminator
+pub const fn max_frame_len(payload_len: usize) -> usize {
+    let len = SEQ_LEN + payload_len + CRC_LEN;
+    // COBS adds a byte for every 254 bytes, plus one
+    len + len / 254 + 2
+}
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// Incremental CRC-32 (IEEE 802.3), the checksum used by Ethernet and zlib
+#[derive(Debug, Clone, Copy)]
+pub struct Crc32(u32);
+
+impl Crc32 {
+    pub const fn new() -> Self {
+        Self(0xFFFF_FFFF)
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        bytes.iter().for_each(|b| {
+            self.0 = CRC32_TABLE[((self.0 ^ *b as u32) & 0xFF) as usize] ^ (self.0 >> 8);
+        });
+    }
+
+    pub const fn finish(self) -> u32 {
+        !self.0
+    }
+
+    /// Checksum of a complete buffer
+    pub fn checksum(bytes: &[u8]) -> u32 {
+        let mut crc = Self::new();
+        crc.update(bytes);
+        crc.finish()
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Postcard flavor that prefixes the sequence number and appends the checksum
+struct Framed<B: SerFlavor> {
+    flav: B,
+    crc: Crc32,
+}
+
+impl<B: SerFlavor> Framed<B> {
+    fn try_new(flav: B, seq: u16) -> Result<Self, ()> {
+        let mut framed = Self {
+            flav,
+            crc: Crc32::new(),
+        };
+        framed.try_extend(&seq.to_le_bytes())?;
+        Ok(framed)
+    }
+}
+
+impl<B: SerFlavor> SerFlavor for Framed<B> {
+    type Output = B::Output;
+
+    fn try_push(&mut self, data: u8) -> Result<(), ()> {
+        self.crc.update(&[data]);
+        self.flav.try_push(data)
+    }
+
+    fn release(mut self) -> Result<Self::Output, ()> {
+        self.flav.try_extend(&self.crc.finish().to_le_bytes())?;
+        self.flav.release()
+    }
+}
+
+/// Sending end of a link, which numbers the frames it encodes
+#[derive(Debug, Default)]
+pub struct FrameEncoder {
+    seq: u16,
+}
+
+impl FrameEncoder {
+    pub const fn new() -> Self {
+        Self { seq: 0 }
+    }
+
+    /// Sequence number the next frame will get
+    pub fn seq(&self) -> u16 {
+        self.seq
+    }
+
+    /// Encode a message into a frame, including the zero terminator
+    pub fn encode<'a, T: Serialize>(
+        &mut self,
+        msg: &T,
+        buf: &'a mut [u8],
+    ) -> postcard::Result<&'a mut [u8]> {
+        let flavor = Framed::try_new(Cobs::try_new(Slice::new(buf))?, self.seq)
+            .map_err(|_| postcard::Error::SerializeBufferFull)?;
+        let frame = postcard::serialize_with_flavor(msg, flavor)?;
+        self.seq = self.seq.wrapping_add(1);
+        Ok(frame)
+    }
+}
+
+/// How the sequence number of a received frame relates to the ones received before
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub enum SeqStatus {
+    /// The frame is the one that was expected
+    InOrder,
+    /// This amount of frames was skipped
+    Dropped(u16),
+    /// The frame arrived after a frame that was sent later
+    OutOfOrder,
+    /// The numbering started over, e.g. because the sender restarted
+    Resync,
+}
+
+/// Statistics of the frames a receiver has seen
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub struct LinkStats {
+    /// Frames that were received intact
+    pub frames: u32,
+    /// Frames of which the checksum or encoding was wrong
+    pub corrupt: u32,
+    /// Intact frames of which the payload could not be deserialized
+    pub deser_errors: u32,
+    /// Frames that did not fit in the receive buffer
+    pub overflows: u32,
+    /// Frames that never arrived, judging by the sequence numbers
+    pub dropped: u32,
+    pub out_of_order: u32,
+    pub resyncs: u32,
+    #[serde(skip)]
+    next_seq: Option<u16>,
+}
+
+impl LinkStats {
+    pub const fn new() -> Self {
+        Self {
+            frames: 0,
+            corrupt: 0,
+            deser_errors: 0,
+            overflows: 0,
+            dropped: 0,
+            out_of_order: 0,
+            resyncs: 0,
+            next_seq: None,
+        }
+    }
+
+    /// Whether anything went wrong since the stats were created
+    pub fn has_errors(&self) -> bool {
+        self.corrupt + self.deser_errors + self.overflows + self.dropped + self.out_of_order > 0
+    }
+
+    /// Record the sequence number of an intact frame
+    pub fn record(&mut self, seq: u16) -> SeqStatus {
+        self.frames += 1;
+        let expected = match self.next_seq {
+            Some(expected) => expected,
+            None => {
+                self.next_seq = Some(seq.wrapping_add(1));
+                return SeqStatus::InOrder;
+            }
+        };
+        let ahead = seq.wrapping_sub(expected);
+        let behind = expected.wrapping_sub(seq);
+        if ahead == 0 {
+            self.next_seq = Some(seq.wrapping_add(1));
+            SeqStatus::InOrder
+        } else if behind <= REORDER_WINDOW {
+            self.out_of_order += 1;
+            SeqStatus::OutOfOrder
+        } else if ahead < u16::MAX / 2 {
+            self.dropped += ahead as u32;
+            self.next_seq = Some(seq.wrapping_add(1));
+            SeqStatus::Dropped(ahead)
+        } else {
+            self.resyncs += 1;
+            self.next_seq = Some(seq.wrapping_add(1));
+            SeqStatus::Resync
+        }
+    }
+}
+
+/// The result of feeding a [FrameAccumulator]
+pub enum FeedResult<'a, T> {
+    /// Consumed all data, still pending
+    Consumed,
+    /// Buffer was filled. Contains remaining section of input, if any.
+    OverFull(&'a [u8]),
+    /// The frame was damaged. Contains remaining section of input, if any.
+    Corrupt(&'a [u8]),
+    /// The frame was intact, but deserialization failed. Contains remaining section of input, if any.
+    DeserError(&'a [u8]),
+    /// Deserialization complete. Contains deserialized data, the sequence status of the frame and
+    /// the remaining section of input, if any.
+    Success {
+        data: T,
+        seq: SeqStatus,
+        remaining: &'a [u8],
+    },
+}
+
+/// Receiving end of a link, collecting chunks of frames, checking their integrity
+/// and keeping [LinkStats]
+pub struct FrameAccumulator<const N: usize> {
+    buf: [u8; N],
+    idx: usize,
+    stats: LinkStats,
+}
+
+impl<const N: usize> FrameAccumulator<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            idx: 0,
+            stats: LinkStats::new(),
+        }
+    }
+
+    pub fn stats(&self) -> &LinkStats {
+        &self.stats
+    }
+
+    /// Append data to the internal buffer, and attempt to deserialize a frame
+    /// if the end of one was found
+    pub fn feed<'a, T>(&mut self, input: &'a [u8]) -> FeedResult<'a, T>
+    where
+        T: for<'de> Deserialize<'de>,
+    {
+        if input.is_empty() {
+            return FeedResult::Consumed;
+        }
+
+        match input.iter().position(|b| *b == 0) {
+            Some(n) => {
+                let (take, release) = input.split_at(n + 1);
+                if self.idx + take.len() > N {
+                    self.idx = 0;
+                    self.stats.overflows += 1;
+                    return FeedResult::OverFull(release);
+                }
+                self.buf[self.idx..self.idx + take.len()].copy_from_slice(take);
+                let len = self.idx + take.len();
+                self.idx = 0;
+                self.decode(len, release)
+            }
+            None => {
+                if self.idx + input.len() > N {
+                    let new_start = N - self.idx;
+                    self.idx = 0;
+                    self.stats.overflows += 1;
+                    FeedResult::OverFull(&input[new_start..])
+                } else {
+                    self.buf[self.idx..self.idx + input.len()].copy_from_slice(input);
+                    self.idx += input.len();
+                    FeedResult::Consumed
+                }
+            }
+        }
+    }
+
+    /// Decode the frame of `len` bytes in the buffer
+    fn decode<'a, T>(&mut self, len: usize, remaining: &'a [u8]) -> FeedResult<'a, T>
+    where
+        T: for<'de> Deserialize<'de>,
+    {
+        // The zero terminator is not part of the encoded data
+        let frame = match cobs::decode_in_place(&mut self.buf[..len - 1]) {
+            Ok(decoded) if decoded >= SEQ_LEN + CRC_LEN => &self.buf[..decoded],
+            _ => {
+                self.stats.corrupt += 1;
+                return FeedResult::Corrupt(remaining);
+            }
+        };
+        let (body, crc) = frame.split_at(frame.len() - CRC_LEN);
+        if Crc32::checksum(body).to_le_bytes() != crc {
+            self.stats.corrupt += 1;
+            return FeedResult::Corrupt(remaining);
+        }
+
+        let seq = u16::from_le_bytes([body[0], body[1]]);
+        match postcard::from_bytes(&body[SEQ_LEN..]) {
+            Ok(data) => FeedResult::Success {
+                data,
+                seq: self.stats.record(seq),
+                remaining,
+            },
+            Err(_) => {
+                self.stats.deser_errors += 1;
+                // The frame arrived, so it doesn't count as dropped
+                self.stats.record(seq);
+                FeedResult::DeserError(remaining)
+            }
+        }
+    }
+}
+
+impl<const N: usize> Default for FrameAccumulator<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::framing::*;
+    use crate::units::Degrees;
+    use crate::ServerToDevice;
+
+    fn msg(pan: i32) -> ServerToDevice {
+        ServerToDevice {
+            pan_degrees: Some(Degrees(pan)),
+            ..ServerToDevice::default()
+        }
+    }
+
+    fn receive(acc: &mut FrameAccumulator<64>, frame: &[u8]) -> Option<(i32, SeqStatus)> {
+        match acc.feed::<ServerToDevice>(frame) {
+            FeedResult::Success { data, seq, .. } => Some((data.pan_degrees.unwrap().0, seq)),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(Crc32::checksum(b"123456789"), 0xCBF4_3926);
+        let mut crc = Crc32::new();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.finish(), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let mut encoder = FrameEncoder::new();
+        let mut acc = FrameAccumulator::<64>::new();
+        let mut buf = [0u8; 64];
+
+        let payload_len = postcard::to_slice(&msg(-45), &mut buf).unwrap().len();
+        let frame = encoder.encode(&msg(-45), &mut buf).unwrap();
+        assert!(frame.len() <= max_frame_len(payload_len));
+        assert_eq!(frame.last(), Some(&0));
+        assert!(frame[..frame.len() - 1].iter().all(|b| *b != 0));
+
+        // Frames may arrive in chunks
+        let (first, second) = frame.split_at(3);
+        assert!(matches!(
+            acc.feed::<ServerToDevice>(first),
+            FeedResult::Consumed
+        ));
+        assert_eq!(receive(&mut acc, second), Some((-45, SeqStatus::InOrder)));
+        assert_eq!(encoder.seq(), 1);
+        assert!(!acc.stats().has_errors());
+    }
+
+    #[test]
+    fn test_several_frames() {
+        let mut encoder = FrameEncoder::new();
+        let mut acc = FrameAccumulator::<64>::new();
+        let mut buf = [0u8; 96];
+
+        // A corrupt frame and two intact ones, the last split over two reads
+        let mut len = 0;
+        [1, 2, 3].iter().for_each(|&i| {
+            len += encoder.encode(&msg(i), &mut buf[len..]).unwrap().len();
+        });
+        buf[2] ^= 0x04;
+        let (first, second) = buf[..len].split_at(len - 2);
+
+        let mut received = [0; 3];
+        let mut count = 0;
+        [first, second].iter().for_each(|&read| {
+            let mut chunk = read;
+            while !chunk.is_empty() {
+                chunk = match acc.feed::<ServerToDevice>(chunk) {
+                    FeedResult::Consumed => &[],
+                    FeedResult::Success {
+                        data, remaining, ..
+                    } => {
+                        received[count] = data.pan_degrees.unwrap().0;
+                        count += 1;
+                        remaining
+                    }
+                    FeedResult::Corrupt(rest) => rest,
+                    _ => panic!("unexpected feed result"),
+                };
+            }
+        });
+        assert_eq!(&received[..count], &[2, 3]);
+        assert_eq!(acc.stats().corrupt, 1);
+        assert_eq!(acc.stats().dropped, 0);
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let mut encoder = FrameEncoder::new();
+        let mut acc = FrameAccumulator::<64>::new();
+        let mut buf = [0u8; 64];
+
+        let frame = encoder.encode(&msg(10), &mut buf).unwrap();
+        // A flipped bit that would otherwise still deserialize
+        frame[4] ^= 0x04;
+        assert!(matches!(
+            acc.feed::<ServerToDevice>(frame),
+            FeedResult::Corrupt(_)
+        ));
+        assert_eq!(acc.stats().corrupt, 1);
+        assert_eq!(acc.stats().frames, 0);
+
+        // A truncated frame
+        let frame = encoder.encode(&msg(10), &mut buf).unwrap();
+        let len = frame.len();
+        frame[len - 3] = 0;
+        assert!(matches!(
+            acc.feed::<ServerToDevice>(&frame[len - 3..]),
+            FeedResult::Corrupt(_)
+        ));
+        assert_eq!(acc.stats().corrupt, 2);
+
+        // Too long for the buffer
+        let mut small = FrameAccumulator::<8>::new();
+        let frame = encoder.encode(&msg(1000), &mut buf).unwrap();
+        assert!(matches!(
+            small.feed::<ServerToDevice>(frame),
+            FeedResult::OverFull(_)
+        ));
+        assert_eq!(small.stats().overflows, 1);
+    }
+
+    #[test]
+    fn test_sequence() {
+        let mut encoder = FrameEncoder::new();
+        let mut acc = FrameAccumulator::<64>::new();
+        let frames: [([u8; 32], usize); 6] = [0, 1, 2, 3, 4, 5].map(|i| {
+            let mut buf = [0u8; 32];
+            let len = encoder.encode(&msg(i), &mut buf).unwrap().len();
+            (buf, len)
+        });
+        let mut send = |i: usize| receive(&mut acc, &frames[i].0[..frames[i].1]).unwrap().1;
+
+        assert_eq!(send(0), SeqStatus::InOrder);
+        assert_eq!(send(2), SeqStatus::Dropped(1));
+        assert_eq!(send(1), SeqStatus::OutOfOrder);
+        assert_eq!(send(3), SeqStatus::InOrder);
+        assert_eq!(send(5), SeqStatus::Dropped(1));
+        // A frame sent long before counts as out of order too
+        assert_eq!(send(0), SeqStatus::OutOfOrder);
+        let stats = *acc.stats();
+        assert_eq!((stats.frames, stats.dropped, stats.out_of_order), (6, 2, 2));
+
+        let mut stats = LinkStats::new();
+        stats.record(1000);
+        assert_eq!(stats.record(2), SeqStatus::Resync);
+        assert_eq!(stats.record(3), SeqStatus::InOrder);
+        assert_eq!(stats.record(u16::MAX), SeqStatus::OutOfOrder);
+    }
+}
@@ -0,0 +1,118 @@
+//! Heartbeats, which let each end of the link notice when the other one is gone.
+//!
+//! The host sends a [Heartbeat] every `interval_ms`, and the device answers with heartbeats
+//! of its own at the same interval. Any message counts as a sign of life, and an end that
+//! hears nothing for [MISSED_BEATS] intervals considers the link lost, see [LinkMonitor].
+
+#[cfg(feature = "defmt")]
+use defmt::Format;
+use serde::{Deserialize, Serialize};
+
+/// Amount of intervals without a message after which the link is considered lost
+pub const MISSED_BEATS: u32 = 3;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub struct Heartbeat {
+    /// Interval at which the sender sends heartbeats, in milliseconds, or 0 if it stopped
+    pub interval_ms: u32,
+}
+
+/// Change in the state of the link, see [LinkMonitor]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub enum LinkEvent {
+    /// The other end went silent for too long
+    Lost,
+    /// The other end was heard from again after the link was lost
+    Restored,
+}
+
+/// Keeps track of when the other end was last heard from
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub struct LinkMonitor {
+    interval_ms: u32,
+    last_seen_ms: u64,
+    lost: bool,
+}
+
+impl LinkMonitor {
+    /// Doesn't watch the link until an interval is set
+    pub const fn new() -> Self {
+        Self {
+            interval_ms: 0,
+            last_seen_ms: 0,
+            lost: false,
+        }
+    }
+
+    /// Expect a message at least every `interval_ms`, or stop watching the link if 0
+    pub fn set_interval(&mut self, interval_ms: u32, now_ms: u64) {
+        self.interval_ms = interval_ms;
+        self.last_seen_ms = now_ms;
+    }
+
+    pub fn interval_ms(&self) -> u32 {
+        self.interval_ms
+    }
+
+    pub fn is_lost(&self) -> bool {
+        self.lost
+    }
+
+    /// Note that a message arrived
+    pub fn seen(&mut self, now_ms: u64) -> Option<LinkEvent> {
+        self.last_seen_ms = now_ms;
+        if self.lost {
+            self.lost = false;
+            Some(LinkEvent::Restored)
+        } else {
+            None
+        }
+    }
+
+    /// Check whether the other end has been silent for too long. A lost link is only
+    /// reported once, until it is restored.
+    pub fn check(&mut self, now_ms: u64) -> Option<LinkEvent> {
+        let timeout_ms = self.interval_ms as u64 * MISSED_BEATS as u64;
+        if self.interval_ms == 0 || self.lost || now_ms <= self.last_seen_ms + timeout_ms {
+            return None;
+        }
+        self.lost = true;
+        Some(LinkEvent::Lost)
+    }
+}
+
+impl Default for LinkMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::heartbeat::*;
+
+    #[test]
+    fn test_link_monitor() {
+        let mut monitor = LinkMonitor::new();
+        // Not watching yet
+        assert_eq!(monitor.check(1_000_000), None);
+
+        monitor.set_interval(100, 1000);
+        assert_eq!(monitor.check(1300), None);
+        assert_eq!(monitor.seen(1250), None);
+        assert_eq!(monitor.check(1550), None);
+        assert_eq!(monitor.check(1551), Some(LinkEvent::Lost));
+        assert!(monitor.is_lost());
+        // Reported once
+        assert_eq!(monitor.check(2000), None);
+        assert_eq!(monitor.seen(2100), Some(LinkEvent::Restored));
+        assert!(!monitor.is_lost());
+        assert_eq!(monitor.check(2400), None);
+
+        monitor.set_interval(0, 2400);
+        assert_eq!(monitor.check(10_000), None);
+    }
+}
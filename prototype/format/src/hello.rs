@@ -0,0 +1,319 @@
+#[cfg(feature = "defmt")]
+use defmt::Format;
+use serde::{Deserialize, Serialize};
+
+use crate::{server_to_device::Configure, units::AdcScale, PROTOCOL_VERSION};
+
+/// Semantic version of a firmware build
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub struct Version {
+    pub major: u8,
+    pub minor: u8,
+    pub patch: u8,
+}
+
+impl Version {
+    /// Parse a version of the form `major.minor.patch`, like `env!("CARGO_PKG_VERSION")`.
+    /// Anything following the patch number, like a pre-release tag, is ignored.
+    pub const fn parse(version: &str) -> Self {
+        let bytes = version.as_bytes();
+        let mut parts = [0u8; 3];
+        let mut part = 0;
+        let mut i = 0;
+        while i < bytes.len() && part < 3 {
+            match bytes[i] {
+                b'.' => part += 1,
+                b @ b'0'..=b'9' => parts[part] = parts[part] * 10 + (b - b'0'),
+                _ => break,
+            }
+            i += 1;
+        }
+        Self {
+            major: parts[0],
+            minor: parts[1],
+            patch: parts[2],
+        }
+    }
+}
+
+/// Set of optional functionality a device has
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub struct Features(u8);
+
+impl Features {
+    /// Samples the microphone array
+    pub const MIC_ARRAY: Self = Self(1 << 0);
+    /// Drives the pan/tilt bracket
+    pub const PAN_TILT: Self = Self(1 << 1);
+    /// Communicates with the host over UART
+    pub const UART: Self = Self(1 << 2);
+    /// Calculates the cross correlation using the FPU
+    pub const FPU: Self = Self(1 << 3);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// The features in the set or in `other`
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    /// Add `other` to the set if `enabled` is true
+    pub const fn with_if(self, other: Self, enabled: bool) -> Self {
+        if enabled {
+            self.union(other)
+        } else {
+            self
+        }
+    }
+
+    /// The features in the set that are not in `other`
+    pub const fn without(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Whether all features in `other` are in the set
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+/// Parameters that determine how the host needs to interpret the samples of a device
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub struct Acquisition {
+    /// Sample period in microseconds
+    pub sample_period_us: u32,
+    /// Distance between two mics in millimeters
+    pub mic_distance_mm: u32,
+    /// Amount of samples in a frame
+    pub frame_len: u32,
+    pub adc_scale: AdcScale,
+    /// Base-2 logarithm of the amount of conversions averaged into a sample
+    pub oversample_log2: u32,
+    /// Time the ADC takes to acquire a sample, in microseconds
+    pub acquisition_time_us: u32,
+}
+
+impl Acquisition {
+    /// The parameters after applying the changes in `configure`. Whether the device can
+    /// actually sample that way is up to the device to decide.
+    pub fn configured(&self, configure: &Configure) -> Self {
+        let mut acquisition = *self;
+        if let Some((num, den)) = configure.gain {
+            acquisition.adc_scale.gain_num = num;
+            acquisition.adc_scale.gain_den = den;
+        }
+        acquisition.sample_period_us = configure.sample_period_us.unwrap_or(self.sample_period_us);
+        acquisition.adc_scale.resolution_bits = configure
+            .resolution_bits
+            .unwrap_or(self.adc_scale.resolution_bits);
+        acquisition.oversample_log2 = configure.oversample_log2.unwrap_or(self.oversample_log2);
+        acquisition.acquisition_time_us = configure
+            .acquisition_time_us
+            .unwrap_or(self.acquisition_time_us);
+        acquisition.frame_len = configure.frame_len.unwrap_or(self.frame_len);
+        acquisition
+    }
+}
+
+/// Sent by the device at boot, and in response to [Identify]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub struct Hello {
+    pub protocol_version: u16,
+    pub firmware_version: Version,
+    pub features: Features,
+    /// Only present if the device samples the microphone array
+    pub acquisition: Option<Acquisition>,
+}
+
+/// Sent by the host to request a [Hello] from the device
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub struct Identify {
+    pub protocol_version: u16,
+}
+
+impl Identify {
+    pub const fn new() -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+        }
+    }
+}
+
+impl Default for Identify {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reason a host can't work with a device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub enum Incompatible {
+    ProtocolVersion { device: u16, host: u16 },
+    SamplePeriod { device_us: u32, host_us: u32 },
+    MicDistance { device_mm: u32, host_mm: u32 },
+    FrameLen { device: u32, host: u32 },
+}
+
+impl Hello {
+    /// Check whether the device speaks the protocol of this build, which is all a host
+    /// needs to exchange messages with it
+    pub fn check_protocol(&self) -> Result<(), Incompatible> {
+        if self.protocol_version != PROTOCOL_VERSION {
+            Err(Incompatible::ProtocolVersion {
+                device: self.protocol_version,
+                host: PROTOCOL_VERSION,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Check whether a host, built for the given acquisition parameters, can interpret
+    /// the messages of the device. Devices that don't sample are only checked on their
+    /// protocol version.
+    pub fn check_compatible(
+        &self,
+        sample_period_us: u32,
+        mic_distance_mm: u32,
+        frame_len: u32,
+    ) -> Result<(), Incompatible> {
+        use Incompatible::*;
+
+        self.check_protocol()?;
+        let acquisition = match self.acquisition {
+            Some(acquisition) => acquisition,
+            None => return Ok(()),
+        };
+        if acquisition.sample_period_us != sample_period_us {
+            Err(SamplePeriod {
+                device_us: acquisition.sample_period_us,
+                host_us: sample_period_us,
+            })
+        } else if acquisition.mic_distance_mm != mic_distance_mm {
+            Err(MicDistance {
+                device_mm: acquisition.mic_distance_mm,
+                host_mm: mic_distance_mm,
+            })
+        } else if acquisition.frame_len != frame_len {
+            Err(FrameLen {
+                device: acquisition.frame_len,
+                host: frame_len,
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::hello::*;
+    use crate::units::AdcReference;
+
+    fn hello(sample_period_us: u32) -> Hello {
+        Hello {
+            protocol_version: PROTOCOL_VERSION,
+            firmware_version: Version::parse("0.1.0"),
+            features: Features::MIC_ARRAY.union(Features::UART),
+            acquisition: Some(Acquisition {
+                sample_period_us,
+                mic_distance_mm: 125,
+                frame_len: 1024,
+                adc_scale: AdcScale::single_ended(AdcReference::Internal, 1, 3, 12),
+                oversample_log2: 0,
+                acquisition_time_us: 5,
+            }),
+        }
+    }
+
+    #[test]
+    fn test_version() {
+        let version = Version::parse("1.12.3-alpha.1");
+        assert_eq!((version.major, version.minor, version.patch), (1, 12, 3));
+        assert!(Version::parse("0.2.0") > Version::parse("0.1.9"));
+    }
+
+    #[test]
+    fn test_features() {
+        let features = hello(37).features;
+        assert!(features.contains(Features::MIC_ARRAY));
+        assert!(!features.contains(Features::PAN_TILT));
+        assert!(features.contains(Features::empty()));
+        assert!(!features.contains(Features::MIC_ARRAY.union(Features::FPU)));
+        assert_eq!(
+            Features::MIC_ARRAY.union(Features::FPU).without(features),
+            Features::FPU
+        );
+        assert!(Features::UART.without(features).is_empty());
+    }
+
+    #[test]
+    fn test_check_compatible() {
+        assert_eq!(hello(37).check_compatible(37, 125, 1024), Ok(()));
+        assert_eq!(
+            hello(37).check_compatible(22, 125, 1024),
+            Err(Incompatible::SamplePeriod {
+                device_us: 37,
+                host_us: 22
+            })
+        );
+        assert!(hello(37).check_compatible(37, 100, 1024).is_err());
+
+        let old = Hello {
+            protocol_version: PROTOCOL_VERSION + 1,
+            ..hello(37)
+        };
+        assert!(matches!(
+            old.check_compatible(37, 125, 1024),
+            Err(Incompatible::ProtocolVersion { .. })
+        ));
+
+        // Nothing to interpret if the device doesn't sample
+        let control = Hello {
+            acquisition: None,
+            ..hello(37)
+        };
+        assert_eq!(control.check_compatible(22, 100, 1), Ok(()));
+
+        // Survives the trip over the wire
+        let mut buf = [0u8; 64];
+        let bytes = postcard::to_slice(&hello(37), &mut buf).unwrap();
+        assert_eq!(postcard::from_bytes::<Hello>(bytes).unwrap(), hello(37));
+    }
+
+    #[test]
+    fn test_configured() {
+        let acquisition = hello(37).acquisition.unwrap();
+        assert_eq!(acquisition.configured(&Configure::default()), acquisition);
+
+        let configured = acquisition.configured(&Configure {
+            sample_period_us: Some(50),
+            gain: Some((1, 4)),
+            frame_len: Some(512),
+            ..Configure::default()
+        });
+        assert_eq!(
+            (configured.sample_period_us, configured.frame_len),
+            (50, 512)
+        );
+        assert_eq!(
+            (configured.adc_scale.gain_num, configured.adc_scale.gain_den),
+            (1, 4)
+        );
+        assert_eq!(configured.adc_scale.resolution_bits, 12);
+        assert_eq!(configured.acquisition_time_us, 5);
+        assert_eq!(configured.mic_distance_mm, 125);
+    }
+}
@@ -1,7 +1,21 @@
 #![no_std]
 
+pub mod chunk;
+pub mod compression;
 pub mod device_to_server;
+pub mod framing;
+pub mod heartbeat;
+pub mod hello;
+pub mod log;
+pub mod mode;
+pub mod motion;
 pub mod server_to_device;
+pub mod status;
+pub mod stream;
+pub mod units;
 
 pub use device_to_server::DeviceToServer;
 pub use server_to_device::ServerToDevice;
+
+/// Version of the protocol, to be incremented on every change to the messages
+pub const PROTOCOL_VERSION: u16 = 14;
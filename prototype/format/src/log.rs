@@ -0,0 +1,207 @@
+//! Log messages the device mirrors to the host, see [crate::DeviceToServer::Log].
+//!
+//! Instead of text, the device sends a [LogCode] and a few numeric arguments. The text of
+//! each code is known to both sides, and the host fills in the arguments to display it.
+
+use core::{fmt, ops::Deref};
+
+#[cfg(feature = "defmt")]
+use defmt::Format;
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+/// Maximum amount of arguments of a log message
+pub const MAX_LOG_ARGS: usize = 4;
+
+/// Arguments of a log message
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct LogArgs(pub Vec<i64, MAX_LOG_ARGS>);
+
+impl LogArgs {
+    /// Take the first [MAX_LOG_ARGS] of `args`
+    pub fn new(args: &[i64]) -> Self {
+        Self(args.iter().copied().take(MAX_LOG_ARGS).collect())
+    }
+}
+
+impl Deref for LogArgs {
+    type Target = [i64];
+
+    fn deref(&self) -> &[i64] {
+        &self.0
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl Format for LogArgs {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "{}", &self.0[..])
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub enum Level {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    /// Parse a level from its name, ignoring case
+    pub fn parse(name: &str) -> Option<Self> {
+        [
+            ("trace", Self::Trace),
+            ("debug", Self::Debug),
+            ("info", Self::Info),
+            ("warn", Self::Warn),
+            ("error", Self::Error),
+        ]
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, level)| *level)
+    }
+}
+
+/// Identifies a log message. Every `{}` in its [text](LogCode::text) is replaced by the
+/// next argument.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub enum LogCode {
+    AccumulatorFull,
+    CorruptFrame,
+    DeserializeError,
+    FrameOutOfSequence,
+    CommandQueueFull,
+    CommandRefused,
+    ProtocolMismatch,
+    Reconfigured,
+    SendFailed,
+    FrameDropped,
+    MoveBracketFailed,
+    StartSamplingFailed,
+    LinkLost,
+    LinkRestored,
+}
+
+impl LogCode {
+    pub fn text(self) -> &'static str {
+        use LogCode::*;
+        match self {
+            AccumulatorFull => "Receive buffer full, dropped its contents ({} overflows so far)",
+            CorruptFrame => "Received a corrupt frame ({} so far)",
+            DeserializeError => "Could not deserialize a command ({} so far)",
+            FrameOutOfSequence => {
+                "Frames went missing ({} so far) or arrived out of order ({} so far)"
+            }
+            CommandQueueFull => "Too many commands queued, refused command {}",
+            CommandRefused => "Refused command {}",
+            ProtocolMismatch => "Host speaks protocol version {}, this device speaks {}",
+            Reconfigured => "Reconfigured acquisition: sample period {} us, frame length {}",
+            SendFailed => "Could not queue a message for sending",
+            FrameDropped => "Processing fell behind, dropped frame {}",
+            MoveBracketFailed => "Could not queue a move of the pan/tilt bracket",
+            StartSamplingFailed => "Could not restart sampling",
+            LinkLost => "Heard nothing from the host for {} ms, entering safe state",
+            LinkRestored => "Heard from the host again",
+        }
+    }
+
+    /// Display the text of the message with the arguments filled in
+    pub fn display(self, args: &[i64]) -> Display<'_> {
+        Display { code: self, args }
+    }
+}
+
+/// A log message with its arguments filled in, see [LogCode::display]
+pub struct Display<'a> {
+    code: LogCode,
+    args: &'a [i64],
+}
+
+impl fmt::Display for Display<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut args = self.args.iter();
+        let mut parts = self.code.text().split("{}");
+        // There's always at least one part
+        f.write_str(parts.next().unwrap_or_default())?;
+        for part in parts {
+            match args.next() {
+                Some(arg) => write!(f, "{}", arg)?,
+                None => f.write_str("?")?,
+            }
+            f.write_str(part)?;
+        }
+        // Arguments without a placeholder are shown anyway
+        args.try_for_each(|arg| write!(f, " {}", arg))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::log::*;
+
+    struct Buf {
+        data: [u8; 128],
+        len: usize,
+    }
+
+    impl fmt::Write for Buf {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            let end = self.len + s.len();
+            self.data
+                .get_mut(self.len..end)
+                .ok_or(fmt::Error)?
+                .copy_from_slice(s.as_bytes());
+            self.len = end;
+            Ok(())
+        }
+    }
+
+    fn check(code: LogCode, args: &[i64], expected: &str) {
+        use fmt::Write;
+        let mut buf = Buf {
+            data: [0; 128],
+            len: 0,
+        };
+        write!(buf, "{}", code.display(args)).unwrap();
+        assert_eq!(core::str::from_utf8(&buf.data[..buf.len]), Ok(expected));
+    }
+
+    #[test]
+    fn test_display() {
+        check(
+            LogCode::ProtocolMismatch,
+            &[5, 7],
+            "Host speaks protocol version 5, this device speaks 7",
+        );
+        check(
+            LogCode::SendFailed,
+            &[],
+            "Could not queue a message for sending",
+        );
+        // Missing and superfluous arguments
+        check(
+            LogCode::ProtocolMismatch,
+            &[5],
+            "Host speaks protocol version 5, this device speaks ?",
+        );
+        check(LogCode::CommandRefused, &[3, -1], "Refused command 3 -1");
+    }
+
+    #[test]
+    fn test_level() {
+        assert_eq!(Level::parse("WARN"), Some(Level::Warn));
+        assert_eq!(Level::parse("info"), Some(Level::Info));
+        assert_eq!(Level::parse("loud"), None);
+        assert!(Level::Error > Level::Warn && Level::Debug > Level::Trace);
+    }
+
+    #[test]
+    fn test_args() {
+        assert_eq!(&*LogArgs::new(&[1, 2]), &[1, 2]);
+        assert_eq!(&*LogArgs::new(&[1, 2, 3, 4, 5, 6]), &[1, 2, 3, 4]);
+    }
+}
@@ -0,0 +1,100 @@
+//! Operating modes of the device, which decide what it does with its microphone array
+//! and pan/tilt bracket. The host switches modes with [crate::ServerToDevice::set_mode],
+//! and the buttons of the dev kit switch them as well. The device sends a
+//! [crate::DeviceToServer::ModeChanged] whenever its mode changes.
+
+#[cfg(feature = "defmt")]
+use defmt::Format;
+use serde::{Deserialize, Serialize};
+
+use crate::hello::Features;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub enum Mode {
+    /// Don't sample, and leave the bracket where it is
+    Idle,
+    /// Sample and find directions, but leave the bracket where it is
+    Measure,
+    /// Only move the bracket when the host says so
+    ManualControl,
+    /// Sample, and point the bracket at the sounds that are found
+    AutoTrack,
+    /// Sample while sweeping the bracket back and forth
+    Scan,
+}
+
+impl Mode {
+    pub const ALL: [Self; 5] = [
+        Self::Idle,
+        Self::Measure,
+        Self::ManualControl,
+        Self::AutoTrack,
+        Self::Scan,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Idle => "idle",
+            Self::Measure => "measure",
+            Self::ManualControl => "manual",
+            Self::AutoTrack => "autotrack",
+            Self::Scan => "scan",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|m| m.name() == name)
+    }
+
+    /// Features a device needs to operate in the mode
+    pub fn features(self) -> Features {
+        match self {
+            Self::Idle => Features::empty(),
+            Self::Measure => Features::MIC_ARRAY,
+            Self::ManualControl => Features::PAN_TILT,
+            Self::AutoTrack | Self::Scan => Features::MIC_ARRAY.union(Features::PAN_TILT),
+        }
+    }
+
+    /// Whether the microphone array is sampled in the mode
+    pub fn samples(self) -> bool {
+        matches!(self, Self::Measure | Self::AutoTrack | Self::Scan)
+    }
+
+    /// The mode after this one in [Mode::ALL] that a device with `features` supports,
+    /// wrapping around
+    pub fn next(self, features: Features) -> Self {
+        let index = self as usize;
+        (1..=Self::ALL.len())
+            .map(|i| Self::ALL[(index + i) % Self::ALL.len()])
+            .find(|m| features.contains(m.features()))
+            .unwrap_or(Self::Idle)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::mode::*;
+
+    #[test]
+    fn test_names() {
+        Mode::ALL
+            .iter()
+            .for_each(|&m| assert_eq!(Mode::parse(m.name()), Some(m)));
+        assert_eq!(Mode::parse("standalone"), None);
+    }
+
+    #[test]
+    fn test_next() {
+        let all = Features::MIC_ARRAY.union(Features::PAN_TILT);
+        assert_eq!(Mode::Idle.next(all), Mode::Measure);
+        assert_eq!(Mode::Scan.next(all), Mode::Idle);
+
+        // Without a bracket, only idling and measuring are left
+        let mic_array = Features::MIC_ARRAY;
+        assert_eq!(Mode::Idle.next(mic_array), Mode::Measure);
+        assert_eq!(Mode::Measure.next(mic_array), Mode::Idle);
+        assert_eq!(Mode::Idle.next(Features::empty()), Mode::Idle);
+    }
+}
@@ -0,0 +1,82 @@
+//! Motions of the pan/tilt bracket the host can ask for, and how fast the bracket makes them.
+//!
+//! Every command that moves the bracket is followed by a [crate::DeviceToServer::MotionDone]
+//! carrying its ID, once the bracket got where the command sent it or went elsewhere instead.
+
+#[cfg(feature = "defmt")]
+use defmt::Format;
+use serde::{Deserialize, Serialize};
+
+use crate::units::{Bearing, DegreeOffset};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub enum Motion {
+    /// Move by offsets from where the bracket is heading, so that moves add up even if
+    /// the previous one didn't finish yet
+    By {
+        pan: DegreeOffset,
+        tilt: DegreeOffset,
+    },
+    /// Head back to the park position
+    Home,
+    /// Stop right where the bracket is, without slowing down first
+    Stop,
+    /// Turn the microphone array to face a direction, given like in a
+    /// [crate::device_to_server::Detection] relative to where the array faces right now
+    LookAt {
+        azimuth: Bearing,
+        elevation: Bearing,
+    },
+}
+
+/// How a motion ended
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub enum MotionEnd {
+    /// The bracket got where the motion sent it
+    Reached,
+    /// The bracket was sent elsewhere before it got there, by another command, a change
+    /// of mode or the safe state
+    Interrupted,
+}
+
+/// How fast an axis of the bracket moves
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub struct AxisLimits {
+    /// Speed the axis moves at once it got going, in degrees per second
+    pub max_speed_deg_s: u32,
+    /// Rate at which the axis speeds up and slows down, in degrees per second squared,
+    /// or 0 to go from standstill to full speed at once
+    pub acceleration_deg_s2: u32,
+}
+
+/// Limits of each axis of the bracket. Axes that are `None` are left as they are.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub struct MotionLimits {
+    pub pan: Option<AxisLimits>,
+    pub tilt: Option<AxisLimits>,
+}
+
+/// Offsets to pan and tilt the bracket by, so that the microphone array faces a sound that
+/// arrives from `azimuth` and `elevation`
+pub fn facing_offsets(azimuth: Bearing, elevation: Bearing) -> (DegreeOffset, DegreeOffset) {
+    (azimuth.from_broadside(), -elevation.from_broadside())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::motion::*;
+
+    #[test]
+    fn test_facing_offsets() {
+        let broadside = facing_offsets(Bearing::BROADSIDE, Bearing::BROADSIDE);
+        assert_eq!(broadside, (DegreeOffset::ZERO, DegreeOffset::ZERO));
+
+        let (pan, tilt) = facing_offsets(Bearing::from_degrees(120), Bearing::from_degrees(60));
+        assert_eq!(pan, DegreeOffset(30));
+        assert_eq!(tilt, DegreeOffset(30));
+    }
+}
@@ -2,10 +2,59 @@
 use defmt::Format;
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+use crate::{
+    compression::SampleEncoding,
+    heartbeat::Heartbeat,
+    hello::Identify,
+    mode::Mode,
+    motion::{Motion, MotionLimits},
+    status::Query,
+    stream::Subscribe,
+    units::Degrees,
+};
+
+/// Identifies a command, so that the reply of the device can be matched to it
+pub type RequestId = u16;
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
 #[cfg_attr(feature = "defmt", derive(Format))]
 pub struct ServerToDevice {
-    pub pan_degrees: Option<i32>,
-    pub tilt_degrees: Option<i32>,
+    /// The device replies to each command with an `Ack` or `Nack` carrying this ID
+    pub id: RequestId,
+    pub pan_degrees: Option<Degrees>,
+    pub tilt_degrees: Option<Degrees>,
     pub set_sampling_enabled: Option<bool>,
+    /// Request a [crate::hello::Hello] from the device
+    pub identify: Option<Identify>,
+    /// Change the way the device samples the microphone array
+    pub configure: Option<Configure>,
+    /// Change the way the device sends frames of samples
+    pub sample_encoding: Option<SampleEncoding>,
+    /// Change how often the device sends the messages of a stream
+    pub subscribe: Option<Subscribe>,
+    /// Tell the device the host is still there, and how often it sends heartbeats
+    pub heartbeat: Option<Heartbeat>,
+    /// Ask about the state of the device, which answers in its `Ack` after carrying out
+    /// the rest of the command
+    pub query: Option<Query>,
+    /// Switch to another operating mode
+    pub set_mode: Option<Mode>,
+    /// Move the bracket, after moving it to `pan_degrees` and `tilt_degrees`
+    pub motion: Option<Motion>,
+    /// Change how fast the bracket moves
+    pub motion_limits: Option<MotionLimits>,
+}
+
+/// Changes to the acquisition parameters of a device, see [crate::hello::Acquisition].
+/// Parameters that are `None` are left as they are.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub struct Configure {
+    pub sample_period_us: Option<u32>,
+    /// Gain of the ADC, as numerator and denominator
+    pub gain: Option<(u32, u32)>,
+    pub resolution_bits: Option<u32>,
+    pub oversample_log2: Option<u32>,
+    pub acquisition_time_us: Option<u32>,
+    pub frame_len: Option<u32>,
 }
@@ -0,0 +1,67 @@
+//! Queries the host sends to learn the state of the device, see
+//! [crate::ServerToDevice::query]. The device answers them in its `Ack`.
+
+#[cfg(feature = "defmt")]
+use defmt::Format;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    device_to_server::PanTiltStatus, hello::Acquisition, mode::Mode, motion::MotionLimits,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub enum Query {
+    /// Ask for a [DeviceStatus]
+    Status,
+    /// Ask for a [DeviceConfig]
+    Config,
+}
+
+/// What the device is doing right now
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub struct DeviceStatus {
+    /// Time since boot, in milliseconds
+    pub uptime_ms: u64,
+    pub mode: Mode,
+    /// Whether the device samples the microphone array
+    pub sampling_enabled: bool,
+    /// Whether the device lost the link to the host and went into its safe state
+    pub safe_state: bool,
+    /// Position the pan/tilt bracket is heading for, if the device has one
+    pub pan_tilt_goal: Option<PanTiltStatus>,
+    /// Position of the pan/tilt bracket right now, if the device has one
+    pub pan_tilt_actual: Option<PanTiltStatus>,
+}
+
+/// How the device is set up
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub struct DeviceConfig {
+    /// Only present if the device samples the microphone array
+    pub acquisition: Option<Acquisition>,
+    /// Only present if the device samples the microphone array
+    pub calibration: Option<Calibration>,
+    /// Interval at which the device sends heartbeats, in milliseconds, or 0 if it doesn't
+    pub heartbeat_interval_ms: u32,
+    /// Only present if the device has a pan/tilt bracket
+    pub motion_limits: Option<MotionLimits>,
+}
+
+/// Corrections the device applies to its samples before finding directions in them
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub struct Calibration {
+    /// Cutoff frequency of the DC blocking filter in Hz, or `None` if the mean of each
+    /// frame is subtracted instead
+    pub dc_block_cutoff_hz: Option<u32>,
+    /// Time between sampling the two channels of each microphone pair in nanoseconds,
+    /// which the lag tables make up for
+    pub pair_skew_ns: [i32; 2],
+    /// Frames with an RMS value of at most this amount of ADC counts are used to learn
+    /// the noise profile
+    pub silence_max_rms: u32,
+    /// Frames the noise profile was learned from since it was last reset
+    pub noise_frames: u32,
+}
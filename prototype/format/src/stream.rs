@@ -0,0 +1,175 @@
+//! Messages the device sends on its own, which the host subscribes to.
+//!
+//! The host picks a decimation factor for each [Stream]: with a factor of `n`, the device
+//! sends every `n`th message of the stream, and a factor of 0 unsubscribes.
+
+#[cfg(feature = "defmt")]
+use defmt::Format;
+use serde::{Deserialize, Serialize};
+
+use crate::hello::Features;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub enum Stream {
+    /// [crate::DeviceToServer::Chunk], one per frame
+    Samples,
+    /// [crate::DeviceToServer::Detection], one per frame with a usable direction
+    Detections,
+    /// [crate::DeviceToServer::Xcorr], one per frame, for debugging the direction finding
+    Xcorr,
+    /// [crate::DeviceToServer::Telemetry], one per telemetry period
+    Telemetry,
+    /// [crate::DeviceToServer::PanTilt], one per step of the bracket
+    PanTilt,
+}
+
+impl Stream {
+    pub const ALL: [Self; 5] = [
+        Self::Samples,
+        Self::Detections,
+        Self::Xcorr,
+        Self::Telemetry,
+        Self::PanTilt,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Samples => "samples",
+            Self::Detections => "detections",
+            Self::Xcorr => "xcorr",
+            Self::Telemetry => "telemetry",
+            Self::PanTilt => "pantilt",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|s| s.name() == name)
+    }
+
+    /// Features a device needs to send the stream
+    pub fn features(self) -> Features {
+        match self {
+            Self::Samples | Self::Detections | Self::Xcorr => Features::MIC_ARRAY,
+            Self::Telemetry => Features::empty(),
+            Self::PanTilt => Features::PAN_TILT,
+        }
+    }
+}
+
+/// Change the decimation factor of a stream
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub struct Subscribe {
+    pub stream: Stream,
+    /// Send every `decimation`th message, or none if 0
+    pub decimation: u16,
+}
+
+impl Subscribe {
+    pub fn every(stream: Stream, decimation: u16) -> Self {
+        Self { stream, decimation }
+    }
+
+    pub fn none(stream: Stream) -> Self {
+        Self::every(stream, 0)
+    }
+}
+
+/// The streams a device sends, and how far along each one is to its next message
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub struct Subscriptions {
+    decimation: [u16; 5],
+    skipped: [u16; 5],
+}
+
+impl Subscriptions {
+    /// Samples, detections and telemetry are sent in full, the other streams not at all
+    pub const fn new() -> Self {
+        Self {
+            decimation: [1, 1, 0, 1, 0],
+            skipped: [0; 5],
+        }
+    }
+
+    /// No stream is sent at all
+    pub const fn none() -> Self {
+        Self {
+            decimation: [0; 5],
+            skipped: [0; 5],
+        }
+    }
+
+    pub fn subscribe(&mut self, Subscribe { stream, decimation }: Subscribe) {
+        self.decimation[stream as usize] = decimation;
+        self.skipped[stream as usize] = 0;
+    }
+
+    pub fn decimation(&self, stream: Stream) -> u16 {
+        self.decimation[stream as usize]
+    }
+
+    pub fn is_subscribed(&self, stream: Stream) -> bool {
+        self.decimation(stream) > 0
+    }
+
+    /// Count a message of the stream, and tell whether it should be sent
+    pub fn tick(&mut self, stream: Stream) -> bool {
+        let decimation = self.decimation[stream as usize];
+        let skipped = &mut self.skipped[stream as usize];
+        if decimation == 0 {
+            return false;
+        }
+        *skipped += 1;
+        if *skipped >= decimation {
+            *skipped = 0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl Default for Subscriptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::stream::*;
+
+    #[test]
+    fn test_subscriptions() {
+        let mut subscriptions = Subscriptions::new();
+        assert!(subscriptions.tick(Stream::Samples));
+        assert!(!subscriptions.tick(Stream::Xcorr));
+
+        subscriptions.subscribe(Subscribe::every(Stream::Xcorr, 3));
+        let sent = (0..9).filter(|_| subscriptions.tick(Stream::Xcorr)).count();
+        assert_eq!(sent, 3);
+        // The first message is sent after a full period
+        assert!(!subscriptions.tick(Stream::Xcorr));
+        assert!(!subscriptions.tick(Stream::Xcorr));
+        assert!(subscriptions.tick(Stream::Xcorr));
+
+        subscriptions.subscribe(Subscribe::none(Stream::Samples));
+        assert!(!subscriptions.is_subscribed(Stream::Samples));
+        assert!((0..10).all(|_| !subscriptions.tick(Stream::Samples)));
+        // Other streams are left alone
+        assert_eq!(subscriptions.decimation(Stream::Detections), 1);
+
+        let mut none = Subscriptions::none();
+        assert!(Stream::ALL.iter().all(|&s| !none.tick(s)));
+    }
+
+    #[test]
+    fn test_names() {
+        Stream::ALL
+            .iter()
+            .for_each(|&s| assert_eq!(Stream::parse(s.name()), Some(s)));
+        assert_eq!(Stream::parse("everything"), None);
+    }
+}
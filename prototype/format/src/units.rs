@@ -0,0 +1,227 @@
+use core::ops::{Add, Neg, Sub};
+
+#[cfg(feature = "defmt")]
+use defmt::Format;
+use serde::{Deserialize, Serialize};
+
+use crate::device_to_server::MicArraySample;
+
+/// Angle in whole degrees, like a servo position. Moving it takes a [DegreeOffset], so that
+/// positions and offsets can't be mixed up.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub struct Degrees(pub i32);
+
+impl Degrees {
+    pub const ZERO: Self = Self(0);
+
+    pub fn to_radians(self) -> Radians {
+        Radians(self.0 as f32 * core::f32::consts::PI / 180.)
+    }
+}
+
+/// Signed difference between two angles in whole degrees, like an amount to move a servo by
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub struct DegreeOffset(pub i32);
+
+impl DegreeOffset {
+    pub const ZERO: Self = Self(0);
+}
+
+impl Add<DegreeOffset> for Degrees {
+    type Output = Self;
+
+    fn add(self, rhs: DegreeOffset) -> Self {
+        Self(self.0 + rhs.0)
+    }
+}
+
+impl Sub<DegreeOffset> for Degrees {
+    type Output = Self;
+
+    fn sub(self, rhs: DegreeOffset) -> Self {
+        Self(self.0 - rhs.0)
+    }
+}
+
+impl Sub for Degrees {
+    type Output = DegreeOffset;
+
+    fn sub(self, rhs: Self) -> DegreeOffset {
+        DegreeOffset(self.0 - rhs.0)
+    }
+}
+
+impl Add for DegreeOffset {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self {
+        Self(self.0 + rhs.0)
+    }
+}
+
+impl Neg for DegreeOffset {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        Self(-self.0)
+    }
+}
+
+/// Signed angle in radians
+#[derive(Debug, Clone, Copy, Default, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub struct Radians(pub f32);
+
+impl Radians {
+    /// Convert to degrees, rounded to the nearest whole degree
+    pub fn to_degrees(self) -> Degrees {
+        let degrees = self.0 * 180. / core::f32::consts::PI;
+        Degrees((degrees + if degrees < 0. { -0.5 } else { 0.5 }) as i32)
+    }
+}
+
+/// Direction a sound arrives from, relative to the axis through a pair of microphones.
+/// Ranges from 0 to 180 degrees, where 90 degrees is broadside to the pair.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub struct Bearing(u32);
+
+impl Bearing {
+    pub const BROADSIDE: Self = Self(90);
+
+    /// Create a bearing, clamping it to 180 degrees
+    pub const fn from_degrees(degrees: u32) -> Self {
+        Self(if degrees > 180 { 180 } else { degrees })
+    }
+
+    pub const fn degrees(self) -> u32 {
+        self.0
+    }
+
+    /// Signed angle between broadside and the bearing, i.e. `bearing - 90°`.
+    /// This is the angle a servo needs to turn to face the sound.
+    pub const fn from_broadside(self) -> DegreeOffset {
+        DegreeOffset(self.0 as i32 - Self::BROADSIDE.0 as i32)
+    }
+
+    pub fn to_radians(self) -> Radians {
+        Degrees(self.0 as i32).to_radians()
+    }
+}
+
+/// Voltage in volts
+#[derive(Debug, Clone, Copy, Default, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub struct Volts(pub f32);
+
+/// Reference voltage of the SAADC
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub enum AdcReference {
+    /// Internal 0.6 V reference
+    Internal,
+    /// A quarter of the supply voltage
+    Vdd4 { vdd_mv: u32 },
+}
+
+impl AdcReference {
+    pub const fn millivolts(self) -> u32 {
+        match self {
+            AdcReference::Internal => 600,
+            AdcReference::Vdd4 { vdd_mv } => vdd_mv / 4,
+        }
+    }
+}
+
+/// SAADC settings needed to convert raw counts into the voltage at the input pin:
+/// `V = counts * reference / (gain * 2^(resolution - differential))`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub struct AdcScale {
+    pub reference: AdcReference,
+    /// Numerator of the gain
+    pub gain_num: u32,
+    /// Denominator of the gain
+    pub gain_den: u32,
+    pub resolution_bits: u32,
+    /// Whether the input is measured differentially, which costs a bit of resolution
+    pub differential: bool,
+}
+
+impl AdcScale {
+    /// Scale of a single-ended measurement
+    pub const fn single_ended(
+        reference: AdcReference,
+        gain_num: u32,
+        gain_den: u32,
+        resolution_bits: u32,
+    ) -> Self {
+        Self {
+            reference,
+            gain_num,
+            gain_den,
+            resolution_bits,
+            differential: false,
+        }
+    }
+
+    /// Convert counts to microvolts, rounding towards zero
+    pub const fn to_microvolts(&self, counts: i32) -> i32 {
+        let bits = self.resolution_bits - self.differential as u32;
+        (counts as i64 * self.reference.millivolts() as i64 * 1000 * self.gain_den as i64
+            / ((self.gain_num as i64) << bits)) as i32
+    }
+
+    pub fn to_volts(&self, counts: i16) -> Volts {
+        let bits = self.resolution_bits - self.differential as u32;
+        Volts(
+            counts as f32 * self.reference.millivolts() as f32 * self.gain_den as f32
+                / (1000. * (self.gain_num << bits) as f32),
+        )
+    }
+
+    /// Convert each channel of a raw sample to volts
+    pub fn sample_to_volts(&self, sample: &MicArraySample) -> [Volts; 4] {
+        sample.map(|counts| self.to_volts(counts))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::units::*;
+
+    #[test]
+    fn test_angles() {
+        assert_eq!(Bearing::from_degrees(200).degrees(), 180);
+        assert_eq!(
+            Bearing::from_degrees(30).from_broadside(),
+            DegreeOffset(-60)
+        );
+        assert_eq!(
+            -Bearing::from_degrees(135).from_broadside(),
+            DegreeOffset(-45)
+        );
+        assert_eq!(Degrees(10) + DegreeOffset(-25), Degrees(-15));
+        assert_eq!(Degrees(10) - Degrees(35), DegreeOffset(-25));
+        assert_eq!(Degrees(-135).to_radians().to_degrees(), Degrees(-135));
+        assert!((Bearing::BROADSIDE.to_radians().0 - core::f32::consts::FRAC_PI_2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_adc_scale() {
+        // 12 bit, gain 1/3, VDD / 4 reference at 3 V: full scale is 2.25 V
+        let scale = AdcScale::single_ended(AdcReference::Vdd4 { vdd_mv: 3000 }, 1, 3, 12);
+        assert_eq!(scale.to_microvolts(4096), 2_250_000);
+        assert_eq!(scale.to_microvolts(1), 549);
+        assert!((scale.to_volts(2048).0 - 1.125).abs() < 1e-6);
+
+        let scale = AdcScale {
+            differential: true,
+            ..AdcScale::single_ended(AdcReference::Internal, 4, 1, 14)
+        };
+        assert_eq!(scale.to_microvolts(-8192), -150_000);
+        assert_eq!(scale.sample_to_volts(&[8192, 0, 0, 0])[0], Volts(0.15));
+    }
+}